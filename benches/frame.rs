@@ -0,0 +1,82 @@
+//! Throughput of the frame codec for small, medium and large payloads.
+//! Run with `cargo bench --bench frame`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use websocket::ws::frame::{self, Frame, FrameHeader, OPCODE_BINARY};
+
+const SIZES : [(&str, usize); 3] = [("small", 16), ("medium", 4 << 10), ("large", 1 << 20)];
+
+const MASK : [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+/// A masked binary frame with `size` bytes of payload, as sent by a client.
+fn masked_frame(size : usize) -> Vec<u8> {
+    let mut frame = Frame::new(true, OPCODE_BINARY, vec![0xab; size]);
+    frame.header.mask = Some(MASK);
+    let mut bytes = Vec::new();
+    frame::write_frame(&mut bytes, &frame).unwrap();
+    return bytes;
+}
+
+fn parse(c : &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, size) in SIZES {
+        let bytes = masked_frame(size);
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &bytes, |b, bytes| {
+            b.iter(|| frame::read_frame(&mut black_box(&bytes[..])).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn header(c : &mut Criterion) {
+    let mut group = c.benchmark_group("parse_header");
+    for (name, size) in SIZES {
+        let bytes = masked_frame(size);
+        group.bench_with_input(BenchmarkId::from_parameter(name), &bytes, |b, bytes| {
+            b.iter(|| FrameHeader::parse(black_box(bytes)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn unmask(c : &mut Criterion) {
+    let mut group = c.benchmark_group("unmask");
+    for (name, size) in SIZES {
+        let mut payload = vec![0xab; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| frame::apply_mask(black_box(&mut payload), black_box(MASK)))
+        });
+    }
+    group.finish();
+}
+
+fn encode(c : &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for (name, size) in SIZES {
+        let unmasked = Frame::new(true, OPCODE_BINARY, vec![0xab; size]);
+        let mut masked = unmasked.clone();
+        masked.header.mask = Some(MASK);
+        let mut out = Vec::with_capacity(FrameHeader::MAX_LEN + size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("unmasked", name), &unmasked, |b, frame| {
+            b.iter(|| {
+                out.clear();
+                frame::write_frame(&mut out, black_box(frame)).unwrap();
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("masked", name), &masked, |b, frame| {
+            b.iter(|| {
+                out.clear();
+                frame::write_frame(&mut out, black_box(frame)).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, header, unmask, encode);
+criterion_main!(benches);
@@ -0,0 +1,85 @@
+//! Feeds arbitrary bytes into a server websocket, split into reads at arbitrary boundaries, and
+//! assembles them with each of the read APIs. Protocol errors are fine, panics are not.
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use websocket::ws::{Message, Websocket};
+
+#[derive(Arbitrary, Debug)]
+enum Api {
+    Read,
+    ReadInto(u16),
+    ReadChunks,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    api : Api,
+    accept_unmasked : bool,
+    // lengths of the reads, the last one repeats
+    splits : Vec<u8>,
+    data : Vec<u8>,
+}
+
+/// Hands out the data in reads of the given lengths and discards everything written. Reading past
+/// the end fails, which ends the input.
+struct Chunked {
+    data : Vec<u8>,
+    offset : usize,
+    splits : Vec<u8>,
+    read_count : usize,
+}
+
+impl std::io::Read for Chunked {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        let split = match self.splits.get(self.read_count).or(self.splits.last()) {
+            Some(&split) => (split as usize).max(1),
+            None => buf.len(),
+        };
+        self.read_count += 1;
+        if self.offset == self.data.len() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let n = split.min(buf.len()).min(self.data.len() - self.offset);
+        buf[..n].copy_from_slice(&self.data[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+impl std::io::Write for Chunked {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> { Ok(buf.len()) }
+
+    fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+}
+
+fuzz_target!(|input : Input| {
+    let connection = Chunked {
+        data: input.data,
+        offset: 0,
+        splits: input.splits,
+        read_count: 0,
+    };
+    let mut socket = Websocket::from(connection);
+    socket.set_accept_unmasked(input.accept_unmasked);
+    // keeps frames announcing huge payloads from exhausting the fuzzer's memory
+    socket.set_max_message_size(1 << 20);
+
+    let mut buf = Vec::new();
+    if let Api::ReadInto(len) = input.api {
+        buf.resize(len as usize, 0);
+    }
+    loop {
+        let result = match input.api {
+            Api::Read => socket.read().map(|messages| messages.iter().any(|m| matches!(m, Message::Close(..)))),
+            Api::ReadInto(_) => socket.read_into(&mut buf).map(|read| read.is_none()),
+            Api::ReadChunks => socket.read_chunks().map(|_| false),
+        };
+        match result {
+            Ok(false) if !socket.is_closed() => {},
+            _ => break,
+        }
+    }
+});
@@ -0,0 +1,17 @@
+//! Parses arbitrary bytes with `frame::read_frame` and checks that re-encoding a parsed frame
+//! gives back a frame with the same header and payload.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use websocket::ws::frame;
+
+fuzz_target!(|data : &[u8]| {
+    let mut reader = data;
+    while let Ok(parsed) = frame::read_frame(&mut reader) {
+        let mut encoded = Vec::new();
+        frame::write_frame(&mut encoded, &parsed).unwrap();
+        let reparsed = frame::read_frame(&mut &encoded[..]).unwrap();
+        assert_eq!(parsed, reparsed);
+    }
+});
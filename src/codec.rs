@@ -0,0 +1,224 @@
+//! The websocket frame codec (RFC 6455 section 5.2). It only needs `alloc`, so it is available
+//! without the `std` feature, e.g. on embedded devices with their own transport. `ws::frame`
+//! adds reading and writing frames on `std::io` streams.
+
+use alloc::vec::Vec;
+
+pub const OPCODE_CONTINUATION : u8 = 0x0;
+pub const OPCODE_TEXT : u8 = 0x1;
+pub const OPCODE_BINARY : u8 = 0x2;
+pub const OPCODE_CLOSE : u8 = 0x8;
+pub const OPCODE_PING : u8 = 0x9;
+pub const OPCODE_PONG : u8 = 0xA;
+
+/// The fixed part of a frame preceding the payload.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FrameHeader {
+    pub fin : bool,
+    /// RSV1-3 as the bits 0b100, 0b010 and 0b001.
+    pub rsv : u8,
+    pub opcode : u8,
+    pub mask : Option<[u8; 4]>,
+    pub payload_len : u64,
+}
+
+/// A frame with its payload. The payload is always stored unmasked.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Frame {
+    pub header : FrameHeader,
+    pub payload : Vec<u8>,
+}
+
+/// XORs `data` with the masking key. Masking and unmasking are the same operation.
+pub fn apply_mask(data : &mut [u8], mask : [u8; 4]) {
+    // eight bytes at a time, which the compiler can vectorize further. Every chunk starts at a
+    // multiple of the key length, so the repeated key lines up with each of them.
+    let mask_word = u64::from_ne_bytes([mask[0], mask[1], mask[2], mask[3], mask[0], mask[1], mask[2], mask[3]]);
+    let mut chunks = data.chunks_exact_mut(8);
+    for chunk in &mut chunks {
+        let word = u64::from_ne_bytes(chunk.try_into().unwrap()) ^ mask_word;
+        chunk.copy_from_slice(&word.to_ne_bytes());
+    }
+    for (i, byte) in chunks.into_remainder().iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+impl FrameHeader {
+
+    /// The maximum size of an encoded header: 2 bytes, 8 bytes extended payload length and the mask.
+    pub const MAX_LEN : usize = 14;
+
+    pub fn new(fin : bool, opcode : u8, payload_len : u64) -> Self {
+        Self {
+            fin: fin,
+            rsv: 0,
+            opcode: opcode,
+            mask: None,
+            payload_len: payload_len,
+        }
+    }
+
+    pub fn is_control(&self) -> bool {
+        (self.opcode & 0x8) != 0
+    }
+
+    /// The payload length, or None if it doesn't fit in a usize, which happens on 32 bit targets.
+    pub fn payload_size(&self) -> Option<usize> {
+        usize::try_from(self.payload_len).ok()
+    }
+
+    /// The length of the header starting with `bytes`, which needs to contain at least the first two bytes.
+    pub fn encoded_len(bytes : &[u8]) -> Option<usize> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        let extended = match bytes[1] & 0x7F {
+            126 => 2, // 16 bit extended payload length
+            127 => 8, // 64 bit extended payload length
+            _ => 0,
+        };
+        let mask = if (bytes[1] >> 7) != 0 { 4 } else { 0 };
+        return Some(2 + extended + mask);
+    }
+
+    /// Parses the header at the start of `bytes`. Returns the header and its length in bytes,
+    /// or None if `bytes` doesn't contain the whole header yet.
+    pub fn parse(bytes : &[u8]) -> Option<(FrameHeader, usize)> {
+        let len = Self::encoded_len(bytes)?;
+        if bytes.len() < len {
+            return None;
+        }
+
+        let (payload_len, mask_offset) = match bytes[1] & 0x7F {
+            126 => {
+                let mut buf = [0u8; 2];
+                buf.clone_from_slice(&bytes[2..4]);
+                (u16::from_be_bytes(buf) as u64, 4)
+            },
+            127 => {
+                let mut buf = [0u8; 8];
+                buf.clone_from_slice(&bytes[2..10]);
+                (u64::from_be_bytes(buf), 10)
+            },
+            v => (v as u64, 2),
+        };
+
+        let mask = if (bytes[1] >> 7) != 0 {
+            let mut buf = [0u8; 4];
+            buf.clone_from_slice(&bytes[mask_offset..mask_offset + 4]);
+            Some(buf)
+        }else{
+            None
+        };
+
+        let header = FrameHeader {
+            fin: (bytes[0] >> 7) != 0,
+            rsv: (bytes[0] >> 4) & 0x7,
+            opcode: bytes[0] & 0xF,
+            mask: mask,
+            payload_len: payload_len,
+        };
+        return Some((header, len));
+    }
+
+    /// Appends the encoded header to `out`, using the shortest payload length encoding.
+    pub fn encode(&self, out : &mut Vec<u8>) {
+        out.push(((self.fin as u8) << 7) | ((self.rsv & 0x7) << 4) | (self.opcode & 0xF));
+
+        let mask_bit = if self.mask.is_some() { 1 << 7 } else { 0 };
+        if self.payload_len < 126 {
+            // one byte payload length
+            out.push(mask_bit | self.payload_len as u8);
+        }else if self.payload_len <= 0xFFFF {
+            // two byte extended payload length
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(self.payload_len as u16).to_be_bytes());
+        }else{
+            // 8 byte extended payload length
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&self.payload_len.to_be_bytes());
+        }
+
+        if let Some(mask) = self.mask {
+            out.extend_from_slice(&mask);
+        }
+    }
+}
+
+impl Frame {
+    /// An unmasked frame without reserved bits.
+    pub fn new(fin : bool, opcode : u8, payload : Vec<u8>) -> Self {
+        Self {
+            header: FrameHeader::new(fin, opcode, payload.len() as u64),
+            payload: payload,
+        }
+    }
+}
+
+/// Generates the masking keys of frames sent by clients. RFC 6455 requires them to be
+/// unpredictable, deterministic generators are only meant for tests.
+pub trait MaskGenerator : Send {
+    /// The key for the next frame, None if no key could be generated.
+    fn next_mask(&mut self) -> Option<[u8; 4]>;
+}
+
+/// A violation of the protocol by the peer. The connection is failed with `code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolError {
+    pub code : u16,
+    pub reason : &'static str,
+}
+
+impl ProtocolError {
+    pub fn new(code : u16, reason : &'static str) -> Self {
+        Self {
+            code: code,
+            reason: reason,
+        }
+    }
+}
+
+/// Appends the encoded frame to `out`, masking the payload if the header carries a masking key.
+/// The header's payload length is taken from the payload.
+pub fn encode_frame(frame : &Frame, out : &mut Vec<u8>) {
+    let mut header = frame.header;
+    header.payload_len = frame.payload.len() as u64;
+
+    out.reserve(FrameHeader::MAX_LEN + frame.payload.len());
+    header.encode(out);
+    let payload_offset = out.len();
+    out.extend_from_slice(&frame.payload);
+    if let Some(mask) = header.mask {
+        apply_mask(&mut out[payload_offset..], mask);
+    }
+}
+
+/// Decodes the frame at the start of `bytes`. Returns the frame with its payload unmasked and the
+/// number of bytes it occupied, or None if `bytes` doesn't contain the whole frame yet.
+/// Frames announcing more than `max_payload` bytes are rejected before their payload arrived.
+pub fn decode_frame(bytes : &[u8], max_payload : usize) -> Result<Option<(Frame, usize)>, ProtocolError> {
+    let (header, header_len) = match FrameHeader::parse(bytes) {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    if header.payload_len >> 63 != 0 {
+        return Err(ProtocolError::new(1002, "most significant bit of the payload length is set"));
+    }
+    if header.payload_len > max_payload as u64 {
+        return Err(ProtocolError::new(1009, "frame exceeds the maximum message size"));
+    }
+    let end = match header.payload_size().and_then(|len| len.checked_add(header_len)) {
+        Some(end) => end,
+        None => return Err(ProtocolError::new(1009, "frame exceeds the maximum message size")),
+    };
+    if bytes.len() < end {
+        return Ok(None);
+    }
+
+    let mut payload = bytes[header_len..end].to_vec();
+    if let Some(mask) = header.mask {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Some((Frame{header: header, payload: payload}, end)))
+}
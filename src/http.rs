@@ -1,7 +1,214 @@
+pub mod access_log;
+pub mod auth;
+pub mod client;
+pub mod compress;
+pub mod files;
+pub mod h2;
+pub mod router;
+pub mod vhost;
 
-#[derive(Clone)]
+pub use auth::Auth;
+pub use compress::Compression;
+pub use router::{Params, Router};
+pub use vhost::VirtualHosts;
+
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Method {
     GET,
+    HEAD,
+    POST,
+    PUT,
+    DELETE,
+    OPTIONS,
+    PATCH,
+    TRACE,
+    /// Any other method token, e.g. CONNECT or an extension method.
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::GET => "GET",
+            Self::HEAD => "HEAD",
+            Self::POST => "POST",
+            Self::PUT => "PUT",
+            Self::DELETE => "DELETE",
+            Self::OPTIONS => "OPTIONS",
+            Self::PATCH => "PATCH",
+            Self::TRACE => "TRACE",
+            Self::Other(method) => method,
+        }
+    }
+}
+
+/// The status of a response, which supplies its reason phrase.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StatusCode {
+    Continue,
+    SwitchingProtocols,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Gone,
+    LengthRequired,
+    ContentTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    MisdirectedRequest,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    HttpVersionNotSupported,
+    /// Any other status, with its code and reason phrase.
+    Custom(u16, String),
+}
+
+impl StatusCode {
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Continue => 100,
+            Self::SwitchingProtocols => 101,
+            Self::Ok => 200,
+            Self::Created => 201,
+            Self::Accepted => 202,
+            Self::NoContent => 204,
+            Self::PartialContent => 206,
+            Self::MovedPermanently => 301,
+            Self::Found => 302,
+            Self::SeeOther => 303,
+            Self::NotModified => 304,
+            Self::TemporaryRedirect => 307,
+            Self::PermanentRedirect => 308,
+            Self::BadRequest => 400,
+            Self::Unauthorized => 401,
+            Self::Forbidden => 403,
+            Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::RequestTimeout => 408,
+            Self::Gone => 410,
+            Self::LengthRequired => 411,
+            Self::ContentTooLarge => 413,
+            Self::UriTooLong => 414,
+            Self::UnsupportedMediaType => 415,
+            Self::RangeNotSatisfiable => 416,
+            Self::MisdirectedRequest => 421,
+            Self::UpgradeRequired => 426,
+            Self::TooManyRequests => 429,
+            Self::RequestHeaderFieldsTooLarge => 431,
+            Self::InternalServerError => 500,
+            Self::NotImplemented => 501,
+            Self::BadGateway => 502,
+            Self::ServiceUnavailable => 503,
+            Self::GatewayTimeout => 504,
+            Self::HttpVersionNotSupported => 505,
+            Self::Custom(code, _) => *code,
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match self {
+            Self::Continue => "Continue",
+            Self::SwitchingProtocols => "Switching Protocols",
+            Self::Ok => "OK",
+            Self::Created => "Created",
+            Self::Accepted => "Accepted",
+            Self::NoContent => "No Content",
+            Self::PartialContent => "Partial Content",
+            Self::MovedPermanently => "Moved Permanently",
+            Self::Found => "Found",
+            Self::SeeOther => "See Other",
+            Self::NotModified => "Not Modified",
+            Self::TemporaryRedirect => "Temporary Redirect",
+            Self::PermanentRedirect => "Permanent Redirect",
+            Self::BadRequest => "Bad Request",
+            Self::Unauthorized => "Unauthorized",
+            Self::Forbidden => "Forbidden",
+            Self::NotFound => "Not Found",
+            Self::MethodNotAllowed => "Method Not Allowed",
+            Self::RequestTimeout => "Request Timeout",
+            Self::Gone => "Gone",
+            Self::LengthRequired => "Length Required",
+            Self::ContentTooLarge => "Content Too Large",
+            Self::UriTooLong => "URI Too Long",
+            Self::UnsupportedMediaType => "Unsupported Media Type",
+            Self::RangeNotSatisfiable => "Range Not Satisfiable",
+            Self::MisdirectedRequest => "Misdirected Request",
+            Self::UpgradeRequired => "Upgrade Required",
+            Self::TooManyRequests => "Too Many Requests",
+            Self::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Self::InternalServerError => "Internal Server Error",
+            Self::NotImplemented => "Not Implemented",
+            Self::BadGateway => "Bad Gateway",
+            Self::ServiceUnavailable => "Service Unavailable",
+            Self::GatewayTimeout => "Gateway Timeout",
+            Self::HttpVersionNotSupported => "HTTP Version Not Supported",
+            Self::Custom(_, reason) => reason,
+        }
+    }
+
+    /// The status with `code`, for codes without a variant a custom one without reason phrase.
+    pub fn from_code(code : u16) -> Self {
+        match code {
+            100 => Self::Continue,
+            101 => Self::SwitchingProtocols,
+            200 => Self::Ok,
+            201 => Self::Created,
+            202 => Self::Accepted,
+            204 => Self::NoContent,
+            206 => Self::PartialContent,
+            301 => Self::MovedPermanently,
+            302 => Self::Found,
+            303 => Self::SeeOther,
+            304 => Self::NotModified,
+            307 => Self::TemporaryRedirect,
+            308 => Self::PermanentRedirect,
+            400 => Self::BadRequest,
+            401 => Self::Unauthorized,
+            403 => Self::Forbidden,
+            404 => Self::NotFound,
+            405 => Self::MethodNotAllowed,
+            408 => Self::RequestTimeout,
+            410 => Self::Gone,
+            411 => Self::LengthRequired,
+            413 => Self::ContentTooLarge,
+            414 => Self::UriTooLong,
+            415 => Self::UnsupportedMediaType,
+            416 => Self::RangeNotSatisfiable,
+            421 => Self::MisdirectedRequest,
+            426 => Self::UpgradeRequired,
+            429 => Self::TooManyRequests,
+            431 => Self::RequestHeaderFieldsTooLarge,
+            500 => Self::InternalServerError,
+            501 => Self::NotImplemented,
+            502 => Self::BadGateway,
+            503 => Self::ServiceUnavailable,
+            504 => Self::GatewayTimeout,
+            505 => Self::HttpVersionNotSupported,
+            _ => Self::Custom(code, String::new()),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -10,11 +217,27 @@ struct StringRange {
     len : usize
 }
 
+/// The HTTP versions understood by the parser.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http10 => "HTTP/1.0",
+            Self::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct RequestLine {
     pub method : Method,
+    pub version : Version,
     request_uri : StringRange,
-    http_version : StringRange,
 }
 
 pub struct Header {
@@ -25,17 +248,78 @@ pub struct Header {
 pub struct Request {
     pub request_line : RequestLine,
     pub raw_request : String,
-    pub headers: Vec<Header>
+    pub headers: Vec<Header>,
+    body : Vec<u8>,
+    trailers : Vec<(String, String)>,
 }
 
 pub struct Response { }
 
 pub struct ResponseWithStatusLine {
     bytes: Vec<u8>,
+    // whether the headers of `ResponseDefaults` are added
+    defaults: bool,
 }
 
+/// Headers for every response of a server, e.g. `Server` or security headers. A router adds them
+/// to its responses, see `Router::defaults`, unless a response sets them itself or opts out with
+/// `ResponseWithStatusLine::without_defaults`.
+#[derive(Clone, Debug)]
+pub struct ResponseDefaults {
+    headers : Vec<(String, String)>,
+}
+
+/// A header which can't be sent, its name isn't a token or its value contains a line break.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidHeader(pub String);
+
 pub struct ResponseComplete {
     bytes: Vec<u8>,
+    // read and sent after the bytes, see `ResponseWithStatusLine::payload_reader`
+    stream: Option<BodyStream>,
+    // sent after a chunked stream, see `ResponseComplete::trailers`
+    trailers: Option<Trailers>,
+    // body bytes written by `send`
+    sent: u64,
+    // whether `ResponseDefaults::apply` adds its headers
+    defaults: bool,
+}
+
+/// Computes the trailer fields of a response once its body was sent.
+type Trailers = Box<dyn FnOnce() -> Vec<(String, String)> + Send>;
+
+/// The head of a `ResponseComplete`, for adapting it after it was built.
+struct ResponseHead<'a> {
+    // length of the head in bytes, including the blank line
+    len : usize,
+    status_line : &'a str,
+    headers : Vec<(&'a str, &'a str)>,
+}
+
+impl ResponseHead<'_> {
+    fn status_code(&self) -> u16 {
+        self.status_line.split(' ').nth(1).and_then(|code| code.parse().ok()).unwrap_or(0)
+    }
+
+    fn get_header(&self, name : &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| *value)
+    }
+}
+
+/// A body which is read while the response is sent.
+enum BodyStream {
+    // exactly this many bytes, sent as they are
+    Sized(Box<dyn std::io::Read + Send>, u64),
+    // everything until the reader ends, in chunks unless the response is HTTP/1.0
+    Chunked(Box<dyn std::io::Read + Send>, bool),
+}
+
+/// Writes the body of a response with chunked transfer coding, see `ResponseWithStatusLine::chunked`.
+pub struct ChunkedWriter<Sender : std::io::Write> {
+    out : Sender,
+    // HTTP/1.0 bodies are sent as they are
+    chunked : bool,
+    finished : bool,
 }
 
 pub struct RequestHeaderIterator<'a> {
@@ -63,10 +347,141 @@ impl <'a> Iterator for RequestHeaderIterator<'a> {
 }
 
 
+/// Formats `time` as IMF-fixdate (RFC 9110 section 5.6.7), e.g. `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// the format of Date and Last-Modified headers.
+pub fn http_date(time : std::time::SystemTime) -> String {
+    let time = UtcTime::from(time);
+    let weekday = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"][time.weekday];
+    let month = MONTHS[time.month];
+    format!("{weekday}, {:02} {month} {} {:02}:{:02}:{:02} GMT", time.day, time.year, time.hour, time.minute, time.second)
+}
+
+const MONTHS : [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// A point in time in UTC split into calendar fields. `month` counts from 0 for January,
+/// `weekday` from 0 for Monday.
+struct UtcTime {
+    year : u64,
+    month : usize,
+    day : u64,
+    weekday : usize,
+    hour : u64,
+    minute : u64,
+    second : u64,
+}
+
+impl UtcTime {
+    fn from(time : std::time::SystemTime) -> Self {
+        let secs = time.duration_since(std::time::UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        let days = secs / 86400;
+        // 1970-01-01 was a Thursday
+        let weekday = ((days + 3) % 7) as usize;
+        // the civil date of the day, in years starting with March (howardhinnant.github.io/date_algorithms.html)
+        let days = days + 719468;
+        let era = days / 146097;
+        let day_of_era = days % 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let year = era * 400 + year_of_era + if month >= 10 { 1 } else { 0 };
+        Self {
+            year: year,
+            month: ((month + 2) % 12) as usize,
+            day: day,
+            weekday: weekday,
+            hour: secs % 86400 / 3600,
+            minute: secs % 3600 / 60,
+            second: secs % 60,
+        }
+    }
+}
+
+/// The value of the Date header, formatted once per second and thread.
+fn current_date() -> String {
+    thread_local! {
+        static DATE : std::cell::RefCell<(u64, String)> = const { std::cell::RefCell::new((u64::MAX, String::new())) };
+    }
+    let now = std::time::SystemTime::now();
+    let secs = now.duration_since(std::time::UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    DATE.with(|date| {
+        let mut date = date.borrow_mut();
+        if date.0 != secs {
+            *date = (secs, http_date(now));
+        }
+        date.1.clone()
+    })
+}
+
+impl ResponseDefaults {
+    /// No headers.
+    pub fn new() -> Self {
+        Self {
+            headers: Vec::new(),
+        }
+    }
+
+    /// The `Server` header naming the software, e.g. `websocket/0.1`.
+    pub fn server(self, value : &str) -> Result<Self, InvalidHeader> {
+        self.header("Server", value)
+    }
+
+    /// Adds a header, replacing an earlier one with the same name. Names have to be tokens and
+    /// values must not contain line breaks. The framing headers `Content-Length` and
+    /// `Transfer-Encoding` are refused as well.
+    pub fn header<Type : std::fmt::Display>(mut self, name : &str, value : Type) -> Result<Self, InvalidHeader> {
+        let value = value.to_string();
+        if name.len() == 0 || !name.bytes().all(is_token_char) || value.contains(['\r', '\n'])
+            || name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+            return Err(InvalidHeader(String::from(name)));
+        }
+        self.headers.retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+        self.headers.push((String::from(name), value));
+        Ok(self)
+    }
+
+    /// `response` with the headers it doesn't set itself, unless it opted out of them.
+    pub fn apply(&self, mut response : ResponseComplete) -> ResponseComplete {
+        if !response.defaults || self.headers.len() == 0 {
+            return response;
+        }
+        let (len, missing) = match response.split_head() {
+            Some(head) => {
+                let missing : Vec<u8> = self.headers.iter()
+                    .filter(|(name, _)| head.get_header(name).is_none())
+                    .flat_map(|(name, value)| format!("{name}: {value}\r\n").into_bytes())
+                    .collect();
+                (head.len, missing)
+            },
+            None => return response,
+        };
+        // before the blank line ending the head
+        response.bytes.splice(len - 2..len - 2, missing);
+        response
+    }
+}
+
+impl std::fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Invalid header '{}'", self.0)
+    }
+}
+
+impl Default for ResponseDefaults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Response {
-    pub fn status(version: &str, status : &str, code: u16) -> ResponseWithStatusLine {
+    /// Starts a response with `status`. Custom codes have to have three digits and their reason
+    /// phrase must not contain line breaks.
+    pub fn status(version : Version, status : StatusCode) -> ResponseWithStatusLine {
+        let (code, reason) = (status.code(), status.reason());
+        assert!((100..1000).contains(&code));
+        assert!(!reason.contains(['\r', '\n']));
         // Status-Line = HTTP-Version SP Status-Code SP Reason-Phrase CRLF
-        let status_str = format!("{version} {code} {status}\r\n");
+        let status_str = format!("{} {code} {reason}\r\n", version.as_str());
         let mut bytes = Vec::new();
         for c in status_str.chars() {
             bytes.push(c as u8);
@@ -80,6 +495,7 @@ impl ResponseWithStatusLine {
     fn from(bytes: Vec<u8>) -> Self {
         Self {
             bytes: bytes,
+            defaults: true,
         }
     }
 
@@ -94,38 +510,176 @@ impl ResponseWithStatusLine {
     pub fn header<Type : std::fmt::Display>(self : &mut Self, name : &str, value: Type) -> Self {
         assert!(name != "Content-Length");
         self.add_header(name, value);
-        return Self::from(std::mem::take(&mut self.bytes));
+        return Self{bytes: std::mem::take(&mut self.bytes), defaults: self.defaults};
+    }
+
+    /// Leaves out the headers of `ResponseDefaults`, e.g. for responses passed on from another server.
+    pub fn without_defaults(self : &mut Self) -> Self {
+        return Self{bytes: std::mem::take(&mut self.bytes), defaults: false};
     }
 
     pub fn payload(self : &mut Self, bytes: &[u8]) -> ResponseComplete {
         self.add_header("Content-Length", bytes.len());
-        for c in "\r\n".chars() {
-            self.bytes.push(c as u8);
-        }
+        self.end_head();
 
         for c in bytes {
             self.bytes.push(*c);
         }
-        ResponseComplete::from(std::mem::take(&mut self.bytes))
+        self.complete()
+    }
+
+    /// A response with `value` serialized as JSON body.
+    #[cfg(feature = "serde")]
+    pub fn json<T : serde::Serialize + ?Sized>(self : &mut Self, value : &T) -> serde_json::Result<ResponseComplete> {
+        let body = serde_json::to_vec(value)?;
+        self.add_header("Content-Type", "application/json");
+        return Ok(self.payload(&body));
+    }
+
+    /// A response whose body of `len` bytes is read from `reader` while it is sent, so it never
+    /// has to be in memory as a whole. If the reader ends early, sending the response fails.
+    pub fn payload_reader<Reader : std::io::Read + Send + 'static>(self : &mut Self, reader : Reader, len : u64) -> ResponseComplete {
+        self.add_header("Content-Length", len);
+        self.end_head();
+        let mut response = self.complete();
+        response.stream = Some(BodyStream::Sized(Box::new(reader), len));
+        response
+    }
+
+    /// Like `payload_reader`, for bodies of unknown length. The body is sent in chunks until
+    /// `reader` ends, see `chunked`.
+    pub fn payload_reader_chunked<Reader : std::io::Read + Send + 'static>(self : &mut Self, reader : Reader) -> ResponseComplete {
+        let chunked = self.chunked_head();
+        let mut response = self.complete();
+        response.stream = Some(BodyStream::Chunked(Box::new(reader), chunked));
+        response
+    }
+
+    /// Sends the head of a response whose body is streamed in chunks, for bodies of unknown length.
+    /// Every write on the returned writer is sent as one chunk. Pass `&mut connection` to keep
+    /// using the connection afterwards. HTTP/1.0 doesn't know chunks, so responses with that
+    /// version send the body as it is and the connection has to be closed to end it.
+    pub fn chunked<Sender : std::io::Write>(self : &mut Self, mut out : Sender) -> std::io::Result<ChunkedWriter<Sender>> {
+        let chunked = self.chunked_head();
+        out.write_all(&self.bytes)?;
+        Ok(ChunkedWriter{out: out, chunked: chunked, finished: false})
+    }
+
+    /// Ends the head of a response with a body of unknown length. Returns whether it is chunked.
+    fn chunked_head(self : &mut Self) -> bool {
+        let chunked = !self.bytes.starts_with(Version::Http10.as_str().as_bytes());
+        if chunked {
+            self.add_header("Transfer-Encoding", "chunked");
+        }else{
+            self.add_header("Connection", "close");
+        }
+        self.end_head();
+        return chunked;
     }
 
     pub fn build(self : &mut Self) -> ResponseComplete {
-        for c in "\r\n".chars() {
-            self.bytes.push(c as u8);
+        self.end_head();
+        self.complete()
+    }
+
+    /// Whether a header named `name` was added, compared case insensitively.
+    fn has_header(self : &Self, name : &str) -> bool {
+        self.bytes.split(|c| *c == b'\n').skip(1).any(|line| {
+            line.len() > name.len() && line[..name.len()].eq_ignore_ascii_case(name.as_bytes()) && line[name.len()] == b':'
+        })
+    }
+
+    /// Ends the head with the blank line. HTTP/1.1 requires a Date header, it is added unless
+    /// one was set.
+    fn end_head(self : &mut Self) {
+        if !self.has_header("Date") {
+            self.add_header("Date", current_date());
         }
+        self.bytes.extend_from_slice(b"\r\n");
+    }
 
-        ResponseComplete::from(std::mem::take(&mut self.bytes))
+    /// The response of the finished head and body.
+    fn complete(self : &mut Self) -> ResponseComplete {
+        let mut response = ResponseComplete::from(std::mem::take(&mut self.bytes));
+        response.defaults = self.defaults;
+        response
     }
 }
 
 impl ResponseComplete {
     fn from(bytes: Vec<u8>) -> Self {
         Self {
-            bytes: bytes
+            bytes: bytes,
+            stream: None,
+            trailers: None,
+            sent: 0,
+            defaults: true,
         }
     }
 
-    pub fn send<Sender : std::io::Write>(&self, out: &mut Sender) -> bool {
+    /// Sends trailer fields after a body streamed in chunks by `payload_reader_chunked`, e.g. a
+    /// checksum of the body. `trailers` is called once the reader ended. Their names should be
+    /// announced with a `Trailer` header. Other responses ignore them, as do HTTP/1.0 ones.
+    pub fn trailers<F : FnOnce() -> Vec<(String, String)> + Send + 'static>(mut self, trailers : F) -> Self {
+        self.trailers = Some(Box::new(trailers));
+        self
+    }
+
+    /// The status code of the response, 0 if its head is malformed.
+    pub fn status_code(&self) -> u16 {
+        self.split_head().map(|head| head.status_code()).unwrap_or(0)
+    }
+
+    /// The number of body bytes written by `send`, without the head and chunk framing.
+    pub fn body_sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Whether the response has `Connection: close`, the connection has to be closed after it.
+    pub fn closes_connection(&self) -> bool {
+        let headers = self.split_head().map(|head| head.headers).unwrap_or_default();
+        headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("Connection") && value.split(',').any(|option| option.trim().eq_ignore_ascii_case("close"))
+        })
+    }
+
+    /// Adds `Connection: keep-alive` to the head, HTTP/1.0 clients close the connection after a
+    /// response without it. Responses which already have a `Connection` header are left unchanged.
+    pub fn keep_alive(mut self) -> Self {
+        let head = match self.split_head() {
+            Some(head) if head.get_header("Connection").is_none() => head.len,
+            _ => return self,
+        };
+        // before the blank line ending the head
+        self.bytes.splice(head - 2..head - 2, b"Connection: keep-alive\r\n".iter().copied());
+        self
+    }
+
+    /// The status line and headers of the head. None if the head isn't complete or not UTF-8.
+    fn split_head(&self) -> Option<ResponseHead<'_>> {
+        let len = self.bytes.windows(4).position(|window| window == b"\r\n\r\n")? + 4;
+        let head = std::str::from_utf8(&self.bytes[..len]).ok()?;
+        let mut lines = head.split("\r\n").filter(|line| line.len() > 0);
+        let status_line = lines.next()?;
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+        Some(ResponseHead{len: len, status_line: status_line, headers: headers})
+    }
+
+    /// Writes only the head of the response, the answer to a HEAD request. Its `Content-Length`
+    /// still announces the body a GET request would get. Returns false if writing failed.
+    pub fn send_head<Sender : std::io::Write>(&mut self, out : &mut Sender) -> bool {
+        let head_len = self.split_head().map(|head| head.len).unwrap_or(self.bytes.len());
+        self.stream = None;
+        self.sent = 0;
+        out.write_all(&self.bytes[..head_len]).is_ok()
+    }
+
+    /// Writes the response to `out`. Returns false if that failed, the connection should be closed
+    /// then. A body given as reader is streamed in buffers of a few kilobytes and can only be sent once.
+    pub fn send<Sender : std::io::Write>(&mut self, out: &mut Sender) -> bool {
         let mut buf : &[u8] = &self.bytes;
 
         while buf.len() > 0 {
@@ -135,7 +689,88 @@ impl ResponseComplete {
                 return false;
             }
         }
-        return true;
+
+        match self.stream.take() {
+            None => {
+                let head_len = self.split_head().map(|head| head.len).unwrap_or(self.bytes.len());
+                self.sent = (self.bytes.len() - head_len) as u64;
+                true
+            },
+            Some(BodyStream::Sized(reader, len)) => {
+                // fewer bytes than announced would leave the client waiting for the rest
+                let copied = std::io::copy(&mut std::io::Read::take(reader, len), out);
+                self.sent = *copied.as_ref().unwrap_or(&0);
+                matches!(copied, Ok(sent) if sent == len)
+            },
+            Some(BodyStream::Chunked(mut reader, chunked)) => {
+                let mut writer = ChunkedWriter{out: out, chunked: chunked, finished: false};
+                match std::io::copy(&mut reader, &mut writer) {
+                    Ok(sent) => self.sent = sent,
+                    Err(_) => return false,
+                }
+                let trailers = self.trailers.take().map(|trailers| trailers()).unwrap_or_default();
+                writer.finish_with_trailers(trailers.iter().map(|(name, value)| (name.as_str(), value.as_str()))).is_ok()
+            },
+        }
+    }
+}
+
+impl<Sender : std::io::Write> ChunkedWriter<Sender> {
+    /// Sends the last chunk which ends the body. Dropping the writer does the same but ignores errors.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.write_last_chunk(&[])
+    }
+
+    /// Like `finish`, sending trailer fields after the last chunk. Their names should be announced
+    /// with a `Trailer` header. HTTP/1.0 responses have no chunks and drop them. Names and values
+    /// must not contain line breaks.
+    pub fn finish_with_trailers<'a, I : IntoIterator<Item = (&'a str, &'a str)>>(mut self, trailers : I) -> std::io::Result<()> {
+        let mut fields = Vec::new();
+        for (name, value) in trailers {
+            assert!(!name.contains(['\r', '\n']) && !value.contains(['\r', '\n']));
+            fields.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        self.write_last_chunk(&fields)
+    }
+
+    fn write_last_chunk(&mut self, trailers : &[u8]) -> std::io::Result<()> {
+        self.finished = true;
+        if self.chunked {
+            let mut last = b"0\r\n".to_vec();
+            last.extend_from_slice(trailers);
+            last.extend_from_slice(b"\r\n");
+            self.out.write_all(&last)?;
+        }
+        self.out.flush()
+    }
+}
+
+impl<Sender : std::io::Write> std::io::Write for ChunkedWriter<Sender> {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        // an empty chunk would end the body
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+        if !self.chunked {
+            return self.out.write(buf);
+        }
+        let mut chunk = format!("{:X}\r\n", buf.len()).into_bytes();
+        chunk.extend_from_slice(buf);
+        chunk.extend_from_slice(b"\r\n");
+        self.out.write_all(&chunk)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<Sender : std::io::Write> Drop for ChunkedWriter<Sender> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.write_last_chunk(&[]).ok();
+        }
     }
 }
 
@@ -156,10 +791,12 @@ impl Request {
             request_line : request_line,
             raw_request : raw_text,
             headers : headers,
+            body : Vec::new(),
+            trailers : Vec::new(),
         })
     }
 
-    pub fn headers(self: &Self) -> RequestHeaderIterator {
+    pub fn headers(self: &Self) -> RequestHeaderIterator<'_> {
         return RequestHeaderIterator{raw_request: &self.raw_request, headers: &self.headers};
     }
 
@@ -181,22 +818,157 @@ impl Request {
         return &self.raw_request[range.offset..range.offset + range.len];
     }
     
+    pub fn get_method(self : &Self) -> &Method {
+        return &self.request_line.method;
+    }
+
     pub fn get_uri(self : &Self) -> &str {
         return self.to_slice(self.request_line.request_uri.clone());
     }
 
-    pub fn get_http_version(self : &Self) -> &str {
-        return self.to_slice(self.request_line.http_version.clone());
+    /// The URI without its query string.
+    pub fn get_path(self : &Self) -> &str {
+        let uri = self.get_uri();
+        return uri.split_once('?').map_or(uri, |(path, _)| path);
     }
 
+    /// The path with its escapes decoded, e.g. `/my%20file.html` gives `/my file.html`. '+' is kept
+    /// as it only means a space in query strings. None if an escape is malformed, or the path
+    /// decodes to invalid UTF-8 or contains a NUL character.
+    pub fn get_decoded_path(self : &Self) -> Option<String> {
+        let decoded = String::from_utf8(percent_decode(self.get_path(), false)?).ok()?;
+        if decoded.contains('\0') {
+            return None;
+        }
+        return Some(decoded);
+    }
+
+    /// The raw query string following the '?' of the URI, if there is one.
+    pub fn get_query(self : &Self) -> Option<&str> {
+        return self.get_uri().split_once('?').map(|(_, query)| query);
+    }
+
+    /// The decoded names and values of the query string in their order, as sent by HTML forms:
+    /// `a=1&b=x+y` gives ("a", "1") and ("b", "x y"). A parameter without '=' has an empty value.
+    pub fn query_pairs(self : &Self) -> Vec<(String, String)> {
+        let query = self.get_query().unwrap_or("");
+        return query.split('&')
+            .filter(|pair| pair.len() > 0)
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode_form_component(name), decode_form_component(value))
+            })
+            .collect();
+    }
+
+    pub fn get_version(self : &Self) -> Version {
+        return self.request_line.version;
+    }
+
+    /// Whether the client wants to send further requests on the connection: by default with
+    /// HTTP/1.1, only if asked for with `Connection: keep-alive` with HTTP/1.0.
+    pub fn keep_alive(self : &Self) -> bool {
+        let has_option = |option : &str| self.connection_options().iter().any(|token| token.eq_ignore_ascii_case(option));
+        match self.get_version() {
+            Version::Http10 => has_option("keep-alive"),
+            Version::Http11 => !has_option("close"),
+        }
+    }
+
+    /// The body announced by the Content-Length header or sent with chunked transfer coding,
+    /// empty if there is none.
+    pub fn body(self : &Self) -> &[u8] {
+        return &self.body;
+    }
+
+    /// The trailer fields sent after a chunked body, e.g. a checksum of it. They are kept apart
+    /// from the headers, which were already acted on when they arrived.
+    pub fn trailers(self : &Self) -> impl Iterator<Item = (&str, &str)> {
+        return self.trailers.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+    }
+
+    /// The value of the first trailer field named `name`, compared case insensitively.
+    pub fn get_trailer(self : &Self, name : &str) -> Option<&str> {
+        return self.trailers().find(|(trailer, _)| trailer.eq_ignore_ascii_case(name)).map(|(_, value)| value);
+    }
+
+    /// Deserializes the JSON body. Fails if the Content-Type isn't `application/json` or another
+    /// JSON media type like `application/problem+json`.
+    #[cfg(feature = "serde")]
+    pub fn json<T : serde::de::DeserializeOwned>(self : &Self) -> serde_json::Result<T> {
+        let media_type = self.get_header("Content-Type").and_then(|value| value.split(';').next()).unwrap_or("").trim();
+        let (kind, subtype) = media_type.split_once('/').unwrap_or(("", ""));
+        let subtype = subtype.to_ascii_lowercase();
+        if !kind.eq_ignore_ascii_case("application") || (subtype != "json" && !subtype.ends_with("+json")) {
+            return Err(serde::de::Error::custom("request body is not json"));
+        }
+        return serde_json::from_slice(&self.body);
+    }
+
+    /// The value of the first header named `name`, compared case insensitively.
     pub fn get_header(self : &Self, name : &str) -> Option<&str> {
-        for header in &self.headers {
-            let slice = self.to_slice(header.name.clone());
-            if slice == name {
-                return Some(self.to_slice(header.value.clone()).trim());
+        return self.headers().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value);
+    }
+
+    /// The values of all headers named `name` in the order they were sent. Names are compared
+    /// case insensitively.
+    pub fn get_headers<'a>(self : &'a Self, name : &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers().filter(move |(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    /// The elements of the comma separated lists in all headers named `name`, empty ones skipped.
+    fn list_header<'a>(self : &'a Self, name : &'a str) -> Vec<&'a str> {
+        return self.get_headers(name)
+            .flat_map(|value| value.split(','))
+            .map(|element| element.trim())
+            .filter(|element| element.len() > 0)
+            .collect();
+    }
+
+    /// The length of the body announced by Content-Length, None without the header. Requests
+    /// with an invalid length or several different ones are rejected by `parse_request`.
+    pub fn content_length(self : &Self) -> Option<u64> {
+        return self.parse_content_length().ok().flatten();
+    }
+
+    fn parse_content_length(self : &Self) -> Result<Option<u64>, ParseError> {
+        let mut length = None;
+        // a list of identical values is allowed, some proxies merge repeated headers into one
+        for value in self.get_headers("Content-Length").flat_map(|value| value.split(',')) {
+            let value = value.trim();
+            let parsed = match value.parse::<u64>() {
+                Ok(parsed) if value.bytes().all(|c| c.is_ascii_digit()) => parsed,
+                _ => return Err(ParseError::InvalidRequest(String::from("Invalid Content-Length"))),
+            };
+            if length.is_some_and(|length| length != parsed) {
+                return Err(ParseError::InvalidRequest(String::from("Conflicting Content-Length values")));
             }
+            length = Some(parsed);
         }
-        None
+        return Ok(length);
+    }
+
+    /// The transfer codings applied to the body in the order they were applied, without their
+    /// parameters, e.g. `["gzip", "chunked"]`.
+    pub fn transfer_encoding(self : &Self) -> Vec<&str> {
+        return self.list_header("Transfer-Encoding").into_iter()
+            .map(|coding| coding.split(';').next().unwrap_or("").trim())
+            .collect();
+    }
+
+    /// The options of the Connection headers, e.g. `close` or `upgrade`.
+    pub fn connection_options(self : &Self) -> Vec<&str> {
+        return self.list_header("Connection");
+    }
+
+    /// The protocols the client asks to switch to with the Upgrade headers, e.g. `websocket`.
+    pub fn upgrade_tokens(self : &Self) -> Vec<&str> {
+        return self.list_header("Upgrade");
+    }
+
+    /// Whether the Upgrade headers ask for `protocol`.
+    pub fn wants_upgrade(self : &Self, protocol : &str) -> bool {
+        return self.upgrade_tokens().iter().any(|token| token.eq_ignore_ascii_case(protocol));
     }
 }
 
@@ -205,7 +977,18 @@ impl Request {
 pub enum ParseError {
     Io(std::io::Error),
     Utf(std::str::Utf8Error),
-    InvalidRequest(String)
+    InvalidRequest(String),
+    InvalidResponse(String),
+    /// The request head wasn't complete when the deadline of `RequestLimits::head_timeout` passed.
+    Timeout,
+    /// The request head has more headers or bytes than `RequestLimits` allows.
+    HeadersTooLarge,
+    /// The request line is longer than `RequestLimits::max_request_line` allows.
+    UriTooLong,
+    /// The body is larger than `RequestLimits::max_body_size` allows.
+    BodyTooLarge,
+    /// The request line names a well-formed HTTP version other than 1.0 and 1.1.
+    UnsupportedVersion,
 }
 
 impl StringRange {
@@ -244,7 +1027,12 @@ impl std::fmt::Display for ParseError {
         match self {
             Self::Utf(utf) => utf.fmt(f),
             Self::Io(io) => io.fmt(f),
-            Self::InvalidRequest(msg) => msg.fmt(f),
+            Self::InvalidRequest(msg) | Self::InvalidResponse(msg) => msg.fmt(f),
+            Self::Timeout => f.write_str("Request head not received in time"),
+            Self::HeadersTooLarge => f.write_str("Request head exceeds the limits"),
+            Self::UriTooLong => f.write_str("Request line exceeds the limit"),
+            Self::BodyTooLarge => f.write_str("Body exceeds the maximum size"),
+            Self::UnsupportedVersion => f.write_str("Unsupported HTTP version"),
         }
     }
 }
@@ -252,17 +1040,48 @@ impl std::fmt::Display for ParseError {
 #[allow(dead_code)]
 fn is_ctl(c : char) -> bool { c as usize <= 31 || c as usize == 127 }
 #[allow(dead_code)]
-fn is_upalpha(c : char) -> bool {c >= 'A' && c <= 'Z'}
+fn is_upalpha(c : char) -> bool {c.is_ascii_uppercase()}
 ///
-///```
+///```ignore
 ///assert_eq!(is_loalpha('1'), false);
 ///```
 #[allow(dead_code)]
-fn is_loalpha(c : char) -> bool {c >= 'a' && c <= 'z'}
+fn is_loalpha(c : char) -> bool {c.is_ascii_lowercase()}
 
 #[allow(dead_code)]
 fn is_ascii_char(c : char) -> bool {c as i32 <= 127 && c as i32 >= 0}
 
+/// Decodes the %XX escapes of RFC 3986 section 2.1, and '+' as a space if `plus_as_space` is set
+/// as in form data. Returns None if a '%' isn't followed by two hex digits.
+pub fn percent_decode(text : &str, plus_as_space : bool) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+                decoded.push(u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap());
+                i += 3;
+                continue;
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            c => decoded.push(c),
+        }
+        i += 1;
+    }
+    return Some(decoded);
+}
+
+/// Decodes a name or value of a query string. Malformed ones are kept as they are, invalid UTF-8
+/// is replaced.
+fn decode_form_component(text : &str) -> String {
+    match percent_decode(text, true) {
+        Some(decoded) => String::from_utf8_lossy(&decoded).into_owned(),
+        None => String::from(text),
+    }
+}
+
 fn next_line(s : &str) -> Option<(&str, &str)> {
     if let Some(idx) = s.find("\r\n") {
         return Some((&s[0..idx], &s[idx + 2..]));
@@ -287,13 +1106,18 @@ fn parse_headers(mut headers_lines : &str, offset: usize) -> Result<Vec<Header>,
                 value: StringRange::from_indices(skipped + delim + 1, skipped + line.len()),
             });
         }else{
-            return Err(ParseError::InvalidRequest(String::from(format!("Header '{}' does not contain colon!", line))));
+            return Err(ParseError::InvalidRequest(format!("Header '{}' does not contain colon!", line)));
         }
         skipped += line.len() + 2;
     }
     return Err(ParseError::InvalidRequest(String::from("Didn't find two consecutives CRLFs")));
 }
 
+/// Characters allowed in a token (RFC 7230 section 3.2.6).
+pub(crate) fn is_token_char(c : u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
 fn parse_request_line(text : &str) -> Result<RequestLine, ParseError> {
     let line = if let Some(line_end) = text.find("\r\n") {
         &text[..line_end]
@@ -307,7 +1131,7 @@ fn parse_request_line(text : &str) -> Result<RequestLine, ParseError> {
         return Err(ParseError::InvalidRequest(String::from("Didn't find two spaces in Request-Line")));
     };
 
-    let idx1 = if let Some(second_index) = (&line[idx0 + 1..]).find(' ') {
+    let idx1 = if let Some(second_index) = line[idx0 + 1..].find(' ') {
         second_index + idx0 + 1
     }else{
         return Err(ParseError::InvalidRequest(String::from("Didn't find two spaces in Request-Line")));
@@ -318,52 +1142,368 @@ fn parse_request_line(text : &str) -> Result<RequestLine, ParseError> {
 
     let method_str = &line[0..idx0];
 
+    // methods are case-sensitive tokens
     let m = match method_str {
         "GET" => Method::GET,
+        "HEAD" => Method::HEAD,
+        "POST" => Method::POST,
+        "PUT" => Method::PUT,
+        "DELETE" => Method::DELETE,
+        "OPTIONS" => Method::OPTIONS,
+        "PATCH" => Method::PATCH,
+        "TRACE" => Method::TRACE,
+        _ if method_str.len() > 0 && method_str.bytes().all(is_token_char) => Method::Other(String::from(method_str)),
         _ => {
             return Err(ParseError::InvalidRequest(String::from("Invalid Method")));
         }
     };
 
 
+    let version = match &line[idx1 + 1..] {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        // HTTP-version = "HTTP/" DIGIT "." DIGIT
+        version if version.strip_prefix("HTTP/").is_some_and(|number| {
+            let number = number.as_bytes();
+            number.len() == 3 && number[0].is_ascii_digit() && number[1] == b'.' && number[2].is_ascii_digit()
+        }) => return Err(ParseError::UnsupportedVersion),
+        _ => return Err(ParseError::InvalidRequest(String::from("Invalid HTTP version"))),
+    };
+
     return Ok(RequestLine {
         method : m,
+        version : version,
         request_uri : StringRange::from_indices(idx0 + 1, idx1),
-        http_version : StringRange::from_indices(idx1 + 1, line.len()),
     });
 }
 
+/// Bodies announced to be larger are refused unless `RequestLimits::max_body_size` says
+/// otherwise, so a client can't make the server buffer without limit.
+const MAX_BODY_SIZE : usize = 1 << 20;
+
+/// Longer chunk-size and trailer lines of chunked bodies are refused.
+const MAX_LINE_LENGTH : usize = 4096;
+
+/// Limits applied by `parse_request_with` while a request is received.
+#[derive(Clone, Debug)]
+pub struct RequestLimits {
+    head_timeout : Option<std::time::Duration>,
+    max_headers : usize,
+    max_head_size : usize,
+    max_request_line : usize,
+    max_body_size : usize,
+}
+
+impl RequestLimits {
+    /// The limits of `parse_request`: a request line of at most 8 KiB and at most 100 headers in
+    /// a head of at most 16 KiB, a body of at most 1 MiB, no deadline.
+    pub fn new() -> Self {
+        Self {
+            head_timeout: None,
+            max_headers: 100,
+            max_head_size: 16 << 10,
+            max_request_line: 8 << 10,
+            max_body_size: MAX_BODY_SIZE,
+        }
+    }
+
+    /// Fails with `ParseError::Timeout` if the head isn't complete `timeout` after its first byte
+    /// arrived, so a client sending it byte by byte can't hold the connection forever. As the
+    /// deadline is checked between reads, the connection needs a read timeout for it to be
+    /// effective. Disabled by default.
+    pub fn head_timeout(mut self, timeout : std::time::Duration) -> Self {
+        self.head_timeout = Some(timeout);
+        self
+    }
+
+    /// Requests with more header lines fail with `ParseError::HeadersTooLarge`.
+    pub fn max_headers(mut self, count : usize) -> Self {
+        self.max_headers = count;
+        self
+    }
+
+    /// Heads of more bytes, counting the request line and the blank line, fail with
+    /// `ParseError::HeadersTooLarge` as soon as that many arrived without the end of the head.
+    pub fn max_head_size(mut self, size : usize) -> Self {
+        self.max_head_size = size;
+        self
+    }
+
+    /// Request lines of more bytes, without the CRLF, fail with `ParseError::UriTooLong` as soon
+    /// as that many arrived. The URI makes up most of the line.
+    pub fn max_request_line(mut self, size : usize) -> Self {
+        self.max_request_line = size;
+        self
+    }
+
+    /// Requests announcing a larger body fail with `ParseError::BodyTooLarge` before it is read.
+    /// The body is left unread, so the connection has to be closed after answering them.
+    pub fn max_body_size(mut self, size : usize) -> Self {
+        self.max_body_size = size;
+        self
+    }
+
+    pub fn get_max_head_size(&self) -> usize {
+        self.max_head_size
+    }
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads a request with its body. Bytes following the body are dropped, connections carrying
+/// several requests are read with `RequestStream`.
 pub fn parse_request<Reader : std::io::Read>(reader : &mut Reader) -> Result<Request, ParseError> {
-    let mut request_text = String::new();
+    return parse_request_with(reader, &RequestLimits::new());
+}
+
+/// Reads a request like `parse_request`, applying `limits`.
+pub fn parse_request_with<Reader : std::io::Read>(reader : &mut Reader, limits : &RequestLimits) -> Result<Request, ParseError> {
+    return parse_buffered(reader, limits, &mut Vec::new());
+}
+
+/// Reads a request starting with the bytes in `buffered`, which are replaced with those read
+/// beyond its end.
+fn parse_buffered<Reader : std::io::Read>(reader : &mut Reader, limits : &RequestLimits, buffered : &mut Vec<u8>) -> Result<Request, ParseError> {
+    let mut bytes = std::mem::take(buffered);
     let mut buffer = [0; 1024];
-    let mut payload_offset : Option<usize> = None;
-    while payload_offset == None {
+    // waiting for a kept alive connection's next request doesn't count, a pipelined one already arrived
+    let mut first_byte = if bytes.len() > 0 { Some(std::time::Instant::now()) } else { None };
+    let mut start = 0;
+    let head_len = loop {
+        let line_len = bytes.windows(2).position(|window| window == b"\r\n").unwrap_or(bytes.len());
+        if line_len > limits.max_request_line {
+            return Err(ParseError::UriTooLong);
+        }
+        if let Some(end) = bytes[start..].windows(4).position(|window| window == b"\r\n\r\n") {
+            break start + end + 4;
+        }
+        if bytes.len() > limits.max_head_size {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        if limits.head_timeout.is_some_and(|timeout| first_byte.is_some_and(|received| received.elapsed() >= timeout)) {
+            return Err(ParseError::Timeout);
+        }
+
         let count = reader.read(&mut buffer)?;
-        buffer[count] = 0;
         if count == 0 {
-            break; // no more bytes available. For TcpStream: the connection has been shutdown.
+            break bytes.len(); // no more bytes available. For TcpStream: the connection has been shutdown.
         }
+        first_byte.get_or_insert_with(std::time::Instant::now);
+        // the blank line may be split between two reads
+        start = bytes.len().saturating_sub(3);
+        bytes.extend_from_slice(&buffer[..count]);
+    };
 
-        let append = std::str::from_utf8(&buffer)?;
-        request_text += &append[0..count];
-        let search_slice = if request_text.len() >= append.len() + 3 {
-            &request_text[request_text.len() - append.len() - 3..]
-        }else{
-            &request_text
-        };
-        
+    if head_len > limits.max_head_size {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    let received = bytes.split_off(head_len);
+    let head = String::from_utf8(bytes).map_err(|e| e.utf8_error())?;
+    let mut request = Request::from(head)?;
+    if request.headers.len() > limits.max_headers {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    // HTTP/1.1 requires it, so a server knows which of its hosts is meant
+    if request.get_version() == Version::Http11 && request.get_header("Host").is_none() {
+        return Err(ParseError::InvalidRequest(String::from("Missing Host header")));
+    }
+    (request.body, request.trailers) = read_body(reader, &request, received, buffered, limits.max_body_size)?;
+    return Ok(request);
+}
+
+/// Reads the body announced by the Content-Length or Transfer-Encoding header of `request` and
+/// the trailer fields of a chunked one. `received` are the bytes which were read together with the
+/// head, those following the body end up in `rest`. Bodies are limited to `max_size` bytes.
+fn read_body<Reader : std::io::Read>(reader : &mut Reader, request : &Request, mut received : Vec<u8>, rest : &mut Vec<u8>, max_size : usize) -> Result<ChunkedBody, ParseError> {
+    if request.get_headers("Transfer-Encoding").next().is_some() {
+        // transfer codings were introduced with HTTP/1.1
+        if request.get_version() == Version::Http10 {
+            return Err(ParseError::InvalidRequest(String::from("Transfer-Encoding in an HTTP/1.0 request")));
+        }
+        // both headers could be used to make two servers disagree on where the request ends
+        if request.get_headers("Content-Length").next().is_some() {
+            return Err(ParseError::InvalidRequest(String::from("Content-Length and Transfer-Encoding are both set")));
+        }
+        // chunked has to be the last coding, otherwise the length of the body is unknown
+        let codings = request.transfer_encoding();
+        if !codings.last().is_some_and(|last| last.eq_ignore_ascii_case("chunked")) {
+            return Err(ParseError::InvalidRequest(format!("Unsupported Transfer-Encoding '{}'", codings.join(", "))));
+        }
+        let mut input = std::io::BufReader::new(std::io::Read::chain(&received[..], reader));
+        let body = read_chunked(&mut input, max_size)?;
+        // read ahead into the buffer or not read from `received` yet
+        *rest = input.buffer().to_vec();
+        rest.extend_from_slice(input.into_inner().into_inner().0);
+        return Ok(body);
+    }
+
+    let length = match request.parse_content_length()? {
+        Some(length) => length,
+        None => {
+            *rest = received;
+            return Ok((Vec::new(), Vec::new()));
+        },
+    };
+    if length > max_size as u64 {
+        return Err(ParseError::BodyTooLarge);
+    }
+    let length = length as usize;
+
+    if received.len() > length {
+        *rest = received.split_off(length);
+    }
+    let missing = length - received.len();
+    let mut remaining = std::io::Read::take(reader, missing as u64);
+    std::io::Read::read_to_end(&mut remaining, &mut received)?;
+    if received.len() < length {
+        return Err(ParseError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    return Ok((received, Vec::new()));
+}
+
+/// A connection carrying one request after another. Bytes read beyond the end of a request,
+/// e.g. a pipelined next request, are kept for the next one. Reads return them before reading
+/// from the stream again, so the connection can be passed to `ws::upgrade` after a request.
+/// Writes go to the stream.
+pub struct RequestStream<Stream> {
+    stream : Stream,
+    buffered : Vec<u8>,
+}
+
+impl<Stream : std::io::Read> RequestStream<Stream> {
+    pub fn new(stream : Stream) -> Self {
+        Self {
+            stream: stream,
+            buffered: Vec::new(),
+        }
+    }
 
-        for k in 0..search_slice.len() - 3 {
-            let candidate = &search_slice[k..k + 4];
-            if candidate == "\r\n\r\n" {
-                payload_offset = Some(0); // TODO: calculate payload start
-                break;
+    /// Reads the next request like `parse_request_with`.
+    pub fn read_request(&mut self, limits : &RequestLimits) -> Result<Request, ParseError> {
+        return parse_buffered(&mut self.stream, limits, &mut self.buffered);
+    }
+
+    /// Whether the next bytes are `prefix`, e.g. the HTTP/2 connection preface. Nothing is
+    /// consumed, the bytes are only read as far as needed to tell. Fails with `ParseError::Timeout`
+    /// if they don't arrive within the head timeout of `limits`.
+    pub fn starts_with(&mut self, prefix : &[u8], limits : &RequestLimits) -> Result<bool, ParseError> {
+        let mut buffer = [0; 1024];
+        let mut first_byte = if self.buffered.len() > 0 { Some(std::time::Instant::now()) } else { None };
+        loop {
+            let n = self.buffered.len().min(prefix.len());
+            if self.buffered[..n] != prefix[..n] {
+                return Ok(false);
+            }
+            if n == prefix.len() {
+                return Ok(true);
+            }
+            if limits.head_timeout.is_some_and(|timeout| first_byte.is_some_and(|received| received.elapsed() >= timeout)) {
+                return Err(ParseError::Timeout);
+            }
+            let count = self.stream.read(&mut buffer)?;
+            if count == 0 {
+                return Ok(false);
             }
+            first_byte.get_or_insert_with(std::time::Instant::now);
+            self.buffered.extend_from_slice(&buffer[..count]);
         }
     }
 
-    
-    return Ok(Request::from(request_text)?);
+    pub fn get_ref(&self) -> &Stream { &self.stream }
+
+    pub fn get_mut(&mut self) -> &mut Stream { &mut self.stream }
+
+    /// The stream and the bytes read from it which no request consumed.
+    pub fn into_inner(self) -> (Stream, Vec<u8>) {
+        (self.stream, self.buffered)
+    }
+}
+
+impl<Stream : std::io::Read> std::io::Read for RequestStream<Stream> {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        if self.buffered.len() == 0 {
+            return self.stream.read(buf);
+        }
+        let n = buf.len().min(self.buffered.len());
+        buf[..n].copy_from_slice(&self.buffered[..n]);
+        self.buffered.drain(..n);
+        Ok(n)
+    }
+}
+
+impl<Stream : std::io::Write> std::io::Write for RequestStream<Stream> {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A body and the trailer fields following it.
+type ChunkedBody = (Vec<u8>, Vec<(String, String)>);
+
+/// Decodes a body with chunked transfer coding (RFC 7230 section 4.1) and the trailer fields
+/// after it. Chunk extensions are skipped, bodies of more than `max_size` bytes fail with
+/// `ParseError::BodyTooLarge`.
+fn read_chunked<Reader : std::io::BufRead>(input : &mut Reader, max_size : usize) -> Result<ChunkedBody, ParseError> {
+    let mut body = Vec::new();
+    loop {
+        // chunk-size [ chunk-ext ] CRLF
+        let line = read_line(input)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        if size.len() == 0 || !size.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseError::InvalidRequest(format!("Invalid chunk size '{size}'")));
+        }
+        let size = usize::from_str_radix(size, 16).unwrap_or(usize::MAX);
+        if size == 0 {
+            break;
+        }
+        if size > max_size - body.len() {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        input.read_exact(&mut body[start..])?;
+        if read_line(input)?.len() > 0 {
+            return Err(ParseError::InvalidRequest(String::from("Chunk is longer than its size")));
+        }
+    }
+
+    // the trailer section ends with an empty line
+    let mut trailers = Vec::new();
+    let mut trailer_len = 0;
+    loop {
+        let line = read_line(input)?;
+        if line.len() == 0 {
+            return Ok((body, trailers));
+        }
+        trailer_len += line.len();
+        if trailer_len > MAX_LINE_LENGTH {
+            return Err(ParseError::InvalidRequest(String::from("Trailer exceeds the maximum size")));
+        }
+        let (name, value) = line.split_once(':')
+            .filter(|(name, _)| name.len() > 0 && name.bytes().all(is_token_char))
+            .ok_or_else(|| ParseError::InvalidRequest(format!("Invalid trailer field '{line}'")))?;
+        trailers.push((String::from(name), String::from(value.trim())));
+    }
+}
+
+/// Reads a line ending with CRLF and returns it without the line break.
+fn read_line<Reader : std::io::BufRead>(input : &mut Reader) -> Result<String, ParseError> {
+    let mut line = Vec::new();
+    std::io::BufRead::read_until(&mut std::io::Read::take(&mut *input, MAX_LINE_LENGTH as u64), b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(ParseError::InvalidRequest(String::from("Expected a line ending with CRLF")));
+    }
+    line.truncate(line.len() - 2);
+    return Ok(String::from_utf8(line).map_err(|e| e.utf8_error())?);
 }
 
 
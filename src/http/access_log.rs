@@ -0,0 +1,144 @@
+//! Logs a line per request in the Common or Combined Log Format known from web servers:
+//!
+//! ```text
+//! 127.0.0.1 - alice [10/Oct/2000:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326 0.004
+//! ```
+//!
+//! The last field is the time in seconds from reading the request until the response was sent,
+//! the user is the one of Basic credentials. The log is opt-in, the server records each response
+//! after sending it, websocket upgrades with status 101 and requests it rejected before reading
+//! them, with `-` as request line:
+//!
+//! ```ignore
+//! let start = std::time::Instant::now();
+//! let mut response = router.handle(&req);
+//! response.send(&mut connection);
+//! access_log.record(peer_addr, &req, &response, start.elapsed());
+//! ```
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use super::{auth, Request, ResponseComplete, UtcTime, MONTHS};
+
+/// The fields of a log line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Peer address, identity (always `-`), user, time, request line, status and body size.
+    Common,
+    /// `Common` followed by the `Referer` and `User-Agent` headers.
+    Combined,
+}
+
+#[derive(Clone)]
+enum Sink {
+    Writer(Arc<Mutex<dyn Write + Send>>),
+    #[cfg(feature = "log")]
+    Log,
+}
+
+/// Writes a line per request. Clones share the sink, so one log can be passed to every connection.
+#[derive(Clone)]
+pub struct AccessLog {
+    format : Format,
+    sink : Sink,
+}
+
+/// `value` with quotes, backslashes and bytes which aren't printable ASCII escaped, so a request
+/// can't forge log lines.
+fn escaped(value : &str) -> String {
+    let mut escaped = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'"' | b'\\' => { escaped.push('\\'); escaped.push(byte as char); },
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{byte:02x}")),
+        }
+    }
+    escaped
+}
+
+fn quoted(value : &str) -> String {
+    format!("\"{}\"", escaped(value))
+}
+
+impl AccessLog {
+    /// Writes lines in the Common Log Format to `out`, e.g. a file opened for appending or stdout.
+    pub fn new<W : Write + Send + 'static>(out : W) -> Self {
+        Self {
+            format: Format::Common,
+            sink: Sink::Writer(Arc::new(Mutex::new(out))),
+        }
+    }
+
+    /// Passes lines in the Common Log Format to the `log` facade, at level info with the target
+    /// `access`. Where they end up is up to the logger of the application.
+    #[cfg(feature = "log")]
+    pub fn log() -> Self {
+        Self {
+            format: Format::Common,
+            sink: Sink::Log,
+        }
+    }
+
+    pub fn format(mut self, format : Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Logs `response` to `req` from `peer`, after it was sent. `duration` is the time it took
+    /// since the request was read.
+    pub fn record(&self, peer : Option<SocketAddr>, req : &Request, response : &ResponseComplete, duration : Duration) {
+        self.record_status(peer, req, response.status_code(), response.body_sent(), duration);
+    }
+
+    /// Like `record`, for answers which weren't sent as `ResponseComplete`, e.g. a websocket
+    /// upgrade with status 101. `size` is the number of body bytes.
+    pub fn record_status(&self, peer : Option<SocketAddr>, req : &Request, status : u16, size : u64, duration : Duration) {
+        self.write(self.line(peer, Some(req), status, size, duration));
+    }
+
+    /// Logs a connection answered with `status` and no body before a request could be read, e.g.
+    /// with 408 when the head didn't arrive in time or with 503 when the server is busy.
+    pub fn record_rejected(&self, peer : Option<SocketAddr>, status : u16, duration : Duration) {
+        self.write(self.line(peer, None, status, 0, duration));
+    }
+
+    fn write(&self, line : String) {
+        match &self.sink {
+            Sink::Writer(out) => {
+                let mut out = out.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                // written at once, so lines of concurrent connections don't interleave
+                let _ = out.write_all(format!("{line}\n").as_bytes()).and_then(|_| out.flush());
+            },
+            #[cfg(feature = "log")]
+            Sink::Log => log::info!(target: "access", "{line}"),
+        }
+    }
+
+    fn line(&self, peer : Option<SocketAddr>, req : Option<&Request>, status : u16, size : u64, duration : Duration) -> String {
+        let host = peer.map(|addr| addr.ip().to_string()).unwrap_or_else(|| String::from("-"));
+        let user = match req.and_then(auth::credentials) {
+            Some(auth::Credentials::Basic{user, ..}) if user.len() > 0 => escaped(&user),
+            _ => String::from("-"),
+        };
+        let time = UtcTime::from(SystemTime::now());
+        let request = match req {
+            Some(req) => format!("{} {} {}", req.get_method().as_str(), req.get_uri(), req.get_version().as_str()),
+            None => String::from("-"),
+        };
+        // no body is logged as '-' rather than 0
+        let size = if size > 0 { size.to_string() } else { String::from("-") };
+        let mut line = format!("{host} - {user} [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] {} {status} {size}",
+            time.day, MONTHS[time.month], time.year, time.hour, time.minute, time.second, quoted(&request));
+        if self.format == Format::Combined {
+            let referer = req.and_then(|req| req.get_header("Referer")).map(quoted).unwrap_or_else(|| String::from("\"-\""));
+            let user_agent = req.and_then(|req| req.get_header("User-Agent")).map(quoted).unwrap_or_else(|| String::from("\"-\""));
+            line.push_str(&format!(" {referer} {user_agent}"));
+        }
+        line.push_str(&format!(" {}.{:03}", duration.as_secs(), duration.subsec_millis()));
+        line
+    }
+}
@@ -0,0 +1,146 @@
+//! Authenticates requests with the `Authorization` header, using Basic credentials (RFC 7617) or
+//! bearer tokens (RFC 6750).
+//!
+//! `Auth::protect` wraps the handler of a route. Websocket upgrades are protected by calling
+//! `Auth::check` before `ws::upgrade` and sending the returned response if it fails:
+//!
+//! ```ignore
+//! if let Err(mut response) = auth.check(&req) {
+//!     response.send(&mut connection);
+//!     return;
+//! }
+//! let ws = ws::upgrade(connection, &req)?;
+//! ```
+
+use std::sync::Arc;
+
+use super::{Params, Request, Response, ResponseComplete, StatusCode, Version};
+
+/// The credentials of an `Authorization` header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Credentials {
+    Basic { user : String, password : String },
+    Bearer(String),
+}
+
+type Verify = dyn Fn(&Credentials) -> bool + Send + Sync;
+
+/// Verifies the credentials of requests and answers requests without valid ones with 401.
+#[derive(Clone)]
+pub struct Auth {
+    bearer : bool,
+    realm : String,
+    verify : Arc<Verify>,
+}
+
+fn base64_value(c : u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Decodes padded base64. None if `text` isn't valid base64.
+pub(super) fn base64_decode(text : &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    for (i, block) in text.chunks(4).enumerate() {
+        let last = i + 1 == text.len() / 4;
+        let padding = block.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut value = 0;
+        for c in &block[..4 - padding] {
+            value = value << 6 | base64_value(*c)?;
+        }
+        value <<= 6 * padding;
+        bytes.extend_from_slice(&value.to_be_bytes()[1..4 - padding]);
+    }
+    Some(bytes)
+}
+
+/// The credentials given by `req`. None if it has no `Authorization` header or the header is
+/// malformed or uses another scheme.
+pub fn credentials(req : &Request) -> Option<Credentials> {
+    let (scheme, value) = req.get_header("Authorization")?.split_once(' ')?;
+    let value = value.trim();
+    if scheme.eq_ignore_ascii_case("Basic") {
+        let decoded = String::from_utf8(base64_decode(value)?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        return Some(Credentials::Basic{user: String::from(user), password: String::from(password)});
+    }
+    if scheme.eq_ignore_ascii_case("Bearer") && value.len() > 0 {
+        return Some(Credentials::Bearer(String::from(value)));
+    }
+    None
+}
+
+impl Auth {
+    /// Accepts Basic credentials for which `verify` returns true. `realm` is shown to users by browsers.
+    pub fn basic<F : Fn(&str, &str) -> bool + Send + Sync + 'static>(realm : &str, verify : F) -> Self {
+        Self {
+            bearer: false,
+            realm: String::from(realm),
+            verify: Arc::new(move |credentials| match credentials {
+                Credentials::Basic{user, password} => verify(user, password),
+                Credentials::Bearer(_) => false,
+            }),
+        }
+    }
+
+    /// Accepts bearer tokens for which `verify` returns true.
+    pub fn bearer<F : Fn(&str) -> bool + Send + Sync + 'static>(realm : &str, verify : F) -> Self {
+        Self {
+            bearer: true,
+            realm: String::from(realm),
+            verify: Arc::new(move |credentials| match credentials {
+                Credentials::Bearer(token) => verify(token),
+                Credentials::Basic{..} => false,
+            }),
+        }
+    }
+
+    /// The verified credentials of `req`, or the 401 response asking for credentials.
+    pub fn check(&self, req : &Request) -> Result<Credentials, ResponseComplete> {
+        let given = credentials(req);
+        match given {
+            Some(credentials) if (self.verify)(&credentials) => Ok(credentials),
+            _ => Err(self.challenge(req.get_version(), req.get_header("Authorization").is_some())),
+        }
+    }
+
+    /// Wraps the handler of a route, which is only called for requests with valid credentials.
+    pub fn protect<F>(&self, handler : F) -> impl Fn(&Request, &Params) -> ResponseComplete + Send + Sync + 'static
+        where F : Fn(&Request, &Params) -> ResponseComplete + Send + Sync + 'static
+    {
+        let auth = self.clone();
+        move |req, params| match auth.check(req) {
+            Ok(_) => handler(req, params),
+            Err(response) => response,
+        }
+    }
+
+    /// The 401 response naming the scheme and realm. `rejected` tells bearer clients that the
+    /// token they sent is invalid.
+    fn challenge(&self, version : Version, rejected : bool) -> ResponseComplete {
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let challenge = if !self.bearer {
+            format!("Basic realm=\"{realm}\", charset=\"UTF-8\"")
+        }else if rejected {
+            format!("Bearer realm=\"{realm}\", error=\"invalid_token\"")
+        }else{
+            format!("Bearer realm=\"{realm}\"")
+        };
+        Response::status(version, StatusCode::Unauthorized)
+            .header("WWW-Authenticate", challenge)
+            .payload(b"")
+    }
+}
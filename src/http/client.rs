@@ -0,0 +1,189 @@
+//! The client side of HTTP/1.1: serializing requests and parsing the responses to them.
+//!
+//! Responses are read without consuming anything following them, so the connection can be used
+//! for further requests or, after a 101 response, for the protocol switched to.
+
+use std::io::{BufReader, Read, Write};
+
+use super::{is_token_char, read_chunked, read_line, Method, ParseError, StatusCode, Version, MAX_BODY_SIZE};
+
+/// Responses with more header lines are refused.
+const MAX_HEADERS : usize = 128;
+
+/// A request to send to a server.
+#[derive(Clone, Debug)]
+pub struct ClientRequest {
+    method : Method,
+    target : String,
+    headers : Vec<(String, String)>,
+    body : Vec<u8>,
+}
+
+/// A response read by `parse_response`.
+#[derive(Clone, Debug)]
+pub struct ClientResponse {
+    version : Version,
+    status : StatusCode,
+    headers : Vec<(String, String)>,
+    body : Vec<u8>,
+    trailers : Vec<(String, String)>,
+}
+
+impl ClientRequest {
+    /// A request for `target`, usually the path and query of the URI.
+    pub fn new(method : Method, target : &str) -> Self {
+        Self {
+            method: method,
+            target: String::from(target),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Adds a header. HTTP/1.1 requires a `Host` header. Content-Length is set by `to_bytes`.
+    pub fn header<Type : std::fmt::Display>(mut self, name : &str, value : Type) -> Self {
+        assert!(!name.eq_ignore_ascii_case("Content-Length"));
+        self.headers.push((String::from(name), value.to_string()));
+        self
+    }
+
+    pub fn body<B : Into<Vec<u8>>>(mut self, body : B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn get_method(&self) -> &Method { &self.method }
+
+    /// The request as it is sent. A body is announced with Content-Length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("{} {} HTTP/1.1\r\n", self.method.as_str(), self.target).into_bytes();
+        for (name, value) in &self.headers {
+            bytes.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        if self.body.len() > 0 || matches!(self.method, Method::POST | Method::PUT | Method::PATCH) {
+            bytes.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+impl ClientResponse {
+    pub fn get_version(&self) -> Version { self.version }
+
+    pub fn get_status(&self) -> &StatusCode { &self.status }
+
+    /// The value of the first header named `name`, compared case insensitively.
+    pub fn get_header(&self, name : &str) -> Option<&str> {
+        self.headers().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn body(&self) -> &[u8] { &self.body }
+
+    /// The trailer fields sent after a chunked body.
+    pub fn trailers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.trailers.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// Errors of the parsing helpers shared with requests refer to the response.
+fn invalid_response(e : ParseError) -> ParseError {
+    match e {
+        ParseError::InvalidRequest(msg) => ParseError::InvalidResponse(msg),
+        ParseError::BodyTooLarge => ParseError::InvalidResponse(String::from("Body exceeds the maximum size")),
+        e => e,
+    }
+}
+
+fn parse_status_line(line : &str) -> Result<(Version, StatusCode), ParseError> {
+    let invalid = || ParseError::InvalidResponse(format!("Invalid status line '{line}'"));
+    // HTTP-version SP status-code SP [ reason-phrase ]
+    let mut parts = line.splitn(3, ' ');
+    let version = match parts.next() {
+        Some("HTTP/1.1") => Version::Http11,
+        Some("HTTP/1.0") => Version::Http10,
+        _ => return Err(invalid()),
+    };
+    let code = parts.next().ok_or_else(invalid)?;
+    if code.len() != 3 || !code.bytes().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let code = code.parse::<u16>().map_err(|_| invalid())?;
+    let reason = parts.next().unwrap_or("");
+    let status = match StatusCode::from_code(code) {
+        StatusCode::Custom(code, _) => StatusCode::Custom(code, String::from(reason)),
+        status => status,
+    };
+    Ok((version, status))
+}
+
+/// Reads the response to a request with `method` from `reader`. Nothing following the response
+/// is consumed, the head is read byte by byte for that. Bodies larger than 1 MiB are refused.
+pub fn parse_response<Reader : Read>(reader : &mut Reader, method : &Method) -> Result<ClientResponse, ParseError> {
+    // a buffer of one byte never holds anything the response doesn't contain
+    let mut input = BufReader::with_capacity(1, reader);
+    let status_line = read_line(&mut input).map_err(invalid_response)?;
+    let (version, status) = parse_status_line(&status_line)?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(&mut input).map_err(invalid_response)?;
+        if line.len() == 0 {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(ParseError::InvalidResponse(String::from("Too many headers")));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| ParseError::InvalidResponse(format!("Invalid header '{line}'")))?;
+        if name.len() == 0 || !name.bytes().all(is_token_char) {
+            return Err(ParseError::InvalidResponse(format!("Invalid header '{line}'")));
+        }
+        headers.push((String::from(name), String::from(value.trim())));
+    }
+    let mut response = ClientResponse{version: version, status: status, headers: headers, body: Vec::new(), trailers: Vec::new()};
+
+    // RFC 7230 section 3.3.3
+    let code = response.status.code();
+    if *method == Method::HEAD || (100..200).contains(&code) || code == 204 || code == 304 {
+        return Ok(response);
+    }
+    if let Some(coding) = response.get_header("Transfer-Encoding") {
+        if !coding.rsplit(',').next().unwrap_or("").trim().eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::InvalidResponse(format!("Unsupported Transfer-Encoding '{coding}'")));
+        }
+        (response.body, response.trailers) = read_chunked(&mut input, MAX_BODY_SIZE).map_err(invalid_response)?;
+        return Ok(response);
+    }
+    let mut body = Vec::new();
+    match response.get_header("Content-Length") {
+        Some(length) => {
+            let length = Some(length).filter(|length| length.len() > 0 && length.bytes().all(|c| c.is_ascii_digit()))
+                .and_then(|length| length.parse::<usize>().ok())
+                .filter(|length| *length <= MAX_BODY_SIZE)
+                .ok_or_else(|| ParseError::InvalidResponse(format!("Invalid Content-Length '{length}'")))?;
+            body.resize(length, 0);
+            input.read_exact(&mut body)?;
+        },
+        None => {
+            // the body ends with the connection
+            Read::take(&mut input, MAX_BODY_SIZE as u64 + 1).read_to_end(&mut body)?;
+            if body.len() > MAX_BODY_SIZE {
+                return Err(ParseError::InvalidResponse(String::from("Body exceeds the maximum size")));
+            }
+        },
+    }
+    response.body = body;
+    Ok(response)
+}
+
+/// Sends `request` on `stream` and reads the response.
+pub fn send<Stream : Read + Write>(stream : &mut Stream, request : &ClientRequest) -> Result<ClientResponse, ParseError> {
+    stream.write_all(&request.to_bytes())?;
+    stream.flush()?;
+    parse_response(stream, &request.method)
+}
@@ -0,0 +1,159 @@
+//! Compresses response bodies with gzip for clients which accept it.
+//!
+//! Bodies in memory are compressed at once and keep their `Content-Length`. Bodies streamed from a
+//! reader are compressed while they are sent, their compressed length isn't known in advance, so
+//! they are sent in chunks. Strong ETags become weak, as the compressed body differs from the
+//! original one byte for byte.
+
+use std::io::Write;
+
+use super::{BodyStream, Request, ResponseComplete, Version};
+
+/// Which responses are compressed, see `Router::compress`.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size : usize,
+    content_types : Vec<String>,
+}
+
+/// Whether the value of an `Accept-Encoding` header allows gzip.
+fn accepts_gzip(value : &str) -> bool {
+    let mut wildcard = false;
+    for coding in value.split(',') {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or("").trim();
+        // a quality of 0 refuses the coding
+        let refused = params.any(|param| {
+            param.trim().strip_prefix("q=").and_then(|q| q.trim().parse::<f32>().ok()) == Some(0.0)
+        });
+        if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+            return !refused;
+        }
+        if name == "*" {
+            wildcard = !refused;
+        }
+    }
+    return wildcard;
+}
+
+impl Compression {
+    /// Compresses text, JavaScript, JSON, SVG and wasm bodies of at least 1 KiB.
+    pub fn new() -> Self {
+        Self {
+            min_size: 1024,
+            content_types: ["text/", "application/javascript", "application/json", "application/wasm", "image/svg+xml"]
+                .into_iter().map(String::from).collect(),
+        }
+    }
+
+    /// Smaller bodies are sent as they are, compressing them gains little.
+    pub fn min_size(mut self, size : usize) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    /// The media types which are compressed, replacing the default ones. A type ending with '/'
+    /// stands for all types below it, e.g. `text/`.
+    pub fn content_types<I : IntoIterator<Item = S>, S : Into<String>>(mut self, types : I) -> Self {
+        self.content_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    fn compresses(&self, content_type : &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        self.content_types.iter().any(|accepted| {
+            if accepted.ends_with('/') {
+                media_type.len() >= accepted.len() && media_type[..accepted.len()].eq_ignore_ascii_case(accepted)
+            }else{
+                media_type.eq_ignore_ascii_case(accepted)
+            }
+        })
+    }
+
+    /// `response` with a gzip compressed body if `req` accepts gzip and the response has a
+    /// successful status, a matching `Content-Type`, no `Content-Encoding` and a large enough body.
+    /// Other responses are returned unchanged.
+    pub fn compress(&self, req : &Request, mut response : ResponseComplete) -> ResponseComplete {
+        if !req.get_header("Accept-Encoding").is_some_and(accepts_gzip) {
+            return response;
+        }
+        let head = match response.split_head() {
+            Some(head) => head,
+            None => return response,
+        };
+        // partial and empty responses are left alone
+        let code = head.status_code();
+        if !(200..300).contains(&code) || code == 204 || code == 206 {
+            return response;
+        }
+        if head.get_header("Content-Encoding").is_some() || !head.get_header("Content-Type").is_some_and(|value| self.compresses(value)) {
+            return response;
+        }
+        let (head_len, status_line) = (head.len, head.status_line);
+        let len = match &response.stream {
+            None => Some((response.bytes.len() - head_len) as u64),
+            Some(BodyStream::Sized(_, len)) => Some(*len),
+            Some(BodyStream::Chunked(..)) => None,
+        };
+        if len.is_some_and(|len| len < self.min_size as u64) {
+            return response;
+        }
+
+        // the compressed length of a stream is only known once everything was read, HTTP/1.0
+        // can only mark its end by closing the connection
+        let chunked = !status_line.starts_with(Version::Http10.as_str());
+        let streamed = matches!(response.stream, Some(BodyStream::Sized(..)));
+        let mut bytes = format!("{status_line}\r\n").into_bytes();
+        for (name, value) in &head.headers {
+            if name.eq_ignore_ascii_case("Content-Length") || (streamed && !chunked && name.eq_ignore_ascii_case("Connection")) {
+                continue;
+            }
+            if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                bytes.extend_from_slice(format!("ETag: W/{value}\r\n").as_bytes());
+                continue;
+            }
+            bytes.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+        }
+        bytes.extend_from_slice(b"Content-Encoding: gzip\r\nVary: Accept-Encoding\r\n");
+
+        let level = flate2::Compression::default();
+        let stream = match response.stream.take() {
+            None => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                let compressed = match encoder.write_all(&response.bytes[head_len..]).and_then(|_| encoder.finish()) {
+                    Ok(compressed) => compressed,
+                    Err(_) => return response,
+                };
+                bytes.extend_from_slice(format!("Content-Length: {}\r\n\r\n", compressed.len()).as_bytes());
+                bytes.extend_from_slice(&compressed);
+                let mut compressed = ResponseComplete::from(bytes);
+                compressed.defaults = response.defaults;
+                return compressed;
+            },
+            Some(BodyStream::Sized(reader, len)) => {
+                if chunked {
+                    bytes.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+                }else{
+                    bytes.extend_from_slice(b"Connection: close\r\n");
+                }
+                let reader = std::io::Read::take(reader, len);
+                BodyStream::Chunked(Box::new(flate2::read::GzEncoder::new(reader, level)), chunked)
+            },
+            Some(BodyStream::Chunked(reader, chunked)) => {
+                BodyStream::Chunked(Box::new(flate2::read::GzEncoder::new(reader, level)), chunked)
+            },
+        };
+        bytes.extend_from_slice(b"\r\n");
+        let mut compressed = ResponseComplete::from(bytes);
+        compressed.stream = Some(stream);
+        compressed.trailers = response.trailers.take();
+        compressed.defaults = response.defaults;
+        compressed
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,205 @@
+//! Serves the files below a document root. Requested paths are resolved and canonicalized before
+//! a file is opened, so `..` segments and symbolic links can't reach anything outside the root.
+//!
+//! A request for a single byte range is answered with that part of the file, so browsers can seek
+//! in media files and resume downloads. Requests for several ranges get the whole file.
+//!
+//! Responses carry an ETag derived from the size and modification time of the file. Requests
+//! whose `If-None-Match` lists it are answered with 304, so reloading a page doesn't transfer
+//! unchanged files again.
+
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use super::{Method, Request, Response, ResponseComplete, StatusCode, Version};
+
+/// Answers requests with the files below `root`. The empty path is answered with `index.html`.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    root : PathBuf,
+}
+
+fn content_type(path : &Path) -> Option<&'static str> {
+    match path.extension()?.to_str()? {
+        "html" => Some("text/html"),
+        "css" => Some("text/css"),
+        "js" => Some("text/javascript"),
+        "wasm" => Some("application/wasm"),
+        "mp4" => Some("video/mp4"),
+        "webm" => Some("video/webm"),
+        "mp3" => Some("audio/mpeg"),
+        "ogg" => Some("audio/ogg"),
+        "wav" => Some("audio/wav"),
+        _ => None,
+    }
+}
+
+/// The part of a file a `Range` header asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Whole,
+    // first and last byte, both included
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// The range requested by the value of a `Range` header, for a file of `len` bytes. Headers which
+/// can't be parsed or ask for several ranges are ignored.
+fn byte_range(value : Option<&str>, len : u64) -> ByteRange {
+    let number = |text : &str| -> Option<u64> {
+        if text.len() == 0 || !text.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        text.parse().ok()
+    };
+    let spec = match value.and_then(|value| value.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Whole,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Whole,
+    };
+
+    if first.len() == 0 {
+        // the last `suffix` bytes
+        return match number(last) {
+            None => ByteRange::Whole,
+            Some(0) => ByteRange::Unsatisfiable,
+            Some(_) if len == 0 => ByteRange::Unsatisfiable,
+            Some(suffix) => ByteRange::Part(len.saturating_sub(suffix), len - 1),
+        };
+    }
+    let start = match number(first) {
+        Some(start) => start,
+        None => return ByteRange::Whole,
+    };
+    let end = if last.len() == 0 { Some(u64::MAX) } else { number(last) };
+    match end {
+        Some(end) if end >= start => {
+            if start >= len {
+                ByteRange::Unsatisfiable
+            }else{
+                ByteRange::Part(start, end.min(len - 1))
+            }
+        },
+        _ => ByteRange::Whole,
+    }
+}
+
+/// A validator which changes whenever the size or modification time of a file changes.
+fn entity_tag(metadata : &std::fs::Metadata) -> String {
+    let modified = metadata.modified().ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+/// Whether the value of an `If-None-Match` header lists `tag`. Weak tags match as well.
+fn lists_tag(value : &str, tag : &str) -> bool {
+    value.trim() == "*" || value.split(',').any(|listed| listed.trim().trim_start_matches("W/") == tag)
+}
+
+fn not_found(version : Version) -> ResponseComplete {
+    Response::status(version, StatusCode::NotFound)
+        .header("Content-Type", "text/html")
+        .payload(b"<b>File Not Found: 404</b>")
+}
+
+fn bad_request(version : Version) -> ResponseComplete {
+    Response::status(version, StatusCode::BadRequest)
+        .header("Content-Type", "text/html")
+        .payload(b"<b>Bad Request: 400</b>")
+}
+
+impl StaticFiles {
+    /// Serves the directory `root`, which has to exist.
+    pub fn new<P : AsRef<Path>>(root : P) -> std::io::Result<Self> {
+        Ok(Self {
+            root: root.as_ref().canonicalize()?,
+        })
+    }
+
+    pub fn root(&self) -> &Path { &self.root }
+
+    /// The file a decoded request path refers to. None if it doesn't exist or lies outside the root.
+    pub fn resolve(&self, path : &str) -> Option<PathBuf> {
+        let relative = path.trim_start_matches('/');
+        let relative = if relative.len() == 0 { "index.html" } else { relative };
+        // canonicalizing removes `..` and follows links, so the prefix check sees the real location
+        let file = self.root.join(relative).canonicalize().ok()?;
+        if !file.starts_with(&self.root) || !file.is_file() {
+            return None;
+        }
+        Some(file)
+    }
+
+    /// The response to `req`: the requested file or the part given by its `Range` header, 400 for
+    /// a malformed path, 404 for files which don't exist, lie outside the root or have an unknown
+    /// type and 416 for ranges beyond the end of the file. 304 if `If-None-Match` lists the current
+    /// ETag of the file. A range is only applied if `If-Range` is missing or names that ETag.
+    /// Methods other than GET and HEAD are answered with 405.
+    pub fn respond(&self, req : &Request) -> ResponseComplete {
+        let version = req.get_version();
+        if !matches!(req.get_method(), Method::GET | Method::HEAD) {
+            return Response::status(version, StatusCode::MethodNotAllowed)
+                .header("Allow", "GET, HEAD")
+                .payload(b"");
+        }
+        let path = match req.get_decoded_path() {
+            Some(path) => path,
+            None => return bad_request(version),
+        };
+        let file = match self.resolve(&path) {
+            Some(file) => file,
+            None => return not_found(version),
+        };
+        let content_type = match content_type(&file) {
+            Some(content_type) => content_type,
+            None => return not_found(version),
+        };
+        // streamed, so big files aren't read into memory at once
+        let (metadata, mut opened) = match std::fs::File::open(&file).and_then(|opened| Ok((opened.metadata()?, opened))) {
+            Ok(opened) => opened,
+            Err(_) => return not_found(version),
+        };
+        let len = metadata.len();
+        let tag = entity_tag(&metadata);
+        if req.get_header("If-None-Match").is_some_and(|value| lists_tag(value, &tag)) {
+            return Response::status(version, StatusCode::NotModified)
+                .header("ETag", &tag)
+                .build();
+        }
+
+        // a range of an older version of the file would be mixed with the current one
+        let range = match req.get_header("If-Range") {
+            Some(value) if value.trim() != tag => None,
+            _ => req.get_header("Range"),
+        };
+        match byte_range(range, len) {
+            ByteRange::Whole => {
+                Response::status(version, StatusCode::Ok)
+                    .header("Content-Type", content_type)
+                    .header("Accept-Ranges", "bytes")
+                    .header("ETag", &tag)
+                    .payload_reader(opened, len)
+            },
+            ByteRange::Part(start, end) => {
+                if opened.seek(SeekFrom::Start(start)).is_err() {
+                    return not_found(version);
+                }
+                Response::status(version, StatusCode::PartialContent)
+                    .header("Content-Type", content_type)
+                    .header("Accept-Ranges", "bytes")
+                    .header("ETag", &tag)
+                    .header("Content-Range", format!("bytes {start}-{end}/{len}"))
+                    .payload_reader(opened, end - start + 1)
+            },
+            ByteRange::Unsatisfiable => {
+                Response::status(version, StatusCode::RangeNotSatisfiable)
+                    .header("Content-Range", format!("bytes */{len}"))
+                    .payload(b"")
+            },
+        }
+    }
+}
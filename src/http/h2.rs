@@ -0,0 +1,833 @@
+//! HTTP/2 (RFC 9113) in front of a `Router`, for clients which negotiated it with ALPN, asked for
+//! it with `Upgrade: h2c` or started with the connection preface right away.
+//!
+//! Streams are multiplexed on the wire, but their requests are answered one after another on the
+//! calling thread, in the order they arrived completely. While a response waits for flow control
+//! credit, frames of other streams are still read, so a client can't stall the connection that
+//! way. Request bodies are limited to 1 MiB like with HTTP/1.1 and server push isn't supported.
+
+pub mod hpack;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::server::Shutdown;
+
+use super::access_log::AccessLog;
+use super::{auth, is_token_char, BodyStream, Method, Request, ResponseComplete, Router, MAX_BODY_SIZE};
+
+/// The first bytes a client sends on an HTTP/2 connection.
+pub const PREFACE : &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// frame types
+const DATA : u8 = 0x0;
+const HEADERS : u8 = 0x1;
+const PRIORITY : u8 = 0x2;
+const RST_STREAM : u8 = 0x3;
+const SETTINGS : u8 = 0x4;
+const PUSH_PROMISE : u8 = 0x5;
+const PING : u8 = 0x6;
+const GOAWAY : u8 = 0x7;
+const WINDOW_UPDATE : u8 = 0x8;
+const CONTINUATION : u8 = 0x9;
+
+// frame flags
+const END_STREAM : u8 = 0x1;
+const ACK : u8 = 0x1;
+const END_HEADERS : u8 = 0x4;
+const PADDED : u8 = 0x8;
+const PRIORITY_FLAG : u8 = 0x20;
+
+// error codes
+const NO_ERROR : u32 = 0x0;
+const PROTOCOL_ERROR : u32 = 0x1;
+const INTERNAL_ERROR : u32 = 0x2;
+const FLOW_CONTROL_ERROR : u32 = 0x3;
+const STREAM_CLOSED : u32 = 0x5;
+const FRAME_SIZE_ERROR : u32 = 0x6;
+const REFUSED_STREAM : u32 = 0x7;
+const COMPRESSION_ERROR : u32 = 0x9;
+const ENHANCE_YOUR_CALM : u32 = 0xb;
+
+// settings
+const SETTINGS_ENABLE_PUSH : u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS : u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE : u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE : u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE : u16 = 0x6;
+
+/// The largest frame we accept, the default of SETTINGS_MAX_FRAME_SIZE.
+const MAX_FRAME_SIZE : usize = 16384;
+const MAX_CONCURRENT_STREAMS : usize = 100;
+/// Announced with SETTINGS_MAX_HEADER_LIST_SIZE, larger header and trailer lists fail the connection.
+const MAX_HEADER_LIST_SIZE : usize = 16 << 10;
+/// The size of the dynamic table of our decoder, the default of SETTINGS_HEADER_TABLE_SIZE.
+const HEADER_TABLE_SIZE : usize = 4096;
+const DEFAULT_WINDOW : i64 = 65535;
+const MAX_WINDOW : i64 = (1 << 31) - 1;
+/// Buffered frames are sent once they reach this size, or before waiting for the client.
+const OUTPUT_BUFFER : usize = 64 << 10;
+
+/// Why a connection ended other than by the client closing it.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// The client violated the protocol. The connection was closed with a GOAWAY frame with this
+    /// error code.
+    Protocol(u32, &'static str),
+}
+
+struct Frame {
+    kind : u8,
+    flags : u8,
+    stream : u32,
+    payload : Vec<u8>,
+}
+
+struct Stream {
+    headers : hpack::HeaderList,
+    body : Vec<u8>,
+    trailers : hpack::HeaderList,
+    // the request was received completely, the stream is half-closed (remote)
+    complete : bool,
+    // the body exceeded the maximum size, the request is answered with 413
+    too_large : bool,
+    // how many bytes of the response the client accepts
+    send_window : i64,
+}
+
+struct Connection<'a, C : Read + Write> {
+    io : C,
+    router : &'a Router,
+    // records every answered stream, with the address of the client
+    access_log : Option<&'a AccessLog>,
+    peer : Option<SocketAddr>,
+    shutdown : Option<&'a Shutdown>,
+    decoder : hpack::Decoder,
+    // streams which are receiving their request or waiting for their response
+    streams : BTreeMap<u32, Stream>,
+    // streams whose request is complete, answered in this order
+    ready : VecDeque<u32>,
+    last_stream : u32,
+    send_window : i64,
+    initial_window : i64,
+    peer_max_frame : usize,
+    // a header block waiting for CONTINUATION frames: stream, END_STREAM flag and fragments so far
+    continuation : Option<(u32, bool, Vec<u8>)>,
+    // either side sent GOAWAY, no new streams are accepted
+    goaway : bool,
+    // the last stream announced in the GOAWAY sent once a shutdown was requested
+    closing : Option<u32>,
+    // frames not sent yet, so small ones share a write
+    output : Vec<u8>,
+}
+
+fn protocol_error<T>(code : u32, reason : &'static str) -> Result<T, Error> {
+    Err(Error::Protocol(code, reason))
+}
+
+/// Serves a connection whose client starts with the connection preface, after ALPN negotiated
+/// `h2` or if the client knows the server speaks HTTP/2. Returns once the client closed the
+/// connection or stayed idle beyond the read timeout. The requests are recorded in `access_log`
+/// like those of HTTP/1.1, as coming from `peer_addr`. Once `shutdown` is requested the client
+/// is told with GOAWAY, the streams it already opened are still answered.
+pub fn serve<C : Read + Write>(connection : C, peer_addr : Option<SocketAddr>, shutdown : Option<&Shutdown>, router : &Router, access_log : Option<&AccessLog>) -> Result<(), Error> {
+    let mut conn = Connection::new(connection, router);
+    conn.access_log = access_log;
+    conn.peer = peer_addr;
+    conn.shutdown = shutdown;
+    conn.run(|conn| {
+        conn.send_settings()?;
+        conn.read_preface()
+    })
+}
+
+/// Whether `req` asks to switch to HTTP/2 without TLS (RFC 7540 section 3.2).
+pub fn wants_h2c(req : &Request) -> bool {
+    req.wants_upgrade("h2c") && req.get_headers("HTTP2-Settings").count() == 1
+}
+
+/// Switches a connection to HTTP/2 after a request for which `wants_h2c` is true. The request
+/// is answered on stream 1, then the connection is served like with `serve`.
+pub fn serve_upgrade<C : Read + Write>(connection : C, peer_addr : Option<SocketAddr>, shutdown : Option<&Shutdown>, req : &Request, router : &Router, access_log : Option<&AccessLog>) -> Result<(), Error> {
+    // base64url without padding
+    let encoded = req.get_headers("HTTP2-Settings").next().unwrap_or("").replace('-', "+").replace('_', "/");
+    let padding = "=".repeat((4 - encoded.len() % 4) % 4);
+    let settings = auth::base64_decode(&(encoded + &padding)).ok_or(Error::Protocol(PROTOCOL_ERROR, "invalid HTTP2-Settings"))?;
+
+    let mut conn = Connection::new(connection, router);
+    conn.access_log = access_log;
+    conn.peer = peer_addr;
+    conn.shutdown = shutdown;
+    conn.run(|conn| {
+        if !settings.len().is_multiple_of(6) {
+            return protocol_error(PROTOCOL_ERROR, "invalid HTTP2-Settings");
+        }
+        conn.apply_settings(&settings)?;
+        conn.io.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n")?;
+        conn.send_settings()?;
+        conn.read_preface()?;
+
+        conn.last_stream = 1;
+        conn.streams.insert(1, Stream::new(conn.initial_window, true));
+        let start = Instant::now();
+        let mut response = router.handle(req);
+        conn.send_response(1, &mut response, req.get_method() == &Method::HEAD)?;
+        conn.record(req, &response, start);
+        Ok(())
+    })
+}
+
+impl Stream {
+    fn new(send_window : i64, complete : bool) -> Self {
+        Self {
+            headers: Vec::new(),
+            body: Vec::new(),
+            trailers: Vec::new(),
+            complete: complete,
+            too_large: false,
+            send_window: send_window,
+        }
+    }
+}
+
+/// A header field as text. None if it contains bytes HTTP/1.1 can't carry.
+fn text_field<'a>(name : &'a [u8], value : &'a [u8]) -> Option<(&'a str, &'a str)> {
+    let value = std::str::from_utf8(value).ok()?;
+    if value.contains(['\r', '\n', '\0']) {
+        return None;
+    }
+    Some((std::str::from_utf8(name).ok()?, value))
+}
+
+/// Whether `name` is valid for a field other than a pseudo-header field. Names are lowercase
+/// and connection-specific fields don't exist in HTTP/2.
+fn is_regular_field(name : &str, value : &str) -> bool {
+    name.len() > 0 && name.bytes().all(|c| is_token_char(c) && !c.is_ascii_uppercase())
+        && !["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"].contains(&name)
+        && (name != "te" || value == "trailers")
+}
+
+/// The request of a stream as if it was sent with HTTP/1.1, so routes don't need to care about
+/// the version. None if the header or trailer fields are malformed (RFC 9113 section 8.2).
+fn build_request(fields : &[(Vec<u8>, Vec<u8>)], trailer_fields : &[(Vec<u8>, Vec<u8>)], body : Vec<u8>) -> Option<Request> {
+    let mut method = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut scheme = None;
+    let mut headers = Vec::new();
+    for (name, value) in fields {
+        let (name, value) = text_field(name, value)?;
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // pseudo-header fields come first and only once
+            let field = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                "scheme" => &mut scheme,
+                _ => return None,
+            };
+            if headers.len() > 0 || field.replace(value).is_some() {
+                return None;
+            }
+            continue;
+        }
+        if !is_regular_field(name, value) {
+            return None;
+        }
+        headers.push((name, value));
+    }
+    let (method, path) = (method?, path?);
+    if path.len() == 0 || path.contains(' ') {
+        return None;
+    }
+
+    let mut text = format!("{method} {path} HTTP/1.1\r\n");
+    if let Some(authority) = authority.filter(|_| !headers.iter().any(|(name, _)| *name == "host")) {
+        text.push_str(&format!("host: {authority}\r\n"));
+    }
+    for (name, value) in headers {
+        text.push_str(&format!("{name}: {value}\r\n"));
+    }
+    text.push_str("\r\n");
+    let mut request = Request::from(text).ok()?;
+    request.body = body;
+    for (name, value) in trailer_fields {
+        let (name, value) = text_field(name, value).filter(|(name, value)| is_regular_field(name, value))?;
+        request.trailers.push((String::from(name), String::from(value)));
+    }
+    Some(request)
+}
+
+impl<'a, C : Read + Write> Connection<'a, C> {
+    fn new(io : C, router : &'a Router) -> Self {
+        Self {
+            io: io,
+            router: router,
+            access_log: None,
+            peer: None,
+            shutdown: None,
+            decoder: hpack::Decoder::new(HEADER_TABLE_SIZE),
+            streams: BTreeMap::new(),
+            ready: VecDeque::new(),
+            last_stream: 0,
+            send_window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            peer_max_frame: MAX_FRAME_SIZE,
+            continuation: None,
+            goaway: false,
+            closing: None,
+            output: Vec::new(),
+        }
+    }
+
+    /// Runs `start` and then answers requests until the connection ends. Connection errors are
+    /// reported to the client with GOAWAY.
+    fn run<F : FnOnce(&mut Self) -> Result<(), Error>>(&mut self, start : F) -> Result<(), Error> {
+        let result = start(self).and_then(|_| self.serve_streams());
+        let code = match &result {
+            Ok(_) => NO_ERROR,
+            Err(Error::Protocol(code, _)) => *code,
+            Err(Error::Io(_)) => return result,
+        };
+        // a shutdown was announced already, streams opened after it were refused
+        if code == NO_ERROR && self.closing.is_some() {
+            self.flush().ok();
+            return result;
+        }
+        let last_stream = self.closing.unwrap_or(self.last_stream);
+        self.write_frame(GOAWAY, 0, 0, &[last_stream.to_be_bytes(), code.to_be_bytes()].concat())
+            .and_then(|_| self.flush()).ok();
+        result
+    }
+
+    fn serve_streams(&mut self) -> Result<(), Error> {
+        loop {
+            while let Some(id) = self.ready.pop_front() {
+                self.respond(id)?;
+            }
+            if self.closing.is_none() && self.shutdown.is_some_and(|shutdown| shutdown.is_requested()) {
+                // no new streams are accepted, the open ones are answered before the connection ends
+                self.closing = Some(self.last_stream);
+                self.goaway = true;
+                self.write_frame(GOAWAY, 0, 0, &[self.last_stream.to_be_bytes(), NO_ERROR.to_be_bytes()].concat())?;
+            }
+            if self.goaway && self.streams.is_empty() {
+                return Ok(());
+            }
+            match self.read_frame()? {
+                Some(frame) => self.handle(frame)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn read_preface(&mut self) -> Result<(), Error> {
+        let mut preface = [0; PREFACE.len()];
+        self.io.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            return protocol_error(PROTOCOL_ERROR, "invalid connection preface");
+        }
+        Ok(())
+    }
+
+    fn send_settings(&mut self) -> Result<(), Error> {
+        let mut payload = Vec::new();
+        for (id, value) in [(SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS), (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE)] {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &payload)
+    }
+
+    /// The next frame, None if the connection ended or stayed idle between two frames.
+    fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        // everything sent so far may be what the client waits for
+        self.flush()?;
+        let mut header = [0; 9];
+        match self.io.read_exact(&mut header) {
+            Ok(()) => {},
+            Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return protocol_error(FRAME_SIZE_ERROR, "frame exceeds the maximum size");
+        }
+        let mut payload = vec![0; len];
+        self.io.read_exact(&mut payload)?;
+        Ok(Some(Frame {
+            kind: header[3],
+            flags: header[4],
+            // the reserved bit is ignored
+            stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+            payload: payload,
+        }))
+    }
+
+    fn write_frame(&mut self, kind : u8, flags : u8, stream : u32, payload : &[u8]) -> Result<(), Error> {
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut frame = vec![len[1], len[2], len[3], kind, flags];
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.output.extend_from_slice(&frame);
+        if self.output.len() >= OUTPUT_BUFFER {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the buffered frames.
+    fn flush(&mut self) -> Result<(), Error> {
+        self.io.write_all(&self.output)?;
+        self.io.flush()?;
+        self.output.clear();
+        Ok(())
+    }
+
+    /// Ends a stream with RST_STREAM.
+    fn reset(&mut self, stream : u32, code : u32) -> Result<(), Error> {
+        self.streams.remove(&stream);
+        self.ready.retain(|id| *id != stream);
+        self.write_frame(RST_STREAM, 0, stream, &code.to_be_bytes())
+    }
+
+    fn window_update(&mut self, stream : u32, increment : usize) -> Result<(), Error> {
+        if increment == 0 {
+            return Ok(());
+        }
+        self.write_frame(WINDOW_UPDATE, 0, stream, &(increment as u32).to_be_bytes())
+    }
+
+    fn handle(&mut self, frame : Frame) -> Result<(), Error> {
+        if let Some((stream, _, _)) = &self.continuation {
+            if frame.kind != CONTINUATION || frame.stream != *stream {
+                return protocol_error(PROTOCOL_ERROR, "expected a CONTINUATION frame");
+            }
+        }
+        // frames which belong to a stream or to the connection
+        let on_stream = matches!(frame.kind, DATA | HEADERS | PRIORITY | RST_STREAM | CONTINUATION);
+        if on_stream && frame.stream == 0 || matches!(frame.kind, SETTINGS | PING | GOAWAY) && frame.stream != 0 {
+            return protocol_error(PROTOCOL_ERROR, "frame on the wrong stream");
+        }
+
+        match frame.kind {
+            DATA => self.on_data(frame),
+            HEADERS => self.on_headers(frame),
+            CONTINUATION => self.on_continuation(frame),
+            PRIORITY => {
+                if frame.payload.len() != 5 {
+                    return self.reset(frame.stream, FRAME_SIZE_ERROR);
+                }
+                Ok(())
+            },
+            RST_STREAM => {
+                if frame.payload.len() != 4 {
+                    return protocol_error(FRAME_SIZE_ERROR, "invalid RST_STREAM frame");
+                }
+                if frame.stream > self.last_stream {
+                    return protocol_error(PROTOCOL_ERROR, "RST_STREAM on an idle stream");
+                }
+                self.streams.remove(&frame.stream);
+                self.ready.retain(|id| *id != frame.stream);
+                Ok(())
+            },
+            SETTINGS => {
+                if frame.flags & ACK != 0 {
+                    if frame.payload.len() != 0 {
+                        return protocol_error(FRAME_SIZE_ERROR, "SETTINGS acknowledgement with payload");
+                    }
+                    return Ok(());
+                }
+                if !frame.payload.len().is_multiple_of(6) {
+                    return protocol_error(FRAME_SIZE_ERROR, "invalid SETTINGS frame");
+                }
+                self.apply_settings(&frame.payload)?;
+                self.write_frame(SETTINGS, ACK, 0, &[])
+            },
+            PUSH_PROMISE => protocol_error(PROTOCOL_ERROR, "clients can't push"),
+            PING => {
+                if frame.payload.len() != 8 {
+                    return protocol_error(FRAME_SIZE_ERROR, "invalid PING frame");
+                }
+                if frame.flags & ACK == 0 {
+                    self.write_frame(PING, ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            },
+            GOAWAY => {
+                self.goaway = true;
+                Ok(())
+            },
+            WINDOW_UPDATE => self.on_window_update(frame),
+            // unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn apply_settings(&mut self, payload : &[u8]) -> Result<(), Error> {
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return protocol_error(PROTOCOL_ERROR, "invalid SETTINGS_ENABLE_PUSH"),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > MAX_WINDOW {
+                        return protocol_error(FLOW_CONTROL_ERROR, "invalid SETTINGS_INITIAL_WINDOW_SIZE");
+                    }
+                    // the windows of open streams change by the difference
+                    let delta = value as i64 - self.initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return protocol_error(FLOW_CONTROL_ERROR, "stream window exceeds the maximum");
+                        }
+                    }
+                    self.initial_window = value as i64;
+                },
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(MAX_FRAME_SIZE as u32..1 << 24).contains(&value) {
+                        return protocol_error(PROTOCOL_ERROR, "invalid SETTINGS_MAX_FRAME_SIZE");
+                    }
+                    self.peer_max_frame = value as usize;
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// The payload of a DATA or HEADERS frame without padding.
+    fn unpadded(frame : &Frame) -> Result<&[u8], Error> {
+        if frame.flags & PADDED == 0 {
+            return Ok(&frame.payload);
+        }
+        let (padding, rest) = frame.payload.split_first().ok_or(Error::Protocol(FRAME_SIZE_ERROR, "missing pad length"))?;
+        if *padding as usize > rest.len() {
+            return protocol_error(PROTOCOL_ERROR, "padding exceeds the payload");
+        }
+        Ok(&rest[..rest.len() - *padding as usize])
+    }
+
+    fn on_data(&mut self, frame : Frame) -> Result<(), Error> {
+        let data = Self::unpadded(&frame)?;
+        let end = frame.flags & END_STREAM != 0;
+        let stream = match self.streams.get_mut(&frame.stream) {
+            Some(stream) if !stream.complete => stream,
+            _ if frame.stream > self.last_stream => return protocol_error(PROTOCOL_ERROR, "DATA on an idle stream"),
+            _ => {
+                // the connection window counts the frame anyway
+                self.window_update(0, frame.payload.len())?;
+                return self.reset(frame.stream, STREAM_CLOSED);
+            },
+        };
+        if stream.body.len() + data.len() > MAX_BODY_SIZE {
+            stream.too_large = true;
+            stream.body.clear();
+        }
+        if !stream.too_large {
+            stream.body.extend_from_slice(data);
+        }
+        if end {
+            stream.complete = true;
+            self.ready.push_back(frame.stream);
+        }
+
+        // the body is buffered anyway, so the credit is returned right away
+        self.window_update(0, frame.payload.len())?;
+        if !end {
+            self.window_update(frame.stream, frame.payload.len())?;
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame : Frame) -> Result<(), Error> {
+        let mut block = Self::unpadded(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            if block.len() < 5 {
+                return protocol_error(FRAME_SIZE_ERROR, "invalid HEADERS frame");
+            }
+            block = &block[5..];
+        }
+        let end_stream = frame.flags & END_STREAM != 0;
+        if frame.flags & END_HEADERS == 0 {
+            self.continuation = Some((frame.stream, end_stream, block.to_vec()));
+            return Ok(());
+        }
+        let block = block.to_vec();
+        self.on_header_block(frame.stream, end_stream, &block)
+    }
+
+    fn on_continuation(&mut self, frame : Frame) -> Result<(), Error> {
+        let (stream, end_stream, mut block) = match self.continuation.take() {
+            Some(continuation) => continuation,
+            None => return protocol_error(PROTOCOL_ERROR, "unexpected CONTINUATION frame"),
+        };
+        block.extend_from_slice(&frame.payload);
+        // compressed blocks are never larger than the decoded list we accept
+        if block.len() > MAX_HEADER_LIST_SIZE {
+            return protocol_error(ENHANCE_YOUR_CALM, "header block exceeds the maximum size");
+        }
+        if frame.flags & END_HEADERS == 0 {
+            self.continuation = Some((stream, end_stream, block));
+            return Ok(());
+        }
+        self.on_header_block(stream, end_stream, &block)
+    }
+
+    fn on_header_block(&mut self, id : u32, end_stream : bool, block : &[u8]) -> Result<(), Error> {
+        // decoded even for refused streams, the decoder's table has to stay in sync. Trailers are
+        // limited like headers, a list beyond the limit leaves the table unusable.
+        let headers = match self.decoder.decode(block, MAX_HEADER_LIST_SIZE) {
+            Ok(headers) => headers,
+            Err(e) => return protocol_error(COMPRESSION_ERROR, e.0),
+        };
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            // trailers, which have to end the request
+            if stream.complete || !end_stream {
+                let code = if stream.complete { STREAM_CLOSED } else { PROTOCOL_ERROR };
+                return self.reset(id, code);
+            }
+            stream.trailers = headers;
+            stream.complete = true;
+            self.ready.push_back(id);
+            return Ok(());
+        }
+        if id <= self.last_stream {
+            return protocol_error(STREAM_CLOSED, "HEADERS on a closed stream");
+        }
+        if id.is_multiple_of(2) {
+            return protocol_error(PROTOCOL_ERROR, "clients use odd stream ids");
+        }
+        self.last_stream = id;
+
+        if self.goaway || self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            return self.reset(id, REFUSED_STREAM);
+        }
+        let mut stream = Stream::new(self.initial_window, end_stream);
+        stream.headers = headers;
+        self.streams.insert(id, stream);
+        if end_stream {
+            self.ready.push_back(id);
+        }
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame : Frame) -> Result<(), Error> {
+        if frame.payload.len() != 4 {
+            return protocol_error(FRAME_SIZE_ERROR, "invalid WINDOW_UPDATE frame");
+        }
+        let increment = (u32::from_be_bytes([frame.payload[0], frame.payload[1], frame.payload[2], frame.payload[3]]) & 0x7fff_ffff) as i64;
+        if frame.stream == 0 {
+            if increment == 0 {
+                return protocol_error(PROTOCOL_ERROR, "WINDOW_UPDATE without increment");
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return protocol_error(FLOW_CONTROL_ERROR, "connection window exceeds the maximum");
+            }
+            return Ok(());
+        }
+        match self.streams.get_mut(&frame.stream) {
+            Some(_) if increment == 0 => self.reset(frame.stream, PROTOCOL_ERROR),
+            Some(stream) => {
+                stream.send_window += increment;
+                if stream.send_window > MAX_WINDOW {
+                    return self.reset(frame.stream, FLOW_CONTROL_ERROR);
+                }
+                Ok(())
+            },
+            None if frame.stream > self.last_stream => protocol_error(PROTOCOL_ERROR, "WINDOW_UPDATE on an idle stream"),
+            // the stream was closed, its updates may still be on the way
+            None => Ok(()),
+        }
+    }
+
+    /// Answers the complete request of stream `id` with the response of the router.
+    fn respond(&mut self, id : u32) -> Result<(), Error> {
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let too_large = stream.too_large;
+        let headers = std::mem::take(&mut stream.headers);
+        let body = std::mem::take(&mut stream.body);
+        let trailers = std::mem::take(&mut stream.trailers);
+        let request = match build_request(&headers, &trailers, body) {
+            Some(request) => request,
+            None => return self.reset(id, PROTOCOL_ERROR),
+        };
+        let start = Instant::now();
+        let mut response = if too_large {
+            let response = super::Response::status(request.get_version(), super::StatusCode::ContentTooLarge).payload(b"");
+            self.router.get_defaults().apply(response)
+        }else{
+            self.router.handle(&request)
+        };
+        self.send_response(id, &mut response, !too_large && request.get_method() == &Method::HEAD)?;
+        self.record(&request, &response, start);
+        Ok(())
+    }
+
+    /// Logs the answer to `request`, which was started at `start`, if there is an access log.
+    fn record(&self, request : &Request, response : &ResponseComplete, start : Instant) {
+        if let Some(access_log) = self.access_log {
+            access_log.record(self.peer, request, response, start.elapsed());
+        }
+    }
+
+    /// Sends `response` on stream `id`, counting the body bytes sent in it.
+    fn send_response(&mut self, id : u32, response : &mut ResponseComplete, head_request : bool) -> Result<(), Error> {
+        let (head_len, status, mut fields) = match response.split_head() {
+            Some(head) => {
+                let fields : Vec<(String, String)> = head.headers.iter()
+                    .map(|(name, value)| (name.to_ascii_lowercase(), String::from(*value)))
+                    .filter(|(name, _)| !["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"].contains(&name.as_str()))
+                    .collect();
+                (head.len, head.status_code(), fields)
+            },
+            None => return self.reset(id, INTERNAL_ERROR),
+        };
+        fields.insert(0, (String::from(":status"), status.to_string()));
+        let stream = response.stream.take();
+        let no_body = head_request || status == 204 || status == 304 || (stream.is_none() && response.bytes.len() == head_len);
+
+        self.send_headers(id, &fields, no_body)?;
+        if no_body {
+            self.streams.remove(&id);
+            return Ok(());
+        }
+        let sent = match stream {
+            None => {
+                let body = response.bytes.split_off(head_len);
+                self.send_data(id, &body, true)?.then_some(body.len() as u64)
+            },
+            Some(BodyStream::Sized(reader, len)) => self.send_reader(id, std::io::Read::take(reader, len), Some(len), true)?,
+            Some(BodyStream::Chunked(reader, _)) => {
+                let trailers = response.trailers.take();
+                let sent = self.send_reader(id, reader, None, trailers.is_none())?;
+                match trailers.map(|trailers| trailers()) {
+                    // an empty trailer section isn't sent, the stream ends with the data
+                    Some(trailers) if sent.is_some() && trailers.len() == 0 => {
+                        let ended = self.send_data(id, &[], true)?;
+                        sent.filter(|_| ended)
+                    },
+                    Some(trailers) if sent.is_some() => {
+                        let fields : Vec<(String, String)> = trailers.into_iter().map(|(name, value)| (name.to_ascii_lowercase(), value)).collect();
+                        self.send_headers(id, &fields, true)?;
+                        sent
+                    },
+                    _ => sent,
+                }
+            },
+        };
+        if let Some(sent) = sent {
+            response.sent = sent;
+            self.streams.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Sends a header block in a HEADERS frame and as many CONTINUATION frames as needed.
+    fn send_headers(&mut self, id : u32, fields : &[(String, String)], end_stream : bool) -> Result<(), Error> {
+        let mut block = Vec::new();
+        hpack::encode(fields.iter().map(|(name, value)| (name.as_str(), value.as_str())), &mut block);
+        let max_frame = self.peer_max_frame;
+        let mut fragments = block.chunks(max_frame).peekable();
+        let first = fragments.next().unwrap_or(&[]);
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        if fragments.peek().is_none() {
+            flags |= END_HEADERS;
+        }
+        self.write_frame(HEADERS, flags, id, first)?;
+        while let Some(fragment) = fragments.next() {
+            let flags = if fragments.peek().is_none() { END_HEADERS } else { 0 };
+            self.write_frame(CONTINUATION, flags, id, fragment)?;
+        }
+        Ok(())
+    }
+
+    /// Streams the body read from `reader`, ending the stream with it if `end`. The number of bytes
+    /// sent, None if the stream was reset meanwhile.
+    fn send_reader<R : Read>(&mut self, id : u32, mut reader : R, len : Option<u64>, end : bool) -> Result<Option<u64>, Error> {
+        let mut buffer = vec![0; MAX_FRAME_SIZE];
+        let mut total = 0;
+        loop {
+            let n = match reader.read(&mut buffer) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => 0,
+            };
+            if n == 0 {
+                if len.is_some_and(|len| total < len) {
+                    // fewer bytes than announced
+                    self.reset(id, INTERNAL_ERROR)?;
+                    return Ok(None);
+                }
+                return Ok(self.send_data(id, &[], end)?.then_some(total));
+            }
+            total += n as u64;
+            // the last chunk of a sized body ends the stream
+            let last = len.is_some_and(|len| total >= len);
+            let sent = self.send_data(id, &buffer[..n], last && end)?;
+            if !sent || last {
+                return Ok(sent.then_some(total));
+            }
+        }
+    }
+
+    /// Sends `data` in DATA frames as the flow control windows allow. While there is no credit,
+    /// frames are read and handled. False if the stream was reset meanwhile.
+    fn send_data(&mut self, id : u32, mut data : &[u8], end : bool) -> Result<bool, Error> {
+        loop {
+            let stream_window = match self.streams.get(&id) {
+                Some(stream) => stream.send_window,
+                None => return Ok(false),
+            };
+            if data.len() == 0 {
+                if end {
+                    self.write_frame(DATA, END_STREAM, id, &[])?;
+                }
+                return Ok(true);
+            }
+            let allowed = self.send_window.min(stream_window).min(self.peer_max_frame as i64);
+            if allowed <= 0 {
+                // requests completed meanwhile are answered after this one
+                match self.read_frame()? {
+                    Some(frame) => self.handle(frame)?,
+                    None => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+                }
+                continue;
+            }
+            let n = data.len().min(allowed as usize);
+            let flags = if end && n == data.len() { END_STREAM } else { 0 };
+            self.write_frame(DATA, flags, id, &data[..n])?;
+            self.send_window -= n as i64;
+            if let Some(stream) = self.streams.get_mut(&id) {
+                stream.send_window -= n as i64;
+            }
+            data = &data[n..];
+            if data.len() == 0 {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e : std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::Protocol(code, reason) => write!(f, "{reason} (error code {code:#x})"),
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! HPACK header compression (RFC 7541).
+//!
+//! The decoder understands the complete format, including Huffman coded strings and the dynamic
+//! table. The encoder only uses the static table and literals which aren't added to the peer's
+//! dynamic table, so it needs no state and the peer's table size doesn't matter.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+/// A header block which can't be decoded. The decoder's state is lost, so the connection has to
+/// be closed with COMPRESSION_ERROR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError(pub &'static str);
+
+/// The static table of RFC 7541 appendix A, index 1 first.
+const STATIC_TABLE : [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// The code and its length in bits of every octet and of EOS, RFC 7541 appendix B.
+const HUFFMAN_CODES : [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+/// Decoded header fields, name and value, in the order they appeared.
+pub type HeaderList = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Entries are counted with their name and value plus this overhead.
+const ENTRY_OVERHEAD : usize = 32;
+
+/// Decodes the header blocks of one direction of a connection.
+pub struct Decoder {
+    // newest entry first
+    dynamic : VecDeque<(Vec<u8>, Vec<u8>)>,
+    size : usize,
+    max_size : usize,
+    // the table size announced to the peer, which size updates can't exceed
+    limit : usize,
+}
+
+/// The symbols ordered by code length and code, with the first code and index of every length.
+/// Huffman codes of the same length are consecutive numbers, so that's enough to decode them.
+struct HuffmanTable {
+    symbols : Vec<u16>,
+    // per code length: first code, index of its symbol in `symbols` and the number of codes
+    lengths : [(u32, usize, usize); 31],
+}
+
+fn huffman_table() -> &'static HuffmanTable {
+    static TABLE : OnceLock<HuffmanTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut symbols : Vec<u16> = (0..257).collect();
+        symbols.sort_by_key(|symbol| (HUFFMAN_CODES[*symbol as usize].1, HUFFMAN_CODES[*symbol as usize].0));
+        let mut lengths = [(0, 0, 0); 31];
+        for (index, symbol) in symbols.iter().enumerate() {
+            let (code, bits) = HUFFMAN_CODES[*symbol as usize];
+            let entry = &mut lengths[bits as usize];
+            if entry.2 == 0 {
+                *entry = (code, index, 0);
+            }
+            entry.2 += 1;
+        }
+        HuffmanTable{symbols: symbols, lengths: lengths}
+    })
+}
+
+fn huffman_decode(bytes : &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let table = huffman_table();
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut bits = 0usize;
+    for byte in bytes {
+        for shift in (0..8).rev() {
+            code = code << 1 | ((byte >> shift) & 1) as u32;
+            bits += 1;
+            let (first, index, count) = table.lengths[bits.min(30)];
+            if bits <= 30 && count > 0 && code >= first && code - first < count as u32 {
+                let symbol = table.symbols[index + (code - first) as usize];
+                if symbol == 256 {
+                    return Err(DecodeError("huffman coded string contains EOS"));
+                }
+                decoded.push(symbol as u8);
+                code = 0;
+                bits = 0;
+            }else if bits >= 30 {
+                return Err(DecodeError("invalid huffman code"));
+            }
+        }
+    }
+    // the padding is a prefix of EOS, i.e. up to 7 one bits
+    if bits > 7 || code != (1 << bits) - 1 {
+        return Err(DecodeError("invalid huffman padding"));
+    }
+    Ok(decoded)
+}
+
+/// Reads an integer whose first byte holds a prefix of `prefix_bits` bits (RFC 7541 section 5.1).
+fn decode_integer(input : &mut &[u8], prefix_bits : u8) -> Result<usize, DecodeError> {
+    let (first, rest) = input.split_first().ok_or(DecodeError("truncated integer"))?;
+    *input = rest;
+    let max_prefix = (1usize << prefix_bits) - 1;
+    let mut value = *first as usize & max_prefix;
+    if value < max_prefix {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (byte, rest) = input.split_first().ok_or(DecodeError("truncated integer"))?;
+        *input = rest;
+        // anything larger than any limit of ours is an error anyway
+        if shift > 21 {
+            return Err(DecodeError("integer too large"));
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn decode_string(input : &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let huffman = input.first().is_some_and(|first| first & 0x80 != 0);
+    let len = decode_integer(input, 7)?;
+    if len > input.len() {
+        return Err(DecodeError("truncated string"));
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    if huffman {
+        return huffman_decode(bytes);
+    }
+    Ok(bytes.to_vec())
+}
+
+fn encode_integer(value : usize, prefix_bits : u8, flags : u8, out : &mut Vec<u8>) {
+    let max_prefix = (1usize << prefix_bits) - 1;
+    if value < max_prefix {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max_prefix as u8);
+    let mut rest = value - max_prefix;
+    while rest >= 0x80 {
+        out.push(0x80 | (rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+fn encode_string(bytes : &[u8], out : &mut Vec<u8>) {
+    encode_integer(bytes.len(), 7, 0, out);
+    out.extend_from_slice(bytes);
+}
+
+/// Appends the header block of `headers` to `out`. Names have to be lowercase.
+pub fn encode<'a, I : IntoIterator<Item = (&'a str, &'a str)>>(headers : I, out : &mut Vec<u8>) {
+    for (name, value) in headers {
+        if let Some(index) = STATIC_TABLE.iter().position(|entry| *entry == (name, value)) {
+            // indexed header field
+            encode_integer(index + 1, 7, 0x80, out);
+            continue;
+        }
+        // literal header field without indexing, with an indexed name if there is one
+        match STATIC_TABLE.iter().position(|(static_name, _)| *static_name == name) {
+            Some(index) => encode_integer(index + 1, 4, 0, out),
+            None => {
+                out.push(0);
+                encode_string(name.as_bytes(), out);
+            },
+        }
+        encode_string(value.as_bytes(), out);
+    }
+}
+
+impl Decoder {
+    /// A decoder whose dynamic table holds up to `limit` bytes, as announced to the peer with
+    /// SETTINGS_HEADER_TABLE_SIZE.
+    pub fn new(limit : usize) -> Self {
+        Self {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit: limit,
+        }
+    }
+
+    fn entry(&self, index : usize) -> Result<(Vec<u8>, Vec<u8>), DecodeError> {
+        if index == 0 {
+            return Err(DecodeError("index 0"));
+        }
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        self.dynamic.get(index - STATIC_TABLE.len() - 1).cloned().ok_or(DecodeError("index beyond the dynamic table"))
+    }
+
+    fn evict(&mut self, max_size : usize) {
+        while self.size > max_size {
+            let (name, value) = self.dynamic.pop_back().unwrap_or_default();
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+
+    fn insert(&mut self, name : Vec<u8>, value : Vec<u8>) {
+        let size = name.len() + value.len() + ENTRY_OVERHEAD;
+        // an entry larger than the table empties it
+        self.evict(self.max_size.saturating_sub(size));
+        if size <= self.max_size {
+            self.size += size;
+            self.dynamic.push_front((name, value));
+        }
+    }
+
+    /// Decodes a complete header block into its names and values. Fails as soon as the list
+    /// exceeds `max_list_size`, counting each field as its name, value and 32 bytes like
+    /// SETTINGS_MAX_HEADER_LIST_SIZE. The table may be out of sync with the encoder then.
+    pub fn decode(&mut self, mut block : &[u8], max_list_size : usize) -> Result<HeaderList, DecodeError> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        // a single byte can repeat a large entry of the dynamic table
+        let mut push = |headers : &mut HeaderList, field : (Vec<u8>, Vec<u8>)| {
+            list_size += field.0.len() + field.1.len() + 32;
+            if list_size > max_list_size {
+                return Err(DecodeError("header list exceeds the maximum size"));
+            }
+            headers.push(field);
+            Ok(())
+        };
+        while let Some(first) = block.first().copied() {
+            if first & 0x80 != 0 {
+                // indexed header field
+                let index = decode_integer(&mut block, 7)?;
+                push(&mut headers, self.entry(index)?)?;
+            }else if first & 0xe0 == 0x20 {
+                // dynamic table size update, only allowed at the start of a block
+                let size = decode_integer(&mut block, 5)?;
+                if headers.len() > 0 || size > self.limit {
+                    return Err(DecodeError("invalid dynamic table size update"));
+                }
+                self.max_size = size;
+                self.evict(size);
+            }else{
+                // literal with incremental indexing, without indexing or never indexed
+                let indexing = first & 0x40 != 0;
+                let index = decode_integer(&mut block, if indexing { 6 } else { 4 })?;
+                let name = if index == 0 { decode_string(&mut block)? } else { self.entry(index)?.0 };
+                let value = decode_string(&mut block)?;
+                if indexing {
+                    self.insert(name.clone(), value.clone());
+                }
+                push(&mut headers, (name, value))?;
+            }
+        }
+        Ok(headers)
+    }
+}
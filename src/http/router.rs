@@ -0,0 +1,158 @@
+//! Dispatches requests to handlers registered per method and path pattern.
+//!
+//! Patterns consist of literal segments and parameters starting with ':', e.g.
+//! `/api/users/:id`. A parameter matches exactly one non-empty segment, its value is decoded and
+//! passed to the handler. Routes are tried in the order they were added.
+
+use super::{percent_decode, Compression, Method, Request, Response, ResponseComplete, ResponseDefaults, StatusCode, Version};
+
+/// Answers a request whose path matched the pattern of its route.
+pub type Handler = Box<dyn Fn(&Request, &Params) -> ResponseComplete + Send + Sync>;
+
+/// The values of the parameters of a pattern, in the order they appear in it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Params {
+    values : Vec<(String, String)>,
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route {
+    method : Method,
+    segments : Vec<Segment>,
+    handler : Handler,
+}
+
+pub struct Router {
+    routes : Vec<Route>,
+    fallback : Handler,
+    compression : Option<Compression>,
+    defaults : ResponseDefaults,
+}
+
+impl Params {
+    /// The value of the parameter `:name`.
+    pub fn get(&self, name : &str) -> Option<&str> {
+        self.values.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+impl Route {
+    /// The parameters if `path` matches the pattern of the route.
+    fn matches(&self, path : &str) -> Option<Params> {
+        let mut params = Params::default();
+        let mut parts = path.split('/').skip(1);
+        for segment in &self.segments {
+            let part = parts.next()?;
+            let decoded = String::from_utf8(percent_decode(part, false)?).ok()?;
+            match segment {
+                Segment::Literal(literal) if *literal == decoded => {},
+                Segment::Param(name) if decoded.len() > 0 => params.values.push((name.clone(), decoded)),
+                _ => return None,
+            }
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+fn not_found(version : Version) -> ResponseComplete {
+    Response::status(version, StatusCode::NotFound)
+        .payload(b"")
+}
+
+impl Router {
+    /// A router answering every request with 404 until routes are added.
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            fallback: Box::new(|req, _| not_found(req.get_version())),
+            compression: None,
+            defaults: ResponseDefaults::new(),
+        }
+    }
+
+    /// Passes requests with `method` whose path matches `pattern` to `handler`.
+    pub fn route<F>(mut self, method : Method, pattern : &str, handler : F) -> Self
+        where F : Fn(&Request, &Params) -> ResponseComplete + Send + Sync + 'static
+    {
+        let segments = pattern.split('/').skip(1).map(|segment| match segment.strip_prefix(':') {
+            Some(name) => Segment::Param(String::from(name)),
+            None => Segment::Literal(String::from(segment)),
+        }).collect();
+        self.routes.push(Route{method: method, segments: segments, handler: Box::new(handler)});
+        self
+    }
+
+    /// Answers the requests no route matched, instead of 404. Requests whose path only matches
+    /// routes of other methods are answered with 405.
+    pub fn fallback<F>(mut self, handler : F) -> Self
+        where F : Fn(&Request, &Params) -> ResponseComplete + Send + Sync + 'static
+    {
+        self.fallback = Box::new(handler);
+        self
+    }
+
+    /// Compresses the responses of all routes and the fallback, see `Compression::compress`.
+    pub fn compress(mut self, compression : Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Adds `defaults` to the responses of all routes and the fallback, see `ResponseDefaults::apply`.
+    pub fn defaults(mut self, defaults : ResponseDefaults) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// The headers added to the responses, e.g. for answering requests the router never sees.
+    pub fn get_defaults(&self) -> &ResponseDefaults {
+        &self.defaults
+    }
+
+    /// The response of the first route matching the method and path of `req`.
+    pub fn handle(&self, req : &Request) -> ResponseComplete {
+        let response = self.dispatch(req);
+        let response = match &self.compression {
+            Some(compression) => compression.compress(req, response),
+            None => response,
+        };
+        self.defaults.apply(response)
+    }
+
+    fn dispatch(&self, req : &Request) -> ResponseComplete {
+        let path = req.get_path();
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            if let Some(params) = route.matches(path) {
+                if route.method == *req.get_method() {
+                    return (route.handler)(req, &params);
+                }
+                if !allowed.contains(&route.method.as_str()) {
+                    allowed.push(route.method.as_str());
+                }
+            }
+        }
+        if allowed.len() > 0 {
+            return Response::status(req.get_version(), StatusCode::MethodNotAllowed)
+                .header("Allow", allowed.join(", "))
+                .payload(b"");
+        }
+        (self.fallback)(req, &Params::default())
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
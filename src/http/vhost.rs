@@ -0,0 +1,124 @@
+//! Serves several sites from one listener, choosing a router by the `Host` header of a request.
+//!
+//! Host patterns are either a name like `example.com` or a wildcard like `*.example.com`, which
+//! matches any subdomain but not `example.com` itself. Names are compared case insensitively,
+//! without the port. Exact names take precedence over wildcards, wildcards are tried in the order
+//! they were added.
+//!
+//! HTTP/2 requests carry the host as `:authority`, which becomes their `Host` header, so
+//! `VirtualHosts::into_router` serves both protocols.
+
+use super::files::StaticFiles;
+use super::{Request, Response, ResponseComplete, ResponseDefaults, Router, StatusCode};
+
+enum Pattern {
+    Exact(String),
+    // the suffix starting with the dot, e.g. `.example.com`
+    Wildcard(String),
+}
+
+struct Site {
+    pattern : Pattern,
+    router : Router,
+}
+
+/// Dispatches requests to the router registered for their host.
+pub struct VirtualHosts {
+    sites : Vec<Site>,
+    fallback : Option<Router>,
+    defaults : ResponseDefaults,
+}
+
+/// The host name of a `Host` header value, lowercase and without port and trailing dot.
+fn host_name(value : &str) -> String {
+    let value = value.trim();
+    let name = if value.starts_with('[') {
+        // an IPv6 address keeps its brackets, its colons aren't a port
+        value.split_inclusive(']').next().unwrap_or(value)
+    }else{
+        value.split(':').next().unwrap_or(value)
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Pattern {
+    fn matches(&self, host : &str) -> bool {
+        match self {
+            Self::Exact(name) => host == name,
+            Self::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+impl VirtualHosts {
+    /// No sites, requests for an unknown host are answered with 421 and requests without `Host`
+    /// header, only possible with HTTP/1.0, with 404.
+    pub fn new() -> Self {
+        Self {
+            sites: Vec::new(),
+            fallback: None,
+            defaults: ResponseDefaults::new(),
+        }
+    }
+
+    /// Serves the requests for hosts matching `pattern` with `router`.
+    pub fn host(mut self, pattern : &str, router : Router) -> Self {
+        let pattern = match pattern.strip_prefix('*') {
+            Some(suffix) => {
+                assert!(suffix.starts_with('.'), "a wildcard must be followed by a dot: {pattern}");
+                Pattern::Wildcard(host_name(suffix))
+            },
+            None => Pattern::Exact(host_name(pattern)),
+        };
+        self.sites.push(Site{pattern: pattern, router: router});
+        self
+    }
+
+    /// Serves the files below a document root for hosts matching `pattern`, see `host`.
+    pub fn files(self, pattern : &str, files : StaticFiles) -> Self {
+        self.host(pattern, Router::new().fallback(move |req, _| files.respond(req)))
+    }
+
+    /// Serves requests for unknown hosts and without `Host` header with `router`, instead of
+    /// answering them with 421 and 404.
+    pub fn fallback(mut self, router : Router) -> Self {
+        self.fallback = Some(router);
+        self
+    }
+
+    /// Adds `defaults` to the responses of every site, after the defaults of its own router, and
+    /// to the 421 and 404 answers.
+    pub fn defaults(mut self, defaults : ResponseDefaults) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// The router for `host`, a `Host` header value.
+    fn router(&self, host : &str) -> Option<&Router> {
+        let host = host_name(host);
+        let exact = self.sites.iter().find(|site| matches!(site.pattern, Pattern::Exact(_)) && site.pattern.matches(&host));
+        let site = exact.or_else(|| self.sites.iter().find(|site| site.pattern.matches(&host)));
+        site.map(|site| &site.router)
+    }
+
+    /// The response of the router registered for the host of `req`.
+    pub fn handle(&self, req : &Request) -> ResponseComplete {
+        let host = req.get_header("Host");
+        if let Some(router) = host.and_then(|host| self.router(host)).or(self.fallback.as_ref()) {
+            return self.defaults.apply(router.handle(req));
+        }
+        let status = if host.is_some() { StatusCode::MisdirectedRequest } else { StatusCode::NotFound };
+        self.defaults.apply(Response::status(req.get_version(), status).payload(b""))
+    }
+
+    /// A router passing every request to `handle`, to be served like any other router.
+    pub fn into_router(self) -> Router {
+        Router::new().fallback(move |req, _| self.handle(req))
+    }
+}
+
+impl Default for VirtualHosts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,13 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod codec;
+#[cfg(feature = "std")]
+pub mod ws;
+#[cfg(feature = "std")]
+pub mod http;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "tls")]
+pub mod tls;
@@ -1,138 +1,290 @@
-pub mod ws;
-pub mod http;
+use websocket::{ws, http, server};
+use websocket::http::access_log::{self, AccessLog};
+use websocket::http::files::StaticFiles;
 
 use std::thread;
 
-use std::io::Read;
+/// Echoes every message back to the client.
+struct Echo;
 
-fn handle_ws<Connection: std::io::Read + std::io::Write>(mut socket : ws::Websocket<Connection>) {
-    while !socket.is_closed() {
-        let messages = match socket.read() {
-            Err(e) => {
-                match e {
-                    ws::Error::IoError(e) => {
-                        eprintln!("Io Error: {e}");
-                        Vec::new()
-                    },
-                    ws::Error::WebsocketError(ws_error) => {
-                        eprintln!("Websocket error: {ws_error}");
-                        socket.close(Some(1002)).ok();
-                        return;
-                    },
+impl<Connection: std::io::Read + std::io::Write> ws::Handler<Connection> for Echo {
+    fn on_message(&mut self, socket : &mut ws::Websocket<Connection>, message : ws::Message) {
+        match &message {
+            ws::Message::Binary(binary) => println!("Received {} bytes", binary.len()),
+            ws::Message::Text(text) => println!("Received {} bytes '{}'", text.len(), text),
+            _ => {},
+        }
+        if let Err(e) = socket.send(message) {
+            eprintln!("Error: {e}");
+        }
+    }
+
+    fn on_error(&mut self, _socket : &mut ws::Websocket<Connection>, error : &ws::Error) {
+        eprintln!("Websocket error: {error}");
+    }
+}
+
+fn handle_connection<Connection: std::io::Read + std::io::Write>(connection : Connection, peer_addr : Option<std::net::SocketAddr>, shutdown : &server::Shutdown, router : &http::Router, access_log : Option<&AccessLog>) {
+    let limits = http::RequestLimits::new().head_timeout(HEAD_TIMEOUT);
+    // keeps pipelined requests which were read together with the previous one
+    let mut connection = http::RequestStream::new(connection);
+    // clients knowing that the server speaks HTTP/2 start with its preface right away
+    match connection.starts_with(http::h2::PREFACE, &limits) {
+        Ok(true) => {
+            if let Err(e) = http::h2::serve(connection, peer_addr, Some(shutdown), router, access_log) {
+                eprintln!("HTTP/2 connection failed: {e}");
+            }
+            return;
+        },
+        Ok(false) => {},
+        // a stalled preface isn't worth an answer
+        Err(_) => return,
+    }
+    let mut first = true;
+    loop {
+        let waiting = std::time::Instant::now();
+        let req = match connection.read_request(&limits) {
+            Ok(req) => req,
+            Err(e @ (http::ParseError::Timeout | http::ParseError::HeadersTooLarge | http::ParseError::UriTooLong | http::ParseError::BodyTooLarge | http::ParseError::UnsupportedVersion)) => {
+                let status = match e {
+                    http::ParseError::Timeout => http::StatusCode::RequestTimeout,
+                    http::ParseError::UriTooLong => http::StatusCode::UriTooLong,
+                    http::ParseError::BodyTooLarge => http::StatusCode::ContentTooLarge,
+                    http::ParseError::UnsupportedVersion => http::StatusCode::HttpVersionNotSupported,
+                    _ => http::StatusCode::RequestHeaderFieldsTooLarge,
+                };
+                let response = http::Response::status(http::Version::Http11, status)
+                    .header("Connection", "close")
+                    .payload(b"");
+                let mut response = router.get_defaults().apply(response);
+                response.send(&mut connection);
+                if let Some(access_log) = access_log {
+                    access_log.record_rejected(peer_addr, response.status_code(), waiting.elapsed());
                 }
+                return;
             },
-            Ok(messages) => messages
+            // a kept alive connection ends once the client closes it or stays idle
+            Err(_) if !first => return,
+            Err(e) => {eprintln!("Could parse request ({e})."); return; }
         };
+        first = false;
+        let start = std::time::Instant::now();
 
-        for message in &messages {
-            match message {
-                ws::Message::Binary(binary) => {
-                    println!("Received {} bytes", binary.len());
-                    socket.send_bytes(&binary).unwrap();
-                },
-                ws::Message::Text(text) => {
-                    println!("Received {} bytes '{}'", text.len(), text);
-                    socket.send_text(&text).unwrap();
+        if http::h2::wants_h2c(&req) {
+            if let Err(e) = http::h2::serve_upgrade(connection, peer_addr, Some(shutdown), &req, router, access_log) {
+                eprintln!("HTTP/2 connection failed: {e}");
+            }
+            return;
+        }
+        if req.wants_upgrade("websocket") {
+            let upgrade = ws::upgrade(connection, &req);
+            if let Some(access_log) = access_log {
+                let status = upgrade.as_ref().err().map_or(101, |e| e.status_code());
+                access_log.record_status(peer_addr, &req, status, 0, start.elapsed());
+            }
+            match upgrade {
+                Ok(mut ws) => {
+                    if let Some(addr) = peer_addr {
+                        ws.set_peer_addr(addr);
+                    }
+                    println!("Websocket connection to {}", ws.path().unwrap_or(""));
+                    ws.set_keepalive(std::time::Duration::from_secs(30), std::time::Duration::from_secs(10));
+                    ws.set_shutdown(shutdown);
+                    ws.set_write_timeout(Some(std::time::Duration::from_secs(10)));
+                    ws.run(&mut Echo);
+                    println!("Websocket connection closed");
                 },
-                ws::Message::Close(code) => {
-                    socket.close(*code).ok();
-                    break;
-                }
+                Err(e) => eprintln!("Rejected websocket upgrade: {e}"),
             }
+            return;
+        }
+
+        let mut response = router.handle(&req);
+        let keep_alive = req.keep_alive() && !response.closes_connection();
+        if keep_alive && req.get_version() == http::Version::Http10 {
+            response = response.keep_alive();
+        }
+        // the body of a HEAD response would be taken for the next response
+        let sent = if *req.get_method() == http::Method::HEAD {
+            response.send_head(&mut connection)
+        }else{
+            response.send(&mut connection)
+        };
+        if let Some(access_log) = access_log {
+            access_log.record(peer_addr, &req, &response, start.elapsed());
+        }
+        if !sent || !keep_alive || shutdown.is_requested() {
+            return;
         }
     }
 }
 
-fn send_file(version: &str, filepath: &str) -> Option<http::ResponseComplete> {
-    if filepath.len() == 0 {
-        return send_file(version, "index.html");
-    }
-    let mut f = if let Ok(file) = std::fs::File::open(filepath) {
-        file
-    }else{
-        eprintln!("Could not open file {}", filepath);
-        return None;
-    };
+/// Time a client has to send the head of a request, or the HTTP/2 connection preface.
+const HEAD_TIMEOUT : std::time::Duration = std::time::Duration::from_secs(10);
 
-    let mut data : Vec<u8> = Vec::new();
-    if let Err(_) = f.read_to_end(&mut data) {
+/// With the tls feature the server is started as `websocket <cert.pem> <key.pem>` to serve https and wss.
+#[cfg(feature = "tls")]
+fn tls_config() -> Option<websocket::tls::TlsConfig> {
+    let args : Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
         return None;
     }
-
-    let extension = if let Some(idx) = filepath.rfind('.') {
-        &filepath[idx + 1..]
-    }else{
-        eprintln!("'{}' has no file extension", filepath);
-        return None; // No file extension
-    };
-
-    let content_type = match extension {
-        "html" => "text/html",
-        "css" => "text/css",
-        "js" => "text/javascript",
-        "wasm" => "application/wasm",
-        &_ => {
-            eprintln!("Invalid file extension '{extension}'");
-            return None;
+    match websocket::tls::TlsConfig::from_pem_files(&args[1], &args[2]) {
+        Ok(config) => Some(config.alpn_protocols(&[b"h2", b"http/1.1"])),
+        Err(e) => {
+            eprintln!("Could not load certificate and key: {e}");
+            std::process::exit(1);
         }
-    };
-
-    let response = http::Response::status(version, "Ok", 200)
-        .header("Content-Type", content_type)
-        .payload(&data);
-
-    Some(response)
+    }
 }
 
-fn file_not_found(version: &str) -> http::ResponseComplete {
-    http::Response::status(version, "Not Ok", 404)
-        .header("Content-Type", "text/html")
-        .payload(b"<b>File Not Found: 404</b>")
+/// Connections served at the same time unless overridden by the MAX_CONNECTIONS environment variable.
+const DEFAULT_MAX_CONNECTIONS : usize = 256;
+
+fn max_connections() -> usize {
+    match std::env::var("MAX_CONNECTIONS").map(|value| value.parse()) {
+        Ok(Ok(max)) => max,
+        Ok(Err(_)) => {
+            eprintln!("MAX_CONNECTIONS is not a number, using {DEFAULT_MAX_CONNECTIONS}");
+            DEFAULT_MAX_CONNECTIONS
+        },
+        Err(_) => DEFAULT_MAX_CONNECTIONS,
+    }
 }
 
-fn handle_connection<Connection: std::io::Read + std::io::Write>(mut connection : Connection) {
-    let req = match http::parse_request(&mut connection) {
-        Ok(req) => req,
-        Err(e) => {eprintln!("Could parse request ({e})."); return; }
+/// The ACCESS_LOG environment variable names a file the requests are appended to in the Combined
+/// Log Format, `-` logs to stdout. Without it nothing is logged.
+fn access_log() -> Option<AccessLog> {
+    let path = std::env::var("ACCESS_LOG").ok()?;
+    let log = if path == "-" {
+        AccessLog::new(std::io::stdout())
+    }else{
+        match std::fs::OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => AccessLog::new(file),
+            Err(e) => {
+                eprintln!("Could not open access log {path}: {e}");
+                std::process::exit(1);
+            }
+        }
     };
+    Some(log.format(access_log::Format::Combined))
+}
 
-    if req.get_header("Upgrade") == Some("websocket") {
-        if let Some(ws) = ws::upgrade(connection, &req) {
-            handle_ws(ws);
-            println!("Websocket connection closed");
+/// Typing `quit` shuts the server down, giving websocket clients 5 seconds to answer the close.
+fn wait_for_quit(shutdown : server::Shutdown) {
+    let mut line = String::new();
+    while let Ok(n) = std::io::stdin().read_line(&mut line) {
+        if n == 0 {
+            return; // no terminal attached, run until killed
         }
-    }else if req.get_uri().len() > 0 {
-        let path = &req.get_uri()[1..];
-        if let Some(response) = send_file(req.get_http_version(), path) {
-            response.send(&mut connection);
+        if line.trim() == "quit" {
+            println!("Shutting down");
+            if !shutdown.shutdown(1001, "server shutting down", std::time::Duration::from_secs(5)) {
+                eprintln!("{} connections did not close in time", shutdown.active());
+            }
             return;
-        }else{
-            file_not_found(req.get_http_version()).send(&mut connection);
         }
-    }else{
-        file_not_found(req.get_http_version()).send(&mut connection);
+        line.clear();
     }
-    
 }
 
 fn main() {
 
+    #[cfg(feature = "tls")]
+    let tls = tls_config();
+
     let listener = std::net::TcpListener::bind("127.0.0.1:8080").unwrap();
+    // polled so the loop notices a shutdown
+    listener.set_nonblocking(true).unwrap();
 
-    for res in listener.incoming() {
-        if let Ok(connection) = res {
-            match connection.peer_addr() {
-                Ok(addr) => println!("Accepted connection: {}", addr),
-                Err(e) => println!("Accepted connection but could not determine peer address! {}", e)
-            };
-
-            connection.set_read_timeout(Some(std::time::Duration::new(1, 0))).unwrap();
-            thread::spawn(move || {
-                handle_connection(connection);
-            });
-        }else if let Err(e) = res {
-            eprintln!("ERROR: {e}");
-        }
+    let defaults = http::ResponseDefaults::new()
+        .server(concat!("websocket/", env!("CARGO_PKG_VERSION")))
+        .and_then(|defaults| defaults.header("X-Content-Type-Options", "nosniff"))
+        .unwrap();
+
+    // files are served from the working directory
+    let files = StaticFiles::new(".").unwrap();
+    let router = http::Router::new()
+        .fallback(move |req, _| files.respond(req))
+        .compress(http::Compression::new())
+        .defaults(defaults);
+    let router = std::sync::Arc::new(router);
+    let max_connections = max_connections();
+    let access_log = access_log();
+    let shutdown = server::Shutdown::new();
+    let quit = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || wait_for_quit(shutdown))
+    };
 
+    while !shutdown.is_requested() {
+        let connection = match listener.accept() {
+            Ok((connection, _)) => connection,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(std::time::Duration::from_millis(50));
+                continue;
+            },
+            Err(e) => {
+                eprintln!("ERROR: {e}");
+                continue;
+            }
+        };
+        let peer_addr = match connection.peer_addr() {
+            Ok(addr) => {
+                println!("Accepted connection: {}", addr);
+                Some(addr)
+            },
+            Err(e) => {
+                println!("Accepted connection but could not determine peer address! {}", e);
+                None
+            },
+        };
+
+        connection.set_nonblocking(false).unwrap();
+        if shutdown.active() >= max_connections {
+            // answered right away without reading the request, so it can't hold up the loop
+            let mut connection = connection;
+            connection.set_write_timeout(Some(std::time::Duration::from_millis(100))).ok();
+            let response = http::Response::status(http::Version::Http11, http::StatusCode::ServiceUnavailable)
+                .header("Connection", "close")
+                .payload(b"");
+            router.get_defaults().apply(response).send(&mut connection);
+            if let Some(access_log) = &access_log {
+                access_log.record_rejected(peer_addr, 503, std::time::Duration::ZERO);
+            }
+            continue;
+        }
+        connection.set_read_timeout(Some(std::time::Duration::new(1, 0))).unwrap();
+        connection.set_write_timeout(Some(std::time::Duration::new(1, 0))).unwrap();
+        #[cfg(feature = "tls")]
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
+        let router = router.clone();
+        let access_log = access_log.clone();
+        let guard = shutdown.register();
+        thread::spawn(move || {
+            let _guard = guard;
+            #[cfg(feature = "tls")]
+            if let Some(tls) = tls {
+                // https is served by the same code as http, only the stream differs
+                match tls.accept(connection) {
+                    Ok(mut stream) if stream.conn.alpn_protocol() == Some(b"h2") => {
+                        if let Err(e) = http::h2::serve(&mut stream, peer_addr, Some(&shutdown), &router, access_log.as_ref()) {
+                            eprintln!("HTTP/2 connection failed: {e}");
+                        }
+                        websocket::tls::close(&mut stream).ok();
+                    },
+                    Ok(mut stream) => {
+                        handle_connection(&mut stream, peer_addr, &shutdown, &router, access_log.as_ref());
+                        websocket::tls::close(&mut stream).ok();
+                    },
+                    Err(e) => eprintln!("TLS handshake failed: {e}"),
+                }
+                return;
+            }
+            handle_connection(connection, peer_addr, &shutdown, &router, access_log.as_ref());
+        });
     }
+
+    quit.join().unwrap();
 }
@@ -0,0 +1,96 @@
+//! Coordination between an accept loop and its connection threads, for shutting a server down
+//! without dropping clients abruptly.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shared between the accept loop and every connection. Cloning gives another handle to the same
+/// shutdown state.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner : Arc<Inner>,
+}
+
+struct Inner {
+    state : Mutex<State>,
+    // notified whenever a connection ends
+    changed : Condvar,
+}
+
+struct State {
+    request : Option<Request>,
+    active : usize,
+}
+
+/// The close frame sent to every connection and until when their answers are awaited.
+#[derive(Clone)]
+pub struct Request {
+    pub code : u16,
+    pub reason : String,
+    pub deadline : Instant,
+}
+
+/// Counts a connection as active until it is dropped. Created by `Shutdown::register`.
+pub struct ConnectionGuard {
+    inner : Arc<Inner>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State{request: None, active: 0}),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Marks a connection as active. Websockets given this shutdown with `Websocket::set_shutdown`
+    /// are closed by `shutdown` while running.
+    pub fn register(&self) -> ConnectionGuard {
+        self.lock().active += 1;
+        ConnectionGuard{inner: self.inner.clone()}
+    }
+
+    /// The number of registered connections which haven't ended yet.
+    pub fn active(&self) -> usize { self.lock().active }
+
+    pub fn is_requested(&self) -> bool { self.lock().request.is_some() }
+
+    /// The pending shutdown, if one was requested.
+    pub fn request(&self) -> Option<Request> { self.lock().request.clone() }
+
+    /// Asks every connection to close with `code` and `reason` and waits until all of them ended
+    /// or `timeout` elapsed. The accept loop should stop once `is_requested` returns true.
+    /// Returns true if all connections ended in time.
+    pub fn shutdown(&self, code : u16, reason : &str, timeout : Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        state.request = Some(Request{code: code, reason: String::from(reason), deadline: deadline});
+        while state.active > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.inner.changed.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+        }
+        return true;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner()).active -= 1;
+        self.inner.changed.notify_all();
+    }
+}
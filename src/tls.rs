@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+
+/// A TLS server stream. Implements Read + Write, so it can be used wherever a TcpStream is used.
+pub type TlsStream = rustls::StreamOwned<rustls::ServerConnection, std::net::TcpStream>;
+
+/// Server side TLS configuration: the certificate chain and private key presented to clients.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config : Arc<rustls::ServerConfig>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Pem(rustls::pki_types::pem::Error),
+    Tls(rustls::Error),
+}
+
+impl TlsConfig {
+    /// Loads a PEM encoded certificate chain and private key.
+    pub fn from_pem_files(cert_path : &str, key_path : &str) -> Result<Self, Error> {
+        let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(key_path)?;
+        return Self::from_der(certs, key);
+    }
+
+    pub fn from_der(certs : Vec<CertificateDer<'static>>, key : PrivateKeyDer<'static>) -> Result<Self, Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// The protocols offered with ALPN, most preferred first, e.g. `h2` and `http/1.1`. The one
+    /// chosen is `stream.conn.alpn_protocol()` after `accept`.
+    pub fn alpn_protocols(mut self, protocols : &[&[u8]]) -> Self {
+        Arc::make_mut(&mut self.config).alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        self
+    }
+
+    /// Performs the TLS handshake on an accepted connection.
+    pub fn accept(&self, mut stream : std::net::TcpStream) -> Result<TlsStream, Error> {
+        let mut connection = rustls::ServerConnection::new(self.config.clone())?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(rustls::StreamOwned::new(connection, stream))
+    }
+}
+
+/// Ends the TLS session with a close_notify alert before the connection is closed. Without it,
+/// clients can't tell a body which ends with the connection from a truncated one.
+pub fn close(stream : &mut TlsStream) -> std::io::Result<()> {
+    stream.conn.send_close_notify();
+    std::io::Write::flush(stream)
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        return Self::Io(value);
+    }
+}
+
+impl From<rustls::pki_types::pem::Error> for Error {
+    fn from(value: rustls::pki_types::pem::Error) -> Self {
+        return Self::Pem(value);
+    }
+}
+
+impl From<rustls::Error> for Error {
+    fn from(value: rustls::Error) -> Self {
+        return Self::Tls(value);
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Io(io) => io.fmt(f),
+            Self::Pem(pem) => pem.fmt(f),
+            Self::Tls(tls) => tls.fmt(f),
+        }
+    }
+}
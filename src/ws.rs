@@ -1,38 +1,299 @@
+#[cfg(feature = "tokio")]
+pub mod r#async;
+mod deflate;
+pub mod extension;
+pub mod frame;
+mod handler;
+pub mod hub;
+mod iter;
+#[cfg(feature = "mio")]
+pub mod poll;
+pub mod replay;
+mod split;
+pub mod testing;
+mod writer;
+
 use crate::http;
 
 use sha1::{Sha1, Digest};
 
+use deflate::PerMessageDeflate;
+use extension::Extension;
+use frame::{Frame, FrameHeader};
+
+pub use crate::codec::{MaskGenerator, ProtocolError};
+pub use handler::Handler;
+pub use iter::Messages;
+pub use split::{SharedConnection, TryClone, WsReader, WsWriter};
+pub use writer::MessageWriter;
+
+/// Default for `Websocket::set_max_message_size`.
+const DEFAULT_MAX_MESSAGE_SIZE : usize = 64 << 20;
+
+/// Default for `Websocket::set_read_buffer_size`.
+const DEFAULT_READ_BUFFER_SIZE : usize = 16 << 10;
+
+/// How many unanswered pings are remembered for measuring the round-trip time.
+const MAX_PINGS_SENT : usize = 16;
+
+/// The limits of the handshake heads read by `ws::async` and `ws::poll`, so a peer can't make the
+/// other side buffer without limit.
+#[cfg(any(feature = "tokio", feature = "mio"))]
+fn handshake_limits() -> http::RequestLimits {
+    http::RequestLimits::new()
+}
+
+/// Default for `Websocket::set_close_timeout`.
+const DEFAULT_CLOSE_TIMEOUT : std::time::Duration = std::time::Duration::from_secs(5);
+
 pub struct Websocket <Connection : std::io::Read + std::io::Write> {
-    closed : bool,
+    state : State,
+    role : Role,
     connection : Connection,
     incomplete_fragment: IncompleteFragment,
     incomplete_message: IncompleteMessage,
+    // reused by every read for the frames it completed
+    received: Vec<Received>,
+    // the connection is read into this buffer, its length is the most read at once
+    read_buffer: Vec<u8>,
+    // read and try_read keep reading until the connection has no more data
+    drain: bool,
+    // the last read from the connection returned no data
+    last_read_empty: bool,
+    // bytes read after the peer's close frame, which belong to whatever follows the websocket
+    unread_after_close: Vec<u8>,
+    // negotiated extensions in the order they are applied to outgoing frames
+    extensions: Vec<Box<dyn Extension>>,
+    subprotocol: Option<String>,
+    // the Sec-WebSocket-Extensions value of the handshake response
+    extensions_header: String,
+    peer: Peer,
+    keepalive: Option<Keepalive>,
+    last_received: std::time::Instant,
+    accept_unmasked: bool,
+    // messages read from the connection but not handed out by read_message yet
+    pending: std::collections::VecDeque<Message>,
+    max_message_size: usize,
+    // outgoing messages are split into frames of at most this many payload bytes
+    fragment_size: Option<usize>,
+    // encoded frames which the connection didn't accept yet
+    outgoing: Vec<u8>,
+    max_queued: usize,
+    max_outgoing: usize,
+    write_timeout: Option<std::time::Duration>,
+    // when the outgoing buffer last failed to drain
+    write_stalled_since: Option<std::time::Instant>,
+    close_timeout: std::time::Duration,
+    // application state attached with set_context
+    context: Option<Box<dyn std::any::Any + Send>>,
+    // payloads and send times of pings which weren't answered yet, oldest first
+    pings_sent: std::collections::VecDeque<(Vec<u8>, std::time::Instant)>,
+    last_rtt: Option<std::time::Duration>,
+    // closes the connection from `run` once a server shutdown is requested
+    shutdown: Option<crate::server::Shutdown>,
+    // shared with the other half after `split`
+    mask_generator: std::sync::Arc<std::sync::Mutex<dyn MaskGenerator>>,
+    stats: Stats,
+    // frames are logged with a hex dump of up to this many payload bytes
+    trace: Option<usize>,
+    // replaces the `log` facade for traced frames and tolerated violations
+    logger: Option<Logger>,
+    // receives a copy of every byte read from the connection, see `ws::replay`
+    recorder: Option<Box<dyn std::io::Write + Send>>,
+    conformance: Conformance,
+}
+
+/// What is known about the other side from accepting the connection and its upgrade request.
+#[derive(Clone, Default)]
+struct Peer {
+    addr : Option<std::net::SocketAddr>,
+    path : Option<String>,
+    headers : Vec<(String, String)>,
+}
+
+/// Sends a ping after `interval` without incoming traffic and gives up if no pong arrives within `timeout`.
+struct Keepalive {
+    interval : std::time::Duration,
+    timeout : std::time::Duration,
+    ping_sent : Option<std::time::Instant>,
+}
+
+/// Decides whether the value of an Origin header is allowed to upgrade.
+type OriginCheck = dyn Fn(&str) -> bool + Send + Sync;
+
+/// Creates the state of an extension for a new connection.
+type ExtensionFactory = dyn Fn() -> Box<dyn Extension> + Send + Sync;
+
+/// Receives the diagnostic lines of a websocket, see `Websocket::set_logger`.
+type Logger = std::sync::Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Options used while upgrading a connection.
+pub struct Config {
+    permessage_deflate : bool,
+    server_no_context_takeover : bool,
+    client_no_context_takeover : bool,
+    subprotocols : Vec<String>,
+    origin_check : Option<Box<OriginCheck>>,
+    extensions : Vec<Box<ExtensionFactory>>,
 }
 
 struct IncompleteMessage {
     opcode : u8,
-    bytes: Vec<u8>
+    // text messages are checked to be valid UTF-8 unless the peer is trusted
+    validate_utf8 : bool,
+    // a message was started but its final frame hasn't arrived yet
+    in_progress : bool,
+    // payloads of data frames, appended directly by `IncompleteFragment::append` without
+    // extensions. May already contain frames of the following messages.
+    bytes: Vec<u8>,
+    // how many of `bytes` belong to the frames of the current message handled so far
+    assembled : usize,
+    // payload bytes of the handled frames of the current message, as received on the wire
+    wire_bytes : usize,
+    // messages whose final frame was handled, for `Stats::messages_received`
+    completed : u64,
+}
+
+
+/// A frame read from the connection, control frames are already handled.
+enum Received {
+    Data(Fragment),
+    Control(Message),
 }
 
 struct Fragment {
-    bytes: Vec<u8>,
-    payload_offset : usize
+    header : FrameHeader,
+    // empty if the payload was appended to the message bytes directly
+    payload : Vec<u8>,
+    in_message : bool,
 }
 
 struct IncompleteFragment {
-    bytes: Vec<u8>
+    // the encoded header until it is complete, the first `header_len` bytes are valid
+    header_bytes: [u8; FrameHeader::MAX_LEN],
+    header_len: usize,
+    header: Option<FrameHeader>,
+    // payload bytes of the current frame received so far
+    received: usize,
+    // whether the payload goes to the message bytes or to `payload`
+    in_message: bool,
+    payload: Vec<u8>,
+    // payload buffers of handled fragments, reused for the next ones
+    spare: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MessageKind {
+    Text,
+    Binary,
+}
+
+/// How violations of RFC 6455 by the peer are handled, see `Websocket::set_conformance`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Conformance {
+    /// Every violation fails the connection. The default.
+    Strict,
+    /// Violations which don't affect parsing are logged, see `Websocket::set_logger`, and
+    /// tolerated: unexpected masking, unknown reserved bits and invalid close codes.
+    Lenient,
+}
+
+/// How much of an incoming message has arrived, see `Websocket::incoming_progress`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Progress {
+    /// Payload bytes of the message received so far.
+    pub received : usize,
+    /// The size of the message once its final frame announced its length.
+    pub total : Option<usize>,
+}
+
+/// Traffic of a connection, see `Websocket::stats`. Messages are text and binary messages, frames
+/// include control frames. Bytes are counted as read from and written to the connection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Stats {
+    pub frames_received : u64,
+    pub frames_sent : u64,
+    pub messages_received : u64,
+    pub messages_sent : u64,
+    pub bytes_received : u64,
+    pub bytes_sent : u64,
+    /// Connections failed because the peer violated the protocol or a limit.
+    pub protocol_errors : u64,
+}
+
+/// Part of a message as returned by `Websocket::read_chunks`.
+pub enum Chunk {
+    /// Payload of one frame of a data message. `fin` is set on the last chunk of a message.
+    Data { kind: MessageKind, payload: Vec<u8>, fin: bool },
+    /// Close, ping and pong messages, which are never fragmented.
+    Control(Message),
 }
 
+/// The payload of text messages. With the `bytes` feature it is reference-counted, so cloning a
+/// message, e.g. to broadcast it, doesn't copy the text.
+#[cfg(not(feature = "bytes"))]
+pub type TextPayload = String;
+#[cfg(feature = "bytes")]
+pub type TextPayload = bytestring::ByteString;
+
+/// The payload of binary messages, reference-counted with the `bytes` feature like `TextPayload`.
+#[cfg(not(feature = "bytes"))]
+pub type BinaryPayload = Vec<u8>;
+#[cfg(feature = "bytes")]
+pub type BinaryPayload = bytes::Bytes;
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum Message {
-    Text(String),
-    Binary(Vec<u8>),
-    Close(Option<u16>),
+    Text(TextPayload),
+    Binary(BinaryPayload),
+    Close(Option<u16>, Option<String>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+}
+
+/// Lifecycle of the closing handshake (RFC 6455 section 7), see `Websocket::state`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    /// Messages are sent and received.
+    Open,
+    /// A close frame was sent and the peer's answer hasn't arrived yet. Messages can't be sent
+    /// anymore.
+    Closing,
+    /// The closing handshake is complete or the connection was lost.
+    Closed,
+}
+
+/// Which end of the connection this side is. Clients have to mask every frame they send.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Server,
+    Client,
 }
 
 #[derive(Debug)]
 pub enum Error {
     IoError(std::io::Error),
     WebsocketError(&'static str),
+    /// No data was available on a nonblocking connection, returned by `try_read`.
+    WouldBlock,
+    /// Writing to the connection failed with the contained error. The websocket is closed and
+    /// the data which wasn't written yet is dropped.
+    ConnectionClosed(std::io::Error),
+}
+
+/// Why an upgrade request was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeError {
+    /// A header required by the opening handshake is missing, answered with 400.
+    MissingHeader(&'static str),
+    /// A header of the opening handshake has an invalid value, answered with 400.
+    InvalidHeader(&'static str),
+    /// Sec-WebSocket-Version isn't 13, answered with 426.
+    UnsupportedVersion,
+    /// The Origin header didn't pass the configured check, answered with 403.
+    OriginNotAllowed,
+    /// The request doesn't use GET, answered with 405.
+    MethodNotAllowed,
 }
 
 
@@ -54,10 +315,9 @@ fn base64_convert(block : u8) -> char {
     return '/';
 }
 
-fn hash_to_base64(bytes : &[u8]) -> String {
-    assert!(bytes.len() == 20);
+fn base64_encode(bytes : &[u8]) -> String {
     let mut buffer = String::new();
-    buffer.reserve_exact(28);
+    buffer.reserve_exact(bytes.len().div_ceil(3) * 4);
     for i in (0..bytes.len()).step_by(3) {
         if i + 1 >= bytes.len() {
             // two padding bytes
@@ -73,7 +333,7 @@ fn hash_to_base64(bytes : &[u8]) -> String {
             buffer.push('=');
         }else if i + 2 >= bytes.len() {
 
-            let byte0 : u8 = bytes[i + 0];
+            let byte0 : u8 = bytes[i];
             let byte1 : u8 = bytes[i + 1];
             let byte2 : u8 = 0;
 
@@ -86,7 +346,7 @@ fn hash_to_base64(bytes : &[u8]) -> String {
             buffer.push(base64_convert(b2));
             buffer.push('=');
         }else{
-            let byte0 : u8 = bytes[i + 0];
+            let byte0 : u8 = bytes[i];
             let byte1 : u8 = bytes[i + 1];
             let byte2 : u8 = bytes[i + 2];
 
@@ -104,9 +364,13 @@ fn hash_to_base64(bytes : &[u8]) -> String {
     return buffer;
 }
 
-pub fn upgrade<Connection : std::io::Read + std::io::Write>(mut conn : Connection, req : &http::Request) -> Option<Websocket<Connection>> {
-    let key = if let Some(tmp) = req.get_header("Sec-WebSocket-Key") {tmp} else {assert!(false); return None; };
+fn hash_to_base64(bytes : &[u8]) -> String {
+    assert!(bytes.len() == 20);
+    return base64_encode(bytes);
+}
 
+/// Computes the Sec-WebSocket-Accept value belonging to a Sec-WebSocket-Key.
+fn accept_key(key : &str) -> String {
     let hash = {
         let concat = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
         let mut hasher = Sha1::new();
@@ -116,216 +380,613 @@ pub fn upgrade<Connection : std::io::Read + std::io::Write>(mut conn : Connectio
 
     let b64 = hash_to_base64(&hash as &[u8]);
     assert!(b64.len() == 28);
+    return b64;
+}
+
+/// Status codes which may be sent in a close frame (RFC 6455 section 7.4).
+/// 1004-1006 and 1015 are reserved for local use, 0-999 and 1016-2999 are not assigned.
+fn is_valid_close_code(code : u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// The payload of a close frame. A reason can only be given together with a status code and is
+/// limited to 123 bytes.
+fn close_payload(code : Option<u16>, reason : Option<&str>) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    if let Some(code) = code {
+        if !is_valid_close_code(code) {
+            return Err(Error::WebsocketError("invalid close code"));
+        }
+        payload.extend_from_slice(&code.to_be_bytes());
+    }
+    if let Some(reason) = reason {
+        if code.is_none() {
+            return Err(Error::WebsocketError("a close reason requires a status code"));
+        }
+        if reason.len() > 123 {
+            return Err(Error::WebsocketError("close reason exceeds 123 bytes"));
+        }
+        payload.extend_from_slice(reason.as_bytes());
+    }
+    return Ok(payload);
+}
+
+/// The default `MaskGenerator`, drawing masking keys from the operating system's CSPRNG.
+pub struct SystemRandom;
+
+impl MaskGenerator for SystemRandom {
+    fn next_mask(&mut self) -> Option<[u8; 4]> {
+        let mut mask = [0u8; 4];
+        getrandom::getrandom(&mut mask).ok()?;
+        return Some(mask);
+    }
+}
+
+/// Connects to a websocket server listening on `addr` and performs the opening handshake for `path`.
+pub fn connect(addr : &str, path : &str) -> Result<Websocket<std::net::TcpStream>, Error> {
+    let stream = std::net::TcpStream::connect(addr)?;
+    return client_handshake(stream, addr, path);
+}
 
-    http::Response::status(req.get_http_version(), "Switching Protocols", 101)
+/// Performs the client side of the opening handshake on an already established connection.
+pub fn client_handshake<Connection : std::io::Read + std::io::Write>(mut conn : Connection, host : &str, path : &str) -> Result<Websocket<Connection>, Error> {
+    let (request, key) = handshake_request(host, path)?;
+    // the response is read without the frames the server may send right after it
+    let response = match http::client::send(&mut conn, &request) {
+        Ok(response) => response,
+        Err(http::ParseError::Io(e)) => return Err(Error::IoError(e)),
+        Err(_) => return Err(Error::WebsocketError("invalid handshake response")),
+    };
+    check_handshake_response(&response, &key)?;
+
+    Ok(Websocket::<Connection>::client(conn))
+}
+
+/// The upgrade request of a client and the key it has to be answered with.
+fn handshake_request(host : &str, path : &str) -> Result<(http::client::ClientRequest, String), Error> {
+    let mut nonce = [0u8; 16];
+    getrandom::getrandom(&mut nonce).map_err(|_| Error::WebsocketError("could not generate Sec-WebSocket-Key"))?;
+    let key = base64_encode(&nonce);
+
+    let request = http::client::ClientRequest::new(http::Method::GET, path)
+        .header("Host", host)
         .header("Upgrade", "websocket")
         .header("Connection", "Upgrade")
-        .header("Sec-WebSocket-Accept", &b64)
-        .build()
-        .send(&mut conn);
-    
-    Some(Websocket::<Connection>::from(conn))
+        .header("Sec-WebSocket-Key", &key)
+        .header("Sec-WebSocket-Version", 13);
+    Ok((request, key))
 }
 
+/// Checks that the server accepted the upgrade request sent with `key`.
+fn check_handshake_response(response : &http::client::ClientResponse, key : &str) -> Result<(), Error> {
+    if *response.get_status() != http::StatusCode::SwitchingProtocols {
+        return Err(Error::WebsocketError("server did not switch protocols"));
+    }
 
-impl<Connection: std::io::Read + std::io::Write> From<Connection> for Websocket<Connection> {
-    fn from(conn: Connection) -> Websocket<Connection> {
-        Websocket::<Connection> {
-            closed: false,
-            connection : conn,
-            incomplete_fragment: IncompleteFragment {bytes: Vec::new()},
-            incomplete_message: IncompleteMessage{bytes: Vec::new(), opcode: 0},
+    let accept = response.get_header("Sec-WebSocket-Accept");
+    if accept != Some(accept_key(key).as_str()) {
+        return Err(Error::WebsocketError("invalid Sec-WebSocket-Accept"));
+    }
+    Ok(())
+}
+
+/// Answers the upgrade request and turns the connection into a websocket. If the request is
+/// rejected, the matching error response has already been sent.
+pub fn upgrade<Connection : std::io::Read + std::io::Write>(conn : Connection, req : &http::Request) -> Result<Websocket<Connection>, UpgradeError> {
+    upgrade_with_config(conn, req, &Config::default())
+}
+
+pub fn upgrade_with_config<Connection : std::io::Read + std::io::Write>(mut conn : Connection, req : &http::Request, config : &Config) -> Result<Websocket<Connection>, UpgradeError> {
+    let key = match check_upgrade_request(req, config) {
+        Ok(key) => key,
+        Err(e) => {
+            e.response(req.get_version()).send(&mut conn);
+            return Err(e);
         }
+    };
+
+    let b64 = accept_key(key);
+
+    let (extensions, accepted) = match negotiate_extensions(req, config) {
+        Ok(negotiated) => negotiated,
+        Err(e) => {
+            e.response(req.get_version()).send(&mut conn);
+            return Err(e);
+        }
+    };
+
+    let mut response = http::Response::status(req.get_version(), http::StatusCode::SwitchingProtocols)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", &b64);
+
+    if accepted.len() > 0 {
+        response = response.header("Sec-WebSocket-Extensions", accepted.join(", "));
+    }
+
+    let subprotocol = select_subprotocol(req.get_header("Sec-WebSocket-Protocol"), &config.subprotocols);
+    if let Some(protocol) = &subprotocol {
+        response = response.header("Sec-WebSocket-Protocol", protocol);
     }
+
+    response.build().send(&mut conn);
+    
+    let mut ws = Websocket::<Connection>::from(conn);
+    ws.extensions = extensions;
+    ws.extensions_header = accepted.join(", ");
+    ws.subprotocol = subprotocol;
+    ws.peer.path = Some(String::from(req.get_uri()));
+    ws.peer.headers = req.headers().map(|(name, value)| (String::from(name), String::from(value))).collect();
+    Ok(ws)
 }
 
-impl IncompleteFragment {
+/// The accepted extensions and their entries for the Sec-WebSocket-Extensions response header.
+type Negotiated = (Vec<Box<dyn Extension>>, Vec<String>);
 
-    const MIN_SIZE : usize = 2;
+/// The line logged for a frame by `Websocket::set_trace`. `direction` is `<-` for received and
+/// `->` for sent frames.
+fn trace_line(direction : &str, header : &FrameHeader, payload : &[u8], dump_bytes : usize) -> String {
+    let mut line = format!("ws {direction} fin={} rsv={:03b} opcode={:#x} len={}", header.fin as u8, header.rsv, header.opcode, header.payload_len);
+    if let Some(mask) = header.mask {
+        line += &format!(" mask={:02x}{:02x}{:02x}{:02x}", mask[0], mask[1], mask[2], mask[3]);
+    }
+    if dump_bytes > 0 && payload.len() > 0 {
+        line += " |";
+        for byte in &payload[..payload.len().min(dump_bytes)] {
+            line += &format!(" {byte:02x}");
+        }
+        if payload.len() > dump_bytes {
+            line += " ...";
+        }
+    }
+    line
+}
 
-    fn is_masked(&self) -> Option<bool> {
-        if self.bytes.len() < 2 {
-            None
-        }else{
-            Some((self.bytes[1] >> 7) != 0)
+/// Offers each configured extension the client's offers for it. The offers may be spread
+/// over several Sec-WebSocket-Extensions headers.
+fn negotiate_extensions(req : &http::Request, config : &Config) -> Result<Negotiated, UpgradeError> {
+    let mut extensions = Vec::new();
+    let mut accepted = Vec::new();
+    let mut offers = Vec::new();
+    for (name, value) in req.headers() {
+        if name.eq_ignore_ascii_case("Sec-WebSocket-Extensions") {
+            let parsed = extension::parse_offers(value).map_err(|_| UpgradeError::InvalidHeader("Sec-WebSocket-Extensions"))?;
+            offers.extend(parsed);
         }
     }
+    if offers.len() == 0 {
+        return Ok((extensions, accepted));
+    }
 
-    fn provisional_payload_length(&self) -> Option<u8> {
-        if self.bytes.len() < 2 {
-            None
-        }else{
-            Some(self.bytes[1] & 0x7F)
+    let mut candidates : Vec<Box<dyn Extension>> = Vec::new();
+    if config.permessage_deflate {
+        candidates.push(Box::new(PerMessageDeflate::new(config.server_no_context_takeover, config.client_no_context_takeover)));
+    }
+    candidates.extend(config.extensions.iter().map(|factory| factory()));
+
+    let mut reserved_bits = 0;
+    for mut candidate in candidates {
+        // two extensions can't give a meaning to the same bit
+        if candidate.reserved_bits() & reserved_bits != 0 {
+            continue;
+        }
+        let matching : Vec<extension::Offer> = offers.iter().filter(|offer| offer.name == candidate.name()).cloned().collect();
+        if matching.len() == 0 {
+            continue;
+        }
+        if let Some(response) = candidate.negotiate(&matching) {
+            reserved_bits |= candidate.reserved_bits();
+            accepted.push(response);
+            extensions.push(candidate);
         }
     }
+    return Ok((extensions, accepted));
+}
 
-    fn try_append_nbytes(&mut self, n : usize, bytes: &mut &[u8]) -> bool {
-        for i in 0..n {
-            if i >= bytes.len() {
-                *bytes = &(*bytes)[i..];
-                return false;
-            }
+/// Validates the opening handshake of a client (RFC 6455 section 4.2.1) and returns its key.
+fn check_upgrade_request<'a>(req : &'a http::Request, config : &Config) -> Result<&'a str, UpgradeError> {
+    if req.get_method() != &http::Method::GET {
+        return Err(UpgradeError::MethodNotAllowed);
+    }
+    if req.get_headers("Upgrade").next().is_none() {
+        return Err(UpgradeError::MissingHeader("Upgrade"));
+    }
+    if !req.wants_upgrade("websocket") {
+        return Err(UpgradeError::InvalidHeader("Upgrade"));
+    }
+    if req.get_headers("Connection").next().is_none() {
+        return Err(UpgradeError::MissingHeader("Connection"));
+    }
+    if !req.connection_options().iter().any(|option| option.eq_ignore_ascii_case("upgrade")) {
+        return Err(UpgradeError::InvalidHeader("Connection"));
+    }
+
+    if req.get_header("Sec-WebSocket-Version") != Some("13") {
+        return Err(UpgradeError::UnsupportedVersion);
+    }
 
-            self.bytes.push(bytes[i]);
+    if let (Some(check), Some(origin)) = (&config.origin_check, req.get_header("Origin")) {
+        if !check(origin) {
+            return Err(UpgradeError::OriginNotAllowed);
         }
+    }
 
-        *bytes = &(*bytes)[n..];
-        return true;
+    let key = req.get_header("Sec-WebSocket-Key").ok_or(UpgradeError::MissingHeader("Sec-WebSocket-Key"))?;
+    // the key is 16 random bytes in base64
+    if key.len() != 24 || !key.ends_with("==") {
+        return Err(UpgradeError::InvalidHeader("Sec-WebSocket-Key"));
     }
+    return Ok(key);
+}
 
-    fn get_length_till_end_of_payload(&self) -> Option<usize> {
-        let provisional = self.provisional_payload_length();
-        if provisional == None {return None; }
-        let res = match self.provisional_payload_length().unwrap() {
-            126 => {
-                // 16 bit extended payload length
-                Self::MIN_SIZE + 2 
-            },
-            127 => {
-                // 64 bit extended payload length
-                Self::MIN_SIZE + 8
-            },
-            _ => {Self::MIN_SIZE}
-        };
-        return Some(res);
+/// Picks the first of our supported subprotocols that the client offered.
+fn select_subprotocol(offered : Option<&str>, supported : &[String]) -> Option<String> {
+    let offered : Vec<&str> = offered?.split(',').map(|p| p.trim()).collect();
+    for protocol in supported {
+        if offered.contains(&protocol.as_str()) {
+            return Some(protocol.clone());
+        }
     }
+    None
+}
 
-    fn get_mask(&self) -> Option<[u8;4]> {
-        let payload_len_end = self.get_length_till_end_of_payload()?;
-        if self.bytes.len() <= payload_len_end + 4 || !self.is_masked().unwrap() {
-            return None;
+impl Config {
+    pub fn new() -> Self {
+        Self {
+            permessage_deflate: true,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            subprotocols: Vec::new(),
+            origin_check: None,
+            extensions: Vec::new(),
         }
+    }
+
+    /// Reset our compressor after every message instead of keeping the sliding window around.
+    /// Saves memory per connection at the cost of compression ratio.
+    pub fn server_no_context_takeover(mut self, enabled : bool) -> Self {
+        self.server_no_context_takeover = enabled;
+        self
+    }
+
+    /// Ask the client to reset its compressor after every message, which lets us drop the
+    /// decompression window between messages as well.
+    pub fn client_no_context_takeover(mut self, enabled : bool) -> Self {
+        self.client_no_context_takeover = enabled;
+        self
+    }
 
-        let mask = &self.bytes[payload_len_end..payload_len_end + 4];
-        let mut buf = [0u8; 4];
-        buf.clone_from_slice(mask);
-        return Some(buf);
+    /// Adds a supported subprotocol. Protocols added first are preferred when the client offers several.
+    pub fn subprotocol(mut self, protocol : &str) -> Self {
+        self.subprotocols.push(String::from(protocol));
+        self
     }
 
-    fn payload_len(&self) -> Option<usize> {
-        let payload_len_end = self.get_length_till_end_of_payload()?;
-        if self.bytes.len() < payload_len_end {
-            return None;
+    pub fn subprotocols(mut self, protocols : &[&str]) -> Self {
+        for protocol in protocols {
+            self.subprotocols.push(String::from(*protocol));
         }
+        self
+    }
 
-        let res = match self.provisional_payload_length()? {
-            126 => {
-                assert!(payload_len_end == 4);
-                let bytes = &self.bytes[2..4];
-                let mut buf = [0u8; 2];
-                buf[0..2].clone_from_slice(bytes);
-                u16::from_be_bytes(buf) as usize
-            },
-            127 => {
-                assert!(payload_len_end == 10);
-                let bytes = &self.bytes[2..10];
-                let mut buf = [0u8; 8];
-                buf[0..8].clone_from_slice(bytes);
-                u64::from_be_bytes(buf) as usize
-            },
-            v => v as usize
-        };
-        return Some(res);
+    /// Only upgrade requests whose Origin header is one of `origins`, others are answered with 403.
+    /// Protects browser-facing servers against cross-site websocket hijacking.
+    pub fn allowed_origins(self, origins : &[&str]) -> Self {
+        let origins : Vec<String> = origins.iter().map(|origin| String::from(*origin)).collect();
+        self.origin_check(move |origin| origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)))
     }
 
+    /// Only upgrade requests whose Origin header passes `check`, others are answered with 403.
+    /// Requests without an Origin header don't come from browsers and are not checked.
+    pub fn origin_check<F : Fn(&str) -> bool + Send + Sync + 'static>(mut self, check : F) -> Self {
+        self.origin_check = Some(Box::new(check));
+        self
+    }
 
-    fn append(&mut self, data : &mut &[u8]) -> Result<Option<Fragment>, Error> {
+    /// Adds an extension which is offered the client's Sec-WebSocket-Extensions. `factory` creates
+    /// its state for every connection. Extensions are negotiated after permessage-deflate in the
+    /// order they were added.
+    pub fn extension<E : Extension + 'static, F : Fn() -> E + Send + Sync + 'static>(mut self, factory : F) -> Self {
+        self.extensions.push(Box::new(move || Box::new(factory()) as Box<dyn Extension>));
+        self
+    }
 
-        if self.bytes.len() < Self::MIN_SIZE {
-            if !self.try_append_nbytes(Self::MIN_SIZE - self.bytes.len(), data) {
-                return Ok(None);
-            }
+    /// Accept the permessage-deflate extension if the client offers it. Enabled by default.
+    pub fn permessage_deflate(mut self, enabled : bool) -> Self {
+        self.permessage_deflate = enabled;
+        self
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl<Connection: std::io::Read + std::io::Write> From<Connection> for Websocket<Connection> {
+    fn from(conn: Connection) -> Websocket<Connection> {
+        Websocket::<Connection> {
+            state: State::Open,
+            role: Role::Server,
+            connection : conn,
+            incomplete_fragment: IncompleteFragment {
+                header_bytes: [0; FrameHeader::MAX_LEN],
+                header_len: 0,
+                header: None,
+                received: 0,
+                in_message: false,
+                payload: Vec::new(),
+                spare: Vec::new(),
+            },
+            incomplete_message: IncompleteMessage{bytes: Vec::new(), assembled: 0, wire_bytes: 0, completed: 0, opcode: 0, validate_utf8: true, in_progress: false},
+            received: Vec::new(),
+            read_buffer: vec![0; DEFAULT_READ_BUFFER_SIZE],
+            drain: false,
+            last_read_empty: false,
+            unread_after_close: Vec::new(),
+            extensions: Vec::new(),
+            subprotocol: None,
+            extensions_header: String::new(),
+            peer: Peer::default(),
+            keepalive: None,
+            last_received: std::time::Instant::now(),
+            accept_unmasked: false,
+            pending: std::collections::VecDeque::new(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            fragment_size: None,
+            outgoing: Vec::new(),
+            max_queued: usize::MAX,
+            max_outgoing: usize::MAX,
+            write_timeout: None,
+            write_stalled_since: None,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            context: None,
+            pings_sent: std::collections::VecDeque::new(),
+            last_rtt: None,
+            shutdown: None,
+            mask_generator: std::sync::Arc::new(std::sync::Mutex::new(SystemRandom)),
+            stats: Stats::default(),
+            trace: None,
+            logger: None,
+            recorder: None,
+            conformance: Conformance::Strict,
         }
-        // is_masked, opcode, provisional_payload_length is now available
+    }
+}
 
-        assert!(self.bytes.len() >= Self::MIN_SIZE);
+impl<Connection: std::io::Read + std::io::Write> Websocket<Connection> {
+    fn client(conn: Connection) -> Websocket<Connection> {
+        let mut ws = Websocket::<Connection>::from(conn);
+        ws.role = Role::Client;
+        ws
+    }
 
-        let till_end_of_extended_payload_len = self.get_length_till_end_of_payload().unwrap();
-        if self.bytes.len() < till_end_of_extended_payload_len {
-            let remaining = till_end_of_extended_payload_len - self.bytes.len();
-            if !self.try_append_nbytes(remaining, data) {
-                return Ok(None);
-            }
+    /// Moves the protocol state onto the connection `wrap` makes of the current one.
+    fn map_connection<Other : std::io::Read + std::io::Write, F : FnOnce(Connection) -> Other>(self, wrap : F) -> Websocket<Other> {
+        Websocket::<Other> {
+            state: self.state,
+            role: self.role,
+            connection: wrap(self.connection),
+            incomplete_fragment: self.incomplete_fragment,
+            incomplete_message: self.incomplete_message,
+            received: Vec::new(),
+            read_buffer: self.read_buffer,
+            drain: self.drain,
+            last_read_empty: false,
+            unread_after_close: self.unread_after_close,
+            extensions: self.extensions,
+            subprotocol: self.subprotocol,
+            extensions_header: self.extensions_header,
+            peer: self.peer,
+            keepalive: self.keepalive,
+            last_received: self.last_received,
+            accept_unmasked: self.accept_unmasked,
+            pending: self.pending,
+            max_message_size: self.max_message_size,
+            fragment_size: self.fragment_size,
+            outgoing: self.outgoing,
+            max_queued: self.max_queued,
+            max_outgoing: self.max_outgoing,
+            write_timeout: self.write_timeout,
+            write_stalled_since: self.write_stalled_since,
+            close_timeout: self.close_timeout,
+            context: self.context,
+            pings_sent: self.pings_sent,
+            last_rtt: self.last_rtt,
+            shutdown: self.shutdown,
+            mask_generator: self.mask_generator,
+            stats: self.stats,
+            trace: self.trace,
+            logger: self.logger,
+            recorder: self.recorder,
+            conformance: self.conformance,
         }
+    }
+
+    /// A websocket on `connection` with the state and settings of this one, but none of its
+    /// buffers, messages, extensions or context.
+    fn with_settings<Other : std::io::Read + std::io::Write>(&self, connection : Other) -> Websocket<Other> {
+        let mut ws = Websocket::<Other>::from(connection);
+        ws.state = self.state;
+        ws.role = self.role;
+        ws.read_buffer = vec![0; self.read_buffer.len()];
+        ws.drain = self.drain;
+        ws.subprotocol = self.subprotocol.clone();
+        ws.extensions_header = self.extensions_header.clone();
+        ws.peer = self.peer.clone();
+        ws.keepalive = self.keepalive.as_ref().map(|keepalive| Keepalive{interval: keepalive.interval, timeout: keepalive.timeout, ping_sent: None});
+        ws.accept_unmasked = self.accept_unmasked;
+        ws.max_message_size = self.max_message_size;
+        ws.fragment_size = self.fragment_size;
+        ws.max_queued = self.max_queued;
+        ws.max_outgoing = self.max_outgoing;
+        ws.write_timeout = self.write_timeout;
+        ws.close_timeout = self.close_timeout;
+        ws.shutdown = self.shutdown.clone();
+        ws.mask_generator = self.mask_generator.clone();
+        ws.trace = self.trace;
+        ws.logger = self.logger.clone();
+        ws.conformance = self.conformance;
+        ws
+    }
+}
 
-        assert!(self.bytes.len() >= till_end_of_extended_payload_len);
+impl IncompleteFragment {
 
-        // payload length is now available
+    const MIN_SIZE : usize = 2;
 
-        let end_of_mask = if self.is_masked().unwrap() {
-            let end_of_mask = till_end_of_extended_payload_len + 4;
-            if self.bytes.len() < end_of_mask {
-                let remaining = end_of_mask - self.bytes.len();
-                if !self.try_append_nbytes(remaining, data) {
-                    return Ok(None);
-                }
-            }
-            end_of_mask
-        }else{
-            till_end_of_extended_payload_len
-        };
+    /// The number of spare buffers kept, enough for the frames completed by a single read.
+    const POOLED_BUFFERS : usize = 4;
+
+    /// Larger buffers are dropped instead of pooled, so a single big frame doesn't pin its memory.
+    const MAX_POOLED_CAPACITY : usize = 64 << 10;
+
+    /// Payload space reserved when a header arrives, so a frame received over many reads fills a
+    /// single allocation. Bounded, as the peer could announce large frames without sending them.
+    const MAX_RESERVED : usize = 1 << 20;
 
-        // mask is now available if it exists
+    fn try_append_nbytes(&mut self, n : usize, bytes: &mut &[u8]) -> bool {
+        let available = n.min(bytes.len());
+        self.header_bytes[self.header_len..self.header_len + available].copy_from_slice(&bytes[..available]);
+        self.header_len += available;
+        *bytes = &(*bytes)[available..];
+        return available == n;
+    }
 
-        // everything in the 'header' is available
-        // Now reading payload data
-        let payload_len = self.payload_len().unwrap();
-        let end_of_fragment = end_of_mask + payload_len;
+    /// Returns the payload buffer of a handled fragment to the pool.
+    fn recycle(&mut self, fragment : Fragment) {
+        let mut payload = fragment.payload;
+        if self.spare.len() < Self::POOLED_BUFFERS && payload.capacity() > 0 && payload.capacity() <= Self::MAX_POOLED_CAPACITY {
+            payload.clear();
+            self.spare.push(payload);
+        }
+    }
 
-        assert!(self.bytes.len() < end_of_fragment);
+    /// The number of bytes of the current frame received so far.
+    fn len(&self) -> usize {
+        self.header_len + self.payload.len()
+    }
 
-        if !self.try_append_nbytes(end_of_fragment - self.bytes.len(), data) {
+    /// Parses the header once it is complete. Frames announcing more than `max_payload` bytes are
+    /// rejected before their payload is read.
+    fn read_header(&mut self, data : &mut &[u8], max_payload : usize) -> Result<Option<FrameHeader>, ProtocolError> {
+        if self.header_len < Self::MIN_SIZE && !self.try_append_nbytes(Self::MIN_SIZE - self.header_len, data) {
             return Ok(None);
         }
+        // is_masked, opcode, provisional_payload_length is now available
 
-        assert!(end_of_mask + self.payload_len().unwrap() == self.bytes.len());
-
-        if let Some(mask) = self.get_mask() {
-            // mask bytes
-            let payload_data = &mut self.bytes[end_of_mask..];
-            for i in 0..payload_data.len() {
-                let j = i % 4;
-                payload_data[i] ^= mask[j];
+        if (self.header_bytes[0] & 0x8) != 0 {
+            // control frames must not be fragmented and carry at most 125 bytes of payload
+            if (self.header_bytes[0] >> 7) == 0 {
+                return Err(ProtocolError::new(1002, "fragmented control frame"));
+            }
+            if (self.header_bytes[1] & 0x7F) > 125 {
+                return Err(ProtocolError::new(1002, "control frame payload exceeds 125 bytes"));
             }
         }
 
-        return Ok(Some(
-                Fragment{
-                    bytes: std::mem::take(&mut self.bytes),
-                    payload_offset: end_of_mask
-                }
-        ));
+        let header_len = FrameHeader::encoded_len(&self.header_bytes[..self.header_len]).unwrap();
+        if self.header_len < header_len && !self.try_append_nbytes(header_len - self.header_len, data) {
+            return Ok(None);
+        }
+
+        let (header, _) = FrameHeader::parse(&self.header_bytes[..header_len]).unwrap();
+        if header.payload_len >> 63 != 0 {
+            return Err(ProtocolError::new(1002, "most significant bit of the payload length is set"));
+        }
+        // the limit may be usize::MAX, which doesn't bound the length on every platform
+        if header.payload_len > max_payload as u64 || header.payload_size().is_none() {
+            return Err(ProtocolError::new(1009, "frame exceeds the maximum message size"));
+        }
+        Ok(Some(header))
+    }
+
+    /// Consumes the bytes of the current frame from `data`. The unmasked payload of data frames is
+    /// appended to `message` directly if `direct` is set, which avoids copying it once more when
+    /// no extension has to decode it first. Returns the frame once it is complete.
+    fn append(&mut self, data : &mut &[u8], max_payload : usize, message : &mut Vec<u8>, direct : bool) -> Result<Option<Fragment>, ProtocolError> {
+        let header = match self.header {
+            Some(header) => header,
+            None => match self.read_header(data, max_payload)? {
+                Some(header) => {
+                    self.header = Some(header);
+                    self.received = 0;
+                    self.in_message = direct && !header.is_control();
+                    header
+                },
+                None => return Ok(None),
+            },
+        };
+
+        let payload_len = header.payload_len as usize;
+        let n = (payload_len - self.received).min(data.len());
+        let destination = if self.in_message { message } else { &mut self.payload };
+        if self.received == 0 {
+            destination.reserve(payload_len.min(Self::MAX_RESERVED));
+        }
+        let start = destination.len();
+        destination.extend_from_slice(&data[..n]);
+        if let Some(mask) = header.mask {
+            // the key continues where the previous part of the payload ended
+            let offset = self.received;
+            let mask = [mask[offset % 4], mask[(offset + 1) % 4], mask[(offset + 2) % 4], mask[(offset + 3) % 4]];
+            frame::apply_mask(&mut destination[start..], mask);
+        }
+        self.received += n;
+        *data = &(*data)[n..];
+        if self.received < payload_len {
+            return Ok(None);
+        }
+
+        self.header = None;
+        self.header_len = 0;
+        let payload = if self.in_message {
+            Vec::new()
+        }else{
+            std::mem::replace(&mut self.payload, self.spare.pop().unwrap_or_default())
+        };
+        return Ok(Some(Fragment{header: header, payload: payload, in_message: self.in_message}));
     }
 }
 
 impl Fragment {
     fn payload(&self) -> &[u8] {
-        &self.bytes[self.payload_offset..]
+        &self.payload
     }
 
     fn is_fin(&self) -> bool {
-        (self.bytes[0] >> 7) != 0
+        self.header.fin
+    }
+
+    fn is_masked(&self) -> bool {
+        self.header.mask.is_some()
+    }
+
+    /// RSV1-3 as the bits 0b100, 0b010 and 0b001.
+    fn reserved_bits(&self) -> u8 {
+        self.header.rsv
     }
 
     fn opcode(&self) -> u8 {
-        self.bytes[0] & 0xF
+        self.header.opcode
     }
 
     fn is_control_frame(&self) -> bool {
-        (self.opcode() >> 3) != 0
+        self.header.is_control()
     }
 }
 
 impl IncompleteMessage {
 
+    /// Control frames are handled out-of-band by `Websocket::receive` and never reach the message,
+    /// so they may arrive between the fragments of a message without disturbing it.
     fn accepts_opcode(&self, opcode: u8) -> bool {
-        if self.bytes.len() == 0 {
+        if !self.in_progress {
             match opcode {
                 0x1 => true, // text
                 0x2 => true, // binary
-                0x8 => true, // close
-                0x9 => true, // ping
-                0xA => true, // pong
                 _ => false
             }
         }else{
@@ -334,168 +995,1199 @@ impl IncompleteMessage {
     }
 
 
-    fn append_fragment(&mut self, fragment: Fragment) -> Result<Option<Message>, Error> {
+    fn start_fragment(&mut self, fragment: &Fragment) -> Result<(), ProtocolError> {
         if !self.accepts_opcode(fragment.opcode()) {
-            return Err(Error::WebsocketError("unexpected opcode"));
+            return Err(ProtocolError::new(1002, "unexpected opcode"));
         }
 
-        if self.bytes.len() == 0 {
+        if !self.in_progress {
             self.opcode = fragment.opcode();
         }
+        self.in_progress = !fragment.is_fin();
+        if fragment.is_fin() {
+            self.completed += 1;
+        }
+        self.wire_bytes = if fragment.is_fin() { 0 } else { self.wire_bytes + fragment.header.payload_len as usize };
+        if fragment.in_message {
+            self.assembled += fragment.header.payload_len as usize;
+        }
+        Ok(())
+    }
+
+    /// The payload of a data frame passed through the negotiated extensions, the last one first.
+    /// Extensions may not make it longer than `max_len`.
+    fn decode_payload(fragment: &mut Fragment, extensions: &mut [Box<dyn Extension>], max_len: usize) -> Result<Vec<u8>, ProtocolError> {
+        let mut payload = std::mem::take(&mut fragment.payload);
+        for extension in extensions.iter_mut().rev() {
+            payload = extension.decode_frame(&fragment.header, payload, max_len)?;
+        }
+        return Ok(payload);
+    }
+
+    /// Appends a payload which wasn't placed in `bytes` while it was received.
+    fn append_payload(&mut self, payload: Vec<u8>) {
+        if self.bytes.len() == 0 {
+            // the payload is already owned, no need to copy it
+            self.bytes = payload;
+        }else{
+            self.bytes.extend_from_slice(&payload);
+        }
+        self.assembled = self.bytes.len();
+    }
 
-        // append payload
-        self.bytes.extend_from_slice(fragment.payload());
+    /// Removes the assembled bytes of the current message, leaving those of following messages.
+    fn take_assembled(&mut self) -> Vec<u8> {
+        let rest = self.bytes.split_off(self.assembled);
+        self.assembled = 0;
+        return std::mem::replace(&mut self.bytes, rest);
+    }
+
+    fn append_fragment(&mut self, fragment: &mut Fragment, extensions: &mut [Box<dyn Extension>], max_size: usize) -> Result<Option<Message>, ProtocolError> {
+        self.start_fragment(fragment)?;
+
+        if !fragment.in_message {
+            let payload = Self::decode_payload(fragment, extensions, max_size.saturating_sub(self.assembled))?;
+            self.append_payload(payload);
+        }
+        if self.assembled > max_size {
+            return Err(ProtocolError::new(1009, "message exceeds the maximum message size"));
+        }
 
         if !fragment.is_fin() {
             return Ok(None);
         }
 
-        return Ok(Some(Message::from(std::mem::take(&mut self.bytes), self.opcode)?));
+        let bytes = self.take_assembled();
+        return Ok(Some(Message::from(bytes, self.opcode, self.validate_utf8)?));
+    }
+
+    /// Like `append_fragment`, but the payload of the message is placed in `buf`, with `len` bytes
+    /// of it already filled. Returns true once the message is complete.
+    fn append_fragment_into(&mut self, fragment: &mut Fragment, extensions: &mut [Box<dyn Extension>], buf: &mut [u8], len: &mut usize) -> Result<bool, ProtocolError> {
+        self.start_fragment(fragment)?;
+
+        // parts of the message received by an earlier `read` or directly with this frame
+        if self.assembled > 0 {
+            Self::copy_into(&self.bytes[..self.assembled], buf, len)?;
+            self.bytes.drain(..self.assembled);
+            self.assembled = 0;
+        }
+        if !fragment.in_message {
+            let payload = Self::decode_payload(fragment, extensions, buf.len() - *len)?;
+            Self::copy_into(&payload, buf, len)?;
+        }
+        if !fragment.is_fin() {
+            return Ok(false);
+        }
+
+        if self.opcode == 0x1 && self.validate_utf8 && std::str::from_utf8(&buf[..*len]).is_err() {
+            return Err(ProtocolError::new(1007, "expected payload to be ut8 encoded"));
+        }
+        return Ok(true);
+    }
+
+    fn copy_into(data: &[u8], buf: &mut [u8], len: &mut usize) -> Result<(), ProtocolError> {
+        if *len + data.len() > buf.len() {
+            return Err(ProtocolError::new(1009, "message exceeds the provided buffer"));
+        }
+        buf[*len..*len + data.len()].copy_from_slice(data);
+        *len += data.len();
+        Ok(())
+    }
+
+    /// The payload of the next frame of a message, at most `max_size` bytes as streamed messages
+    /// aren't limited as a whole.
+    fn append_chunk(&mut self, fragment: &mut Fragment, extensions: &mut [Box<dyn Extension>], max_size: usize) -> Result<Chunk, ProtocolError> {
+        self.start_fragment(fragment)?;
+
+        let fin = fragment.is_fin();
+        let payload = if fragment.in_message {
+            self.take_assembled()
+        }else{
+            Self::decode_payload(fragment, extensions, max_size)?
+        };
+
+        let kind = if self.opcode == 0x1 { MessageKind::Text } else { MessageKind::Binary };
+        return Ok(Chunk::Data{kind: kind, payload: payload, fin: fin});
+    }
+}
+
+impl Stats {
+    fn frame_sent(&mut self, fin : bool, opcode : u8) {
+        self.frames_sent += 1;
+        // the final frame of a data message, which may be a continuation frame
+        if fin && opcode & 0x8 == 0 {
+            self.messages_sent += 1;
+        }
     }
 }
 
 impl Message {
-    fn from(data : Vec<u8>, opcode : u8) -> Result<Message, Error> {
+    // the payload types only differ from String and Vec<u8> with the bytes feature
+    #[allow(clippy::useless_conversion)]
+    fn from(data : Vec<u8>, opcode : u8, validate_utf8 : bool) -> Result<Message, ProtocolError> {
         match opcode {
+            0x1 if !validate_utf8 => {
+                // SAFETY: whoever disabled validation vouched for the peer sending UTF-8
+                Ok(Self::Text(unsafe { String::from_utf8_unchecked(data) }.into()))
+            },
             0x1 => {
-                if let Ok(s) = std::str::from_utf8(&data) {
-                    Ok(Self::Text(s.to_string()))
+                if let Ok(s) = String::from_utf8(data) {
+                    Ok(Self::Text(s.into()))
                 }else{
-                    Err(Error::WebsocketError("expected payload to be ut8 encoded"))
+                    Err(ProtocolError::new(1007, "expected payload to be ut8 encoded"))
                 }
             },
             0x2 => {
-                Ok(Self::Binary(data))
+                Ok(Self::Binary(data.into()))
             },
             _ => {
-                Err(Error::WebsocketError("Unsupported opcode"))
+                Err(ProtocolError::new(1002, "Unsupported opcode"))
             }
         }
     }
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::IoError(e) => e.fmt(f),
-            Self::WebsocketError(e) => e.fmt(f),
-        }
+impl From<&str> for Message {
+    fn from(text : &str) -> Self {
+        Self::Text(TextPayload::from(text))
     }
 }
 
-impl From<std::io::Error> for Error {
-    fn from(err : std::io::Error) -> Self {
-        Self::IoError(err)
+impl From<String> for Message {
+    #[allow(clippy::useless_conversion)]
+    fn from(text : String) -> Self {
+        Self::Text(text.into())
     }
 }
 
-impl<Connection: std::io::Read + std::io::Write> Websocket<Connection> {
+impl From<&[u8]> for Message {
+    fn from(data : &[u8]) -> Self {
+        Self::Binary(BinaryPayload::from(data.to_vec()))
+    }
+}
 
-    pub fn is_closed(&self) -> bool { self.closed }
+impl From<Vec<u8>> for Message {
+    #[allow(clippy::useless_conversion)]
+    fn from(data : Vec<u8>) -> Self {
+        Self::Binary(data.into())
+    }
+}
 
-    pub fn read(&mut self) -> Result<Vec<Message>, Error> {
-        let mut buffer = [0; 1024];
+#[cfg(feature = "bytes")]
+impl From<bytestring::ByteString> for Message {
+    fn from(text : bytestring::ByteString) -> Self {
+        Self::Text(text)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl From<bytes::Bytes> for Message {
+    fn from(data : bytes::Bytes) -> Self {
+        Self::Binary(data)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => e.fmt(f),
+            Self::WebsocketError(e) => e.fmt(f),
+            Self::WouldBlock => "operation would block".fmt(f),
+            Self::ConnectionClosed(e) => write!(f, "connection closed: {e}"),
+        }
+    }
+}
+
+impl UpgradeError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::MissingHeader(_) | Self::InvalidHeader(_) => 400,
+            Self::UnsupportedVersion => 426,
+            Self::OriginNotAllowed => 403,
+            Self::MethodNotAllowed => 405,
+        }
+    }
+
+    /// The response rejecting the upgrade request, `version` is the HTTP version of the request.
+    pub fn response(&self, version : http::Version) -> http::ResponseComplete {
+        match self {
+            Self::MissingHeader(_) | Self::InvalidHeader(_) => {
+                http::Response::status(version, http::StatusCode::BadRequest)
+                    .payload(self.to_string().as_bytes())
+            },
+            Self::UnsupportedVersion => {
+                // the only version defined by RFC 6455, tell the client which one we speak
+                http::Response::status(version, http::StatusCode::UpgradeRequired)
+                    .header("Sec-WebSocket-Version", 13)
+                    .payload(b"")
+            },
+            Self::OriginNotAllowed => {
+                http::Response::status(version, http::StatusCode::Forbidden)
+                    .payload(b"")
+            },
+            Self::MethodNotAllowed => {
+                http::Response::status(version, http::StatusCode::MethodNotAllowed)
+                    .header("Allow", "GET")
+                    .payload(b"")
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::MissingHeader(name) => write!(f, "missing {name} header"),
+            Self::InvalidHeader(name) => write!(f, "invalid {name} header"),
+            Self::UnsupportedVersion => "unsupported websocket version".fmt(f),
+            Self::OriginNotAllowed => "origin not allowed".fmt(f),
+            Self::MethodNotAllowed => "upgrade request must use GET".fmt(f),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err : std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(err : Error) -> Self {
+        match err {
+            Error::IoError(e) => e,
+            Error::WebsocketError(e) => std::io::Error::other(e),
+            Error::WouldBlock => std::io::Error::from(std::io::ErrorKind::WouldBlock),
+            Error::ConnectionClosed(e) => e,
+        }
+    }
+}
+
+impl<Connection: std::io::Read + std::io::Write> Websocket<Connection> {
+
+    pub fn is_closed(&self) -> bool { self.state == State::Closed }
+
+    /// Where the websocket is in the closing handshake.
+    pub fn state(&self) -> State { self.state }
+
+    /// Consumes the websocket and returns the connection, e.g. to continue with another protocol
+    /// once the closing handshake is complete. Also returns the bytes already read from the
+    /// connection but not consumed, i.e. whatever followed the close frame. Incomplete frames are
+    /// dropped as their payload is unmasked while received. Frames which weren't written yet are
+    /// dropped as well, see `flush_pending`.
+    pub fn into_inner(self) -> (Connection, Vec<u8>) {
+        (self.connection, self.unread_after_close)
+    }
+
+    /// The subprotocol selected during the opening handshake, if any.
+    pub fn subprotocol(&self) -> Option<&str> { self.subprotocol.as_deref() }
+
+    /// The extensions agreed on during the opening handshake with their parameters, as sent in the
+    /// response. Empty if none were negotiated.
+    pub fn extensions(&self) -> Vec<extension::Offer<'_>> {
+        extension::parse_offers(&self.extensions_header).unwrap_or_default()
+    }
+
+    /// The address of the peer, if the server provided it with `set_peer_addr`.
+    pub fn peer_addr(&self) -> Option<std::net::SocketAddr> { self.peer.addr }
+
+    /// Records the address of the peer, as the websocket only sees a generic connection.
+    pub fn set_peer_addr(&mut self, addr : std::net::SocketAddr) {
+        self.peer.addr = Some(addr);
+    }
+
+    /// The request target of the upgrade request, including the query. None for clients.
+    pub fn path(&self) -> Option<&str> { self.peer.path.as_deref() }
+
+    /// A header of the upgrade request, e.g. a cookie or the user agent. Names are compared
+    /// case-insensitively.
+    pub fn request_header(&self, name : &str) -> Option<&str> {
+        self.peer.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All headers of the upgrade request in the order they were sent.
+    pub fn request_headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.peer.headers.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Attaches application state to the connection, e.g. the authenticated user, replacing any
+    /// previous context.
+    pub fn set_context<T : std::any::Any + Send>(&mut self, context : T) {
+        self.context = Some(Box::new(context));
+    }
+
+    /// The attached context, None if there is none or it isn't a `T`.
+    pub fn context<T : std::any::Any>(&self) -> Option<&T> {
+        self.context.as_ref()?.downcast_ref()
+    }
+
+    pub fn context_mut<T : std::any::Any>(&mut self) -> Option<&mut T> {
+        self.context.as_mut()?.downcast_mut()
+    }
+
+    /// Removes the attached context and returns it if it is a `T`.
+    pub fn take_context<T : std::any::Any>(&mut self) -> Option<T> {
+        match self.context.take()?.downcast() {
+            Ok(context) => Some(*context),
+            Err(context) => {
+                self.context = Some(context);
+                None
+            },
+        }
+    }
+
+    /// Lets `run` close the connection once `shutdown` is requested, waiting for the close reply
+    /// until the shutdown's deadline.
+    pub fn set_shutdown(&mut self, shutdown : &crate::server::Shutdown) {
+        self.shutdown = Some(shutdown.clone());
+    }
+
+    /// Enables keepalive pings: after `interval` without receiving anything a ping is sent, and the
+    /// connection is closed with 1001 if no pong arrives within `timeout`. Checked on every `read()`,
+    /// so the connection should have a read timeout shorter than `interval`.
+    pub fn set_keepalive(&mut self, interval : std::time::Duration, timeout : std::time::Duration) {
+        self.keepalive = Some(Keepalive{interval: interval, timeout: timeout, ping_sent: None});
+    }
+
+    pub fn disable_keepalive(&mut self) {
+        self.keepalive = None;
+    }
+
+    /// Chooses between failing the connection on every protocol violation of the peer, which is
+    /// the default, and tolerating those which can be handled safely. Framing errors, invalid
+    /// UTF-8 and exceeded limits always fail the connection.
+    pub fn set_conformance(&mut self, conformance : Conformance) {
+        self.conformance = conformance;
+    }
+
+    /// Accept unmasked frames from clients, which RFC 6455 forbids. Only meant for test harnesses
+    /// which don't bother with masking.
+    pub fn set_accept_unmasked(&mut self, accept : bool) {
+        self.accept_unmasked = accept;
+    }
+
+    /// Replaces the source of the masking keys of a client, `SystemRandom` by default. Servers
+    /// don't mask their frames.
+    pub fn set_mask_generator<G : MaskGenerator + 'static>(&mut self, generator : G) {
+        self.mask_generator = std::sync::Arc::new(std::sync::Mutex::new(generator));
+    }
+
+    fn next_mask(&mut self) -> Result<[u8; 4], Error> {
+        self.mask_generator.lock().unwrap_or_else(|e| e.into_inner()).next_mask()
+            .ok_or(Error::WebsocketError("could not generate masking key"))
+    }
+
+    /// Skips the UTF-8 validation of incoming text messages, which is a measurable cost for large
+    /// messages.
+    ///
+    /// # Safety
+    /// Text messages are turned into `String`s unchecked, so the peer has to be trusted to only
+    /// send valid UTF-8. Anything else is undefined behavior.
+    pub unsafe fn set_skip_utf8_validation(&mut self, skip : bool) {
+        self.incomplete_message.validate_utf8 = !skip;
+    }
+
+    /// Makes `read` and `try_read` keep reading until the connection reports `WouldBlock` or end
+    /// of file, returning every message of a burst at once instead of one buffer per call. Meant
+    /// for nonblocking connections, a blocking one waits for more data until its read timeout.
+    pub fn set_drain(&mut self, drain : bool) {
+        self.drain = drain;
+    }
+
+    /// Logs every frame received or sent: FIN, reserved bits, opcode, payload length and masking
+    /// key, followed by a hex dump of up to `dump_bytes` bytes of the unmasked payload. None
+    /// disables tracing, which is the default. The lines go to the logger of `set_logger`, or with
+    /// the log feature to the `log` facade at level trace.
+    pub fn set_trace(&mut self, dump_bytes : Option<usize>) {
+        self.trace = dump_bytes;
+    }
+
+    /// Passes traced frames and tolerated protocol violations to `logger` instead of the `log`
+    /// facade. Without the log feature they are dropped unless a logger is set.
+    pub fn set_logger<F : Fn(&str) + Send + Sync + 'static>(&mut self, logger : F) {
+        self.logger = Some(std::sync::Arc::new(logger));
+    }
+
+    fn log_trace(&self, line : &str) {
+        match &self.logger {
+            Some(logger) => logger(line),
+            #[cfg(feature = "log")]
+            None => log::trace!("{line}"),
+            #[cfg(not(feature = "log"))]
+            None => {},
+        }
+    }
+
+    fn log_warning(&self, line : &str) {
+        match &self.logger {
+            Some(logger) => logger(line),
+            #[cfg(feature = "log")]
+            None => log::warn!("{line}"),
+            #[cfg(not(feature = "log"))]
+            None => {},
+        }
+    }
+
+    /// Writes every byte read from the connection from now on to `recorder`, e.g. a file. The
+    /// recording can be parsed again with `ws::replay::from_file`. Recording stops if writing to
+    /// the recorder fails.
+    pub fn record_to<W : std::io::Write + Send + 'static>(&mut self, recorder : W) {
+        self.recorder = Some(Box::new(recorder));
+    }
+
+    /// Stops the recording started by `record_to`.
+    pub fn stop_recording(&mut self) {
+        self.recorder = None;
+    }
+
+    /// Splits outgoing text and binary messages longer than `size` bytes into several frames, so
+    /// the peer and intermediaries don't have to buffer them whole. None sends every message as a
+    /// single frame, which is the default. Doesn't apply to `start_text` and `start_binary`.
+    pub fn set_fragment_size(&mut self, size : Option<usize>) {
+        self.fragment_size = size.map(|size| size.max(1));
+    }
+
+    /// Limits the size of incoming messages, 64 MiB by default. Frames announcing a larger payload
+    /// are refused with close code 1009 before any of their payload is buffered.
+    /// For `read_chunks` the limit applies to single frames.
+    pub fn set_max_message_size(&mut self, max_size : usize) {
+        self.max_message_size = max_size;
+    }
+
+    /// Sets how many bytes are read from the connection at once, 16 KiB by default. Larger buffers
+    /// need fewer reads for large messages but take memory for every connection. At least one byte.
+    pub fn set_read_buffer_size(&mut self, size : usize) {
+        self.read_buffer = vec![0; size.max(1)];
+    }
+
+    /// Disconnects peers which stop reading: if outgoing data can't be written for `timeout`, the
+    /// connection is failed with 1001. Takes effect on the next send, so blocking connections need
+    /// a write timeout of their own. Disabled by default.
+    pub fn set_write_timeout(&mut self, timeout : Option<std::time::Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    /// Fails the connection with 1001 once more than `max_bytes` of outgoing data couldn't be
+    /// written. Unlimited by default.
+    pub fn set_max_outgoing(&mut self, max_bytes : usize) {
+        self.max_outgoing = max_bytes;
+    }
+
+    /// Limits the bytes held by `queue` before `flush`, measured before compression. Unlimited by default.
+    pub fn set_max_queued(&mut self, max_bytes : usize) {
+        self.max_queued = max_bytes;
+    }
+
+    /// How long `close` waits for the peer's close frame, 5 seconds by default. As the deadline is
+    /// checked between reads, the connection needs a read timeout for it to be effective.
+    pub fn set_close_timeout(&mut self, timeout : std::time::Duration) {
+        self.close_timeout = timeout;
+    }
+
+    /// Reserved bits whose meaning was defined by a negotiated extension.
+    fn negotiated_reserved_bits(&self) -> u8 {
+        self.extensions.iter().fold(0, |bits, extension| bits | extension.reserved_bits())
+    }
+
+    fn check_keepalive(&mut self) -> Result<(), Error> {
+        if self.state != State::Open {
+            return Ok(());
+        }
+        let keepalive = if let Some(keepalive) = &mut self.keepalive { keepalive } else { return Ok(()); };
+
+        if let Some(ping_sent) = keepalive.ping_sent {
+            if ping_sent.elapsed() >= keepalive.timeout {
+                return Err(self.fail(1001, "keepalive timeout"));
+            }
+        }else if self.last_received.elapsed() >= keepalive.interval {
+            keepalive.ping_sent = Some(std::time::Instant::now());
+            self.send_ping(&[])?;
+        }
+        Ok(())
+    }
+
+    /// Reads once from the connection, or until it has no more data with `set_drain`, and returns all
+    /// messages completed by the received data.
+    /// If no data is available yet (read timeout or nonblocking connection) an empty Vec is returned.
+    /// Pings and a close frame are answered with a single write once the received data is parsed.
+    /// If the connection fails without a closing handshake, a close message with code 1006 and the
+    /// error as its reason is returned and the websocket is closed. The same happens without a
+    /// reason if the peer closes the connection without a close frame.
+    pub fn read(&mut self) -> Result<Vec<Message>, Error> {
+        self.read_messages(false)
+    }
+
+    /// Like `read`, but returns `Error::WouldBlock` if the connection had no data available, so
+    /// nonblocking connections can be driven by an external polling loop.
+    pub fn try_read(&mut self) -> Result<Vec<Message>, Error> {
+        self.read_messages(true)
+    }
+
+    /// Blocks until a complete text or binary message or a close message arrived and returns it.
+    /// Pings and pongs are skipped.
+    pub fn read_message(&mut self) -> Result<Message, Error> {
+        loop {
+            while let Some(message) = self.pending.pop_front() {
+                match message {
+                    Message::Ping(_) | Message::Pong(_) => {},
+                    message => return Ok(message),
+                }
+            }
+            if self.is_closed() {
+                return Err(Error::WebsocketError("connection is closed"));
+            }
+            let messages = self.read()?;
+            self.pending.extend(messages);
+        }
+    }
+
+    /// Blocks until a text or binary message arrived and copies its payload into `buf`, avoiding an
+    /// allocation per message. Returns the kind of the message and its length, or None once the
+    /// connection is closed. A message larger than `buf` fails the connection with 1009.
+    /// Pings and pongs are skipped. With negotiated extensions, every frame is decoded into an
+    /// internal buffer first.
+    pub fn read_into(&mut self, buf : &mut [u8]) -> Result<Option<(MessageKind, usize)>, Error> {
+        // bytes of the message written to buf so far, it may span several reads
+        let mut len = 0;
+        loop {
+            // messages completed by an earlier read
+            while let Some(message) = self.pending.pop_front() {
+                let (kind, payload) = match &message {
+                    Message::Text(text) => (MessageKind::Text, str::as_bytes(text)),
+                    Message::Binary(data) => (MessageKind::Binary, &data[..]),
+                    Message::Close(..) => return Ok(None),
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                };
+                let mut len = 0;
+                if let Err(e) = IncompleteMessage::copy_into(payload, buf, &mut len) {
+                    return Err(self.fail(e.code, e.reason));
+                }
+                return Ok(Some((kind, len)));
+            }
+            if self.is_closed() {
+                return Ok(None);
+            }
+
+            let mut completed = None;
+            let mut received = self.receive(false)?;
+            for item in received.drain(..) {
+                let mut fragment = match item {
+                    Received::Control(msg) => {
+                        self.pending.push_back(msg);
+                        continue;
+                    },
+                    Received::Data(fragment) => fragment,
+                };
+                let result = if completed.is_some() {
+                    // later messages of the same read are kept until the next call
+                    self.incomplete_message.append_fragment(&mut fragment, &mut self.extensions, self.max_message_size)
+                        .map(|msg| self.pending.extend(msg))
+                }else{
+                    self.incomplete_message.append_fragment_into(&mut fragment, &mut self.extensions, buf, &mut len)
+                        .map(|fin| if fin {
+                            completed = Some(if self.incomplete_message.opcode == 0x1 { MessageKind::Text } else { MessageKind::Binary });
+                        })
+                };
+                self.incomplete_fragment.recycle(fragment);
+                if let Err(e) = result {
+                    return Err(self.fail(e.code, e.reason));
+                }
+            }
+            self.received = received;
+            if let Some(kind) = completed {
+                return Ok(Some((kind, len)));
+            }
+        }
+    }
+
+    /// Iterates over incoming messages until the connection is closed. The close message is the
+    /// last item, an error ends the iteration as well.
+    pub fn messages(&mut self) -> Messages<'_, Connection> {
+        Messages::new(self)
+    }
+
+    fn read_messages(&mut self, nonblocking : bool) -> Result<Vec<Message>, Error> {
+        if self.pending.len() > 0 {
+            return Ok(self.pending.drain(..).collect());
+        }
 
         let mut messages = Vec::new();
-        
-        let nread = match self.connection.read(&mut buffer) {
+        let mut first = true;
+        loop {
+            // only the first read may wait for data, the following ones just drain the connection
+            let mut received = match self.receive(nonblocking || !first) {
+                Err(Error::WouldBlock) if !first => return Ok(messages),
+                result => result?,
+            };
+            for item in received.drain(..) {
+                match item {
+                    Received::Control(msg) => messages.push(msg),
+                    Received::Data(mut fragment) => {
+                        let result = self.incomplete_message.append_fragment(&mut fragment, &mut self.extensions, self.max_message_size);
+                        self.incomplete_fragment.recycle(fragment);
+                        match result {
+                            Ok(Some(msg)) => messages.push(msg),
+                            Ok(None) => {},
+                            Err(e) => return Err(self.fail(e.code, e.reason)),
+                        }
+                    },
+                }
+            }
+            self.received = received;
+            if !self.drain || self.last_read_empty || self.is_closed() {
+                return Ok(messages);
+            }
+            first = false;
+        }
+    }
+
+    /// Like `read`, but hands out the payload of data frames as soon as they arrive instead of
+    /// assembling whole messages. Compressed messages are inflated chunk by chunk. Text chunks are
+    /// not validated as they may split characters. Don't mix with `read` within one message.
+    pub fn read_chunks(&mut self) -> Result<Vec<Chunk>, Error> {
+        let mut chunks = Vec::new();
+        let mut received = self.receive(false)?;
+        for item in received.drain(..) {
+            match item {
+                Received::Control(msg) => chunks.push(Chunk::Control(msg)),
+                Received::Data(mut fragment) => {
+                    let result = self.incomplete_message.append_chunk(&mut fragment, &mut self.extensions, self.max_message_size);
+                    self.incomplete_fragment.recycle(fragment);
+                    match result {
+                        Ok(chunk) => chunks.push(chunk),
+                        Err(e) => return Err(self.fail(e.code, e.reason)),
+                    }
+                },
+            }
+        }
+        self.received = received;
+        return Ok(chunks);
+    }
+
+    /// Reads from the connection and handles control frames. Returns the control messages and
+    /// data frames in the order they were received. The returned Vec should be handed back in
+    /// `self.received` once drained, so its allocation is reused by the next read.
+    /// With `nonblocking` set, `Error::WouldBlock` is returned if the connection had no data available.
+    /// A connection failing or reaching its end before the closing handshake is reported as a
+    /// close with code 1006.
+    fn receive(&mut self, nonblocking : bool) -> Result<Vec<Received>, Error> {
+        // taken while parsing, which needs the rest of the websocket
+        let mut buffer = std::mem::take(&mut self.read_buffer);
+        let result = self.receive_into(&mut buffer, nonblocking);
+        self.read_buffer = buffer;
+        result
+    }
+
+    fn receive_into(&mut self, buffer : &mut [u8], nonblocking : bool) -> Result<Vec<Received>, Error> {
+        let mut messages = std::mem::take(&mut self.received);
+        messages.clear();
+
+        let nread = match self.connection.read(buffer) {
+            Ok(0) if self.state != State::Closed => {
+                // the peer went away without a closing handshake
+                self.last_read_empty = true;
+                messages.push(self.connection_lost(None));
+                return Ok(messages);
+            },
             Ok(nread) => Ok(nread),
             Err(e) => {
-                if e.kind() == std::io::ErrorKind::WouldBlock {
+                if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut {
+                    if nonblocking {
+                        self.check_keepalive()?;
+                        return Err(Error::WouldBlock);
+                    }
+                    Ok(0)
+                }else if e.kind() == std::io::ErrorKind::Interrupted {
                     Ok(0)
+                }else if self.state != State::Closed {
+                    // the connection broke without a closing handshake
+                    messages.push(self.connection_lost(Some(e.to_string())));
+                    return Ok(messages);
                 }else{
                     Err(Error::IoError(e))
                 }
             }
         }?;
 
+        self.last_read_empty = nread == 0;
+        self.stats.bytes_received += nread as u64;
+        if let Some(recorder) = &mut self.recorder {
+            if recorder.write_all(&buffer[..nread]).is_err() {
+                // a broken recording doesn't affect the connection
+                self.recorder = None;
+            }
+        }
+        if nread > 0 {
+            self.last_received = std::time::Instant::now();
+        }
+        self.check_keepalive()?;
+
         let mut received = &buffer[0..nread];
+        let mut answered = false;
 
         while received.len() > 0 {
-            if let Some(fragment) = self.incomplete_fragment.append(&mut received)? {
+            let direct = self.extensions.len() == 0;
+            let fragment = match self.incomplete_fragment.append(&mut received, self.max_message_size, &mut self.incomplete_message.bytes, direct) {
+                Ok(fragment) => fragment,
+                Err(e) => return Err(self.fail(e.code, e.reason)),
+            };
+            if let Some(fragment) = fragment {
+                self.stats.frames_received += 1;
+                if let Some(dump) = self.trace {
+                    let payload = if fragment.in_message {
+                        let bytes = &self.incomplete_message.bytes;
+                        &bytes[bytes.len() - fragment.header.payload_len as usize..]
+                    }else{
+                        fragment.payload()
+                    };
+                    self.log_trace(&trace_line("<-", &fragment.header, payload, dump));
+                }
+                // clients always mask their frames, servers never do
+                if self.role == Role::Server && !fragment.is_masked() && !self.accept_unmasked {
+                    self.violation(1002, "received unmasked frame")?;
+                }
+                if self.role == Role::Client && fragment.is_masked() {
+                    self.violation(1002, "received masked frame")?;
+                }
+                // reserved bits are only allowed if an extension defined their meaning
+                if fragment.reserved_bits() & !self.negotiated_reserved_bits() != 0 {
+                    self.violation(1002, "reserved bits set without a negotiated extension")?;
+                }
+                // extensions only apply to data frames
+                if fragment.reserved_bits() != 0 && fragment.is_control_frame() {
+                    self.violation(1002, "reserved bits set on a control frame")?;
+                }
+
                 if fragment.is_control_frame() {
                     // handle control frame
                     if fragment.opcode() == 0x8 {
                         // close frame
-                        if fragment.payload().len() >= 2 {
+                        if fragment.payload().len() == 1 {
+                            // tolerated as a close frame without a code
+                            self.violation(1002, "close frame with one byte payload")?;
+                        }
+                        let (code, reason) = if fragment.payload().len() >= 2 {
                             let mut buf = [0u8; 2];
                             buf.clone_from_slice(&fragment.payload()[0..2]);
-                            let code = u16::from_be_bytes(buf);
-                            messages.push(Message::Close(Some(code)));
+                            if !is_valid_close_code(u16::from_be_bytes(buf)) {
+                                self.violation(1002, "invalid close code")?;
+                            }
+                            let reason = match std::str::from_utf8(&fragment.payload()[2..]) {
+                                Ok("") => None,
+                                Ok(reason) => Some(reason.to_string()),
+                                Err(_) => return Err(self.fail(1007, "close reason is not utf8 encoded")),
+                            };
+                            (Some(u16::from_be_bytes(buf)), reason)
                         }else{
-                            messages.push(Message::Close(None));
+                            (None, None)
+                        };
+                        if self.state == State::Open {
+                            // the peer initiated the close, answer it before the stream is dropped.
+                            // An invalid code is not echoed.
+                            let answer = if code.is_some_and(is_valid_close_code) { &fragment.payload()[0..2] } else { &[] };
+                            self.queue_raw(0x8, answer)?;
+                            answered = true;
                         }
-                        self.closed = true;
+                        self.state = State::Closed;
+                        messages.push(Received::Control(Message::Close(code, reason)));
+                        self.unread_after_close.extend_from_slice(received);
                         break;
                     }else if fragment.opcode() == 0x9 {
-                        // ping frame
-                        self.send(0xA, fragment.payload())?;
+                        // ping frame, answered once the received data is parsed
+                        if self.state == State::Open {
+                            self.queue_raw(0xA, fragment.payload())?;
+                            answered = true;
+                        }
+                        messages.push(Received::Control(Message::Ping(fragment.payload().to_vec())));
+                    }else if fragment.opcode() == 0xA {
+                        // pong frame
+                        if let Some(keepalive) = &mut self.keepalive {
+                            keepalive.ping_sent = None;
+                        }
+                        self.pong_received(fragment.payload());
+                        messages.push(Received::Control(Message::Pong(fragment.payload().to_vec())));
                     }
-                }else if let Some(msg) = self.incomplete_message.append_fragment(fragment)? {
-                    messages.push(msg);
+                    self.incomplete_fragment.recycle(fragment);
+                }else{
+                    messages.push(Received::Data(fragment));
                 }
             }
         }
+        if answered {
+            // the answers to all control frames of this read are written together
+            self.flush_pending()?;
+            self.connection.flush().map_err(|e| self.write_failed(e))?;
+        }
         return Ok(messages);
     }
 
 
-    fn send(&mut self, opcode : u8, mut data : &[u8]) -> Result<(), Error> {
-        let mut header : [u8; 16] = [0; 16];
-        assert!((&header).len() == 16);
+    fn send_raw(&mut self, opcode : u8, data : &[u8]) -> Result<(), Error> {
+        self.queue_raw(opcode, data)?;
+        self.flush_pending()?;
+        Ok(())
+    }
 
-        header[0] = (1 << 7) | (opcode & 0xF);
+    fn queue_raw(&mut self, opcode : u8, data : &[u8]) -> Result<(), Error> {
+        match self.fragment_size {
+            Some(size) if opcode & 0x8 == 0 && data.len() > size => {
+                let mut chunks = data.chunks(size).peekable();
+                let mut opcode = opcode;
+                while let Some(chunk) = chunks.next() {
+                    self.queue_frame(chunks.peek().is_none(), opcode, chunk)?;
+                    // only the first frame carries the opcode, all following are continuation frames
+                    opcode = 0x0;
+                }
+                Ok(())
+            },
+            _ => self.queue_frame(true, opcode, data),
+        }
+    }
 
+    /// Appends a frame to the outgoing buffer without writing it. Data frames are passed through
+    /// the negotiated extensions.
+    fn queue_frame(&mut self, fin : bool, opcode : u8, data : &[u8]) -> Result<(), Error> {
+        if opcode & 0x8 != 0 || self.extensions.len() == 0 {
+            return self.encode_frame(fin, 0, opcode, data);
+        }
+        let mut header = FrameHeader::new(fin, opcode, 0);
+        let mut payload = data.to_vec();
+        for extension in self.extensions.iter_mut() {
+            payload = extension.encode_frame(&mut header, payload)?;
+        }
+        self.encode_frame(fin, header.rsv, opcode, &payload)
+    }
 
-        let offset = if data.len() < 126 {
-            // one byte payload length
-            header[1] = (data.len() & 0x7F) as u8;
-            2
-        }else if data.len() <= 0xFFFF {
-            // two byte extended payload length
-            header[1] = 126;
-            let bytes = (data.len() as u16).to_be_bytes();
-            let payload_len = &mut header[2..4];
-            payload_len.clone_from_slice(&bytes);
-            4
-        }else{
-            // 8 byte extended payload length
-            header[1] = 127;
-            let bytes = (data.len() as u64).to_be_bytes();
-            let payload_len = &mut header[2..10];
-            payload_len.clone_from_slice(&bytes);
-            10
-        };
+    fn write_frame(&mut self, fin : bool, opcode : u8, data : &[u8]) -> Result<(), Error> {
+        self.queue_frame(fin, opcode, data)?;
+        self.flush_pending()?;
+        Ok(())
+    }
+
+    fn encode_frame(&mut self, fin : bool, rsv : u8, opcode : u8, data : &[u8]) -> Result<(), Error> {
+        let mut header = FrameHeader::new(fin, opcode, data.len() as u64);
+        header.rsv = rsv;
 
-        let mut hdr = &header[0..offset];
-        while hdr.len() > 0 {
-            let nread = self.connection.write(hdr)?;
-            hdr = &hdr[nread..];
+        if self.role == Role::Client {
+            // clients must mask every frame with a fresh, unpredictable key
+            header.mask = Some(self.next_mask()?);
         }
 
-        // send payload
-        while data.len() > 0 {
-            let nread = self.connection.write(data)?;
-            data = &data[nread..];
+        self.stats.frame_sent(fin, opcode);
+        if let Some(dump) = self.trace {
+            self.log_trace(&trace_line("->", &header, data, dump));
+        }
+        // the whole frame is queued so a partial write can't leave the stream in the middle of a frame
+        header.encode(&mut self.outgoing);
+        let payload_offset = self.outgoing.len();
+        self.outgoing.extend_from_slice(data);
+        if let Some(mask) = header.mask {
+            frame::apply_mask(&mut self.outgoing[payload_offset..], mask);
         }
         Ok(())
     }
 
+    /// Writes the data which couldn't be sent yet because the connection returned `WouldBlock`.
+    /// Sends queue their frames and never fail half way, so on nonblocking connections this has
+    /// to be called again once the connection is writable. Returns the number of bytes still queued.
+    /// Fails with close code 1001 if the peer stopped reading, see `set_write_timeout`.
+    pub fn flush_pending(&mut self) -> Result<usize, Error> {
+        self.write_outgoing()?;
+
+        if self.outgoing.len() == 0 {
+            self.write_stalled_since = None;
+            return Ok(0);
+        }
+        let stalled_since = *self.write_stalled_since.get_or_insert_with(std::time::Instant::now);
+        let timed_out = self.write_timeout.is_some_and(|timeout| stalled_since.elapsed() >= timeout);
+        if self.state != State::Closed && (timed_out || self.outgoing.len() > self.max_outgoing) {
+            // the close frame is queued behind the unsent data, it is only a last attempt
+            self.state = State::Closed;
+            let mut payload = 1001u16.to_be_bytes().to_vec();
+            payload.extend_from_slice(b"peer is not reading");
+            self.encode_frame(true, 0, 0x8, &payload)?;
+            self.write_outgoing().ok();
+            return Err(Error::WebsocketError("peer is not reading"));
+        }
+        Ok(self.outgoing.len())
+    }
+
+    /// Writes as much of the outgoing buffer as the connection accepts.
+    fn write_outgoing(&mut self) -> Result<(), Error> {
+        let mut written = 0;
+        while written < self.outgoing.len() {
+            match self.connection.write(&self.outgoing[written..]) {
+                Ok(0) => return Err(self.write_failed(std::io::ErrorKind::WriteZero.into())),
+                Ok(n) => {
+                    written += n;
+                    self.stats.bytes_sent += n as u64;
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => break,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(self.write_failed(e)),
+            }
+        }
+        self.outgoing.drain(..written);
+        Ok(())
+    }
+
+    /// Closes the websocket after a write to the connection failed. Later writes would only go
+    /// into the same broken connection.
+    fn write_failed(&mut self, error : std::io::Error) -> Error {
+        self.state = State::Closed;
+        self.outgoing.clear();
+        Error::ConnectionClosed(error)
+    }
+
+    /// The number of bytes queued by sends which are not written to the connection yet.
+    pub fn pending_write_bytes(&self) -> usize { self.outgoing.len() }
+
+    /// Whether an event loop should wait for the connection to become readable. True until the
+    /// closing handshake is complete.
+    pub fn wants_read(&self) -> bool { self.state != State::Closed }
+
+    /// Whether an event loop should wait for the connection to become writable and then call
+    /// `flush_pending`.
+    pub fn wants_write(&self) -> bool { self.outgoing.len() > 0 }
+
+    /// Whether messages were already read from the connection and can be returned by `try_read`
+    /// without the connection becoming readable again.
+    pub fn has_pending_messages(&self) -> bool { self.pending.len() > 0 }
+
+    /// The number of received bytes belonging to a frame or message which isn't complete yet.
+    pub fn partial_read_bytes(&self) -> usize {
+        self.incomplete_fragment.len() + self.incomplete_message.bytes.len()
+    }
+
+    /// Counters of the traffic so far. After `split`, each half counts its own traffic.
+    pub fn stats(&self) -> Stats {
+        Stats{messages_received: self.incomplete_message.completed, ..self.stats}
+    }
+
+    /// How much of the data message currently being received has arrived, None between messages.
+    /// The total is only known once the final frame started, which for unfragmented messages is
+    /// their only frame. Sizes are those on the wire, before extensions decode the payload.
+    pub fn incoming_progress(&self) -> Option<Progress> {
+        let message = &self.incomplete_message;
+        let mut progress = Progress{received: message.wire_bytes, total: None};
+        match self.incomplete_fragment.header {
+            Some(header) if !header.is_control() => {
+                progress.received += self.incomplete_fragment.received;
+                if header.fin {
+                    progress.total = Some(message.wire_bytes + header.payload_len as usize);
+                }
+            },
+            _ if !message.in_progress => return None,
+            _ => {},
+        }
+        Some(progress)
+    }
+
+    /// Queues a message without writing it, so a burst of messages can be written together by
+    /// `flush`. Fails if the queue would grow beyond the limit set by `set_max_queued`.
+    /// Close messages can't be queued.
+    pub fn queue<M : Into<Message>>(&mut self, message : M) -> Result<(), Error> {
+        if self.state != State::Open {
+            return Err(Error::WebsocketError("connection is closing"));
+        }
+        let message = message.into();
+        let (opcode, data) : (u8, &[u8]) = match &message {
+            Message::Text(text) => (0x1, str::as_bytes(text)),
+            Message::Binary(data) => (0x2, data),
+            Message::Ping(data) => (0x9, data),
+            Message::Pong(data) => (0xA, data),
+            Message::Close(..) => return Err(Error::WebsocketError("close messages can't be queued")),
+        };
+        if opcode & 0x8 != 0 && data.len() > 125 {
+            return Err(Error::WebsocketError("control frame payload exceeds 125 bytes"));
+        }
+        if self.outgoing.len() + data.len() > self.max_queued {
+            return Err(Error::WebsocketError("outgoing queue is full"));
+        }
+        self.queue_raw(opcode, data)
+    }
+
+    /// Writes the queued messages. Returns the number of bytes still queued, which is only
+    /// non-zero on nonblocking connections.
+    pub fn flush(&mut self) -> Result<usize, Error> {
+        let remaining = self.flush_pending()?;
+        if remaining == 0 {
+            self.connection.flush().map_err(|e| self.write_failed(e))?;
+        }
+        Ok(remaining)
+    }
+
+    /// Sends any kind of message. Close messages start the closing handshake like `close`.
+    pub fn send<M : Into<Message>>(&mut self, message : M) -> Result<(), Error> {
+        match message.into() {
+            Message::Text(text) => self.send_text(&text),
+            Message::Binary(data) => self.send_bytes(&data),
+            Message::Close(code, reason) => self.close(code, reason.as_deref()),
+            Message::Ping(data) => self.send_ping(&data),
+            Message::Pong(data) => self.send_pong(&data),
+        }
+    }
+
     pub fn send_text(&mut self, data : &str) -> Result<(), Error> {
-        self.send(0x1, data.as_bytes())
+        if self.state != State::Open {
+            return Err(Error::WebsocketError("connection is closing"));
+        }
+        self.send_raw(0x1, data.as_bytes())
     }
 
     pub fn send_bytes(&mut self, data : &[u8]) -> Result<(), Error> {
-        self.send(0x2, data)
+        if self.state != State::Open {
+            return Err(Error::WebsocketError("connection is closing"));
+        }
+        self.send_raw(0x2, data)
     }
 
-    pub fn close(&mut self, code : Option<u16>) -> Result<(), Error> {
-        self.closed = true;
-        if let Some(code) = code {
-            self.send(0x8, &code.to_be_bytes())
-        }else{
-            self.send(0x8, &[0u8; 0])
+    /// Sends a single frame as is, bypassing fragmentation, compression and the closing handshake.
+    /// Only the masking key is replaced according to the side of the connection.
+    pub fn send_frame(&mut self, frame : &Frame) -> Result<(), Error> {
+        let mut frame = frame.clone();
+        frame.header.mask = if self.role == Role::Client { Some(self.next_mask()?) } else { None };
+        frame::write_frame(&mut self.outgoing, &frame)?;
+        self.stats.frame_sent(frame.header.fin, frame.header.opcode);
+        if let Some(dump) = self.trace {
+            self.log_trace(&trace_line("->", &frame.header, &frame.payload, dump));
+        }
+        self.flush_pending()?;
+        Ok(())
+    }
+
+    /// Starts a fragmented text message. Every write on the returned writer is sent as one frame,
+    /// the message is completed by `MessageWriter::finish`.
+    pub fn start_text(&mut self) -> Result<MessageWriter<'_, Connection>, Error> {
+        self.start_message(0x1)
+    }
+
+    /// Starts a fragmented binary message, see `start_text`.
+    pub fn start_binary(&mut self) -> Result<MessageWriter<'_, Connection>, Error> {
+        self.start_message(0x2)
+    }
+
+    fn start_message(&mut self, opcode : u8) -> Result<MessageWriter<'_, Connection>, Error> {
+        if self.state != State::Open {
+            return Err(Error::WebsocketError("connection is closing"));
+        }
+        Ok(MessageWriter::new(self, opcode))
+    }
+
+    /// Sends a ping, the peer is expected to answer with a pong carrying the same payload.
+    /// The time until the answer arrives is available from `last_rtt`.
+    pub fn send_ping(&mut self, data : &[u8]) -> Result<(), Error> {
+        self.send_control(0x9, data)?;
+        if self.pings_sent.len() == MAX_PINGS_SENT {
+            self.pings_sent.pop_front();
+        }
+        self.pings_sent.push_back((data.to_vec(), std::time::Instant::now()));
+        Ok(())
+    }
+
+    /// The round-trip time of the most recently answered ping.
+    pub fn last_rtt(&self) -> Option<std::time::Duration> { self.last_rtt }
+
+    fn pong_received(&mut self, payload : &[u8]) {
+        // a pong answers the matching ping, earlier pings are considered lost
+        if let Some(index) = self.pings_sent.iter().position(|(data, _)| data == payload) {
+            let (_, sent) = self.pings_sent.drain(..=index).next_back().unwrap();
+            self.last_rtt = Some(sent.elapsed());
+        }
+    }
+
+    /// Sends an unsolicited pong, which can serve as a unidirectional heartbeat.
+    pub fn send_pong(&mut self, data : &[u8]) -> Result<(), Error> {
+        self.send_control(0xA, data)
+    }
+
+    fn send_control(&mut self, opcode : u8, data : &[u8]) -> Result<(), Error> {
+        if self.state != State::Open {
+            return Err(Error::WebsocketError("connection is closing"));
+        }
+        if data.len() > 125 {
+            return Err(Error::WebsocketError("control frame payload exceeds 125 bytes"));
+        }
+        self.send_raw(opcode, data)
+    }
+
+    /// Starts the closing handshake and waits until the peer answered with its own close frame.
+    /// Messages arriving in the meantime are discarded, the peer's close message is kept and
+    /// returned by the next `read_message`. If the answer doesn't arrive within the
+    /// close timeout, the connection is considered closed abnormally: an error is returned and
+    /// `read_message` reports a close with code 1006.
+    /// The underlying stream is closed once the Websocket is dropped.
+    /// A reason can only be given together with a status code and is limited to 123 bytes.
+    /// Once the closing handshake started, further calls do nothing.
+    pub fn close(&mut self, code : Option<u16>, reason : Option<&str>) -> Result<(), Error> {
+        if self.state != State::Open {
+            return Ok(());
+        }
+
+        let payload = close_payload(code, reason)?;
+        self.state = State::Closing;
+        self.send_raw(0x8, &payload)?;
+
+        let start = std::time::Instant::now();
+        while self.state == State::Closing {
+            if start.elapsed() >= self.close_timeout {
+                self.state = State::Closed;
+                // 1006 is never sent, it reports a connection closed without a close frame
+                self.pending.push_back(Message::Close(Some(1006), Some(String::from("close handshake timed out"))));
+                return Err(Error::WebsocketError("close handshake timed out"));
+            }
+            match self.read() {
+                // the peer's answer is kept for read_message
+                Ok(messages) => self.pending.extend(messages.into_iter().filter(|m| matches!(m, Message::Close(..)))),
+                Err(e) => {
+                    self.state = State::Closed;
+                    return Err(e);
+                },
+            }
+        }
+
+        self.state = State::Closed;
+        self.flush_pending()?;
+        self.connection.flush().map_err(|e| self.write_failed(e))?;
+        Ok(())
+    }
+
+    /// Handles a violation of RFC 6455 which can safely be tolerated: fails the connection in
+    /// strict mode, otherwise logs it as a warning and carries on.
+    fn violation(&mut self, code : u16, reason : &'static str) -> Result<(), Error> {
+        match self.conformance {
+            Conformance::Strict => Err(self.fail(code, reason)),
+            Conformance::Lenient => {
+                self.log_warning(&format!("ws: tolerating protocol violation: {reason}"));
+                Ok(())
+            },
+        }
+    }
+
+    /// Closes the websocket after the connection was lost, which is reported like a close frame
+    /// with code 1006. Nothing queued can be sent anymore.
+    fn connection_lost(&mut self, reason : Option<String>) -> Received {
+        self.state = State::Closed;
+        self.outgoing.clear();
+        Received::Control(Message::Close(Some(1006), reason))
+    }
+
+    /// Fails the connection: sends a close frame with `code` without waiting for an answer.
+    fn fail(&mut self, code : u16, reason : &'static str) -> Error {
+        // 1001 reports a timeout on our side rather than anything the peer sent
+        if code != 1001 {
+            self.stats.protocol_errors += 1;
+        }
+        if self.state == State::Open {
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.as_bytes());
+            self.send_raw(0x8, &payload).ok();
         }
+        self.state = State::Closed;
+        Error::WebsocketError(reason)
     }
 }
 
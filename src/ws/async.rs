@@ -0,0 +1,228 @@
+//! Websockets on tokio's `AsyncRead` and `AsyncWrite` streams, for applications which can't block
+//! a thread per connection.
+//!
+//! The protocol is handled by the same `Websocket` as the blocking API. It runs on in-memory
+//! buffers, which the async methods fill from the stream and write out to it. Keepalive pings and
+//! the close timeout aren't supported, wrap calls in `tokio::time::timeout` instead.
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::http;
+use super::{Config, Error, Message, State};
+
+/// The connection of the inner websocket. Reads consume the bytes received from the stream so far
+/// and return `WouldBlock` once there are none, writes are collected until they are sent.
+#[derive(Default)]
+struct Buffers {
+    input : VecDeque<u8>,
+    output : Vec<u8>,
+}
+
+/// A websocket on an async stream.
+pub struct Websocket<T : AsyncRead + AsyncWrite + Unpin> {
+    stream : T,
+    inner : super::Websocket<Buffers>,
+    // messages read from the stream but not returned by `read` yet
+    messages : VecDeque<Message>,
+}
+
+impl Read for Buffers {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        if self.input.len() == 0 && buf.len() > 0 {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(self.input.len());
+        for (byte, value) in buf.iter_mut().zip(self.input.drain(..n)) {
+            *byte = value;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Buffers {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads from `stream` until `head` contains a complete HTTP head and returns its length. Bytes
+/// following the head may have been read as well.
+async fn read_head<T : AsyncRead + Unpin>(stream : &mut T, head : &mut Vec<u8>, limits : &http::RequestLimits) -> Result<usize, Error> {
+    let mut buffer = [0; 1024];
+    loop {
+        if let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            return Ok(end + 4);
+        }
+        if head.len() > limits.get_max_head_size() {
+            return Err(Error::WebsocketError("handshake exceeds the maximum size"));
+        }
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Err(Error::WebsocketError("connection closed during handshake"));
+        }
+        head.extend_from_slice(&buffer[..n]);
+    }
+}
+
+/// Reads the upgrade request of a client and answers it, see `ws::upgrade`.
+pub async fn accept<T : AsyncRead + AsyncWrite + Unpin>(stream : T) -> Result<Websocket<T>, Error> {
+    accept_with_config(stream, &Config::default()).await
+}
+
+/// Like `accept`, with the subprotocols, extensions and origin check of `config`. A rejected
+/// request is answered with the matching error response before the error is returned.
+pub async fn accept_with_config<T : AsyncRead + AsyncWrite + Unpin>(mut stream : T, config : &Config) -> Result<Websocket<T>, Error> {
+    let limits = super::handshake_limits();
+    let mut head = Vec::new();
+    let end = read_head(&mut stream, &mut head, &limits).await?;
+    let request = http::parse_request_with(&mut &head[..end], &limits).map_err(|_| Error::WebsocketError("invalid upgrade request"))?;
+
+    match super::upgrade_with_config(Buffers::default(), &request, config) {
+        Ok(inner) => Websocket::new(stream, inner, &head[end..]).await,
+        Err(e) => {
+            let mut response = Vec::new();
+            e.response(request.get_version()).send(&mut response);
+            stream.write_all(&response).await?;
+            stream.flush().await?;
+            Err(Error::WebsocketError("upgrade request rejected"))
+        },
+    }
+}
+
+/// Performs the client side of the opening handshake on an already established stream.
+pub async fn client_handshake<T : AsyncRead + AsyncWrite + Unpin>(mut stream : T, host : &str, path : &str) -> Result<Websocket<T>, Error> {
+    let (request, key) = super::handshake_request(host, path)?;
+    stream.write_all(&request.to_bytes()).await?;
+    stream.flush().await?;
+
+    let mut head = Vec::new();
+    let end = read_head(&mut stream, &mut head, &super::handshake_limits()).await?;
+    let response = http::client::parse_response(&mut &head[..end], request.get_method())
+        .map_err(|_| Error::WebsocketError("invalid handshake response"))?;
+    super::check_handshake_response(&response, &key)?;
+    // the server may have sent frames right after its response
+    Websocket::new(stream, super::Websocket::client(Buffers::default()), &head[end..]).await
+}
+
+impl<T : AsyncRead + AsyncWrite + Unpin> Websocket<T> {
+    async fn new(stream : T, mut inner : super::Websocket<Buffers>, received : &[u8]) -> Result<Self, Error> {
+        // everything buffered is parsed by a single read
+        inner.set_drain(true);
+        inner.connection.input.extend(received);
+        let mut ws = Self {
+            stream: stream,
+            inner: inner,
+            messages: VecDeque::new(),
+        };
+        // the response of a server
+        ws.write_output().await?;
+        Ok(ws)
+    }
+
+    /// Waits for the next message. Pings are answered before they are returned. A failing or ending
+    /// stream is reported as a close with code 1006. Once the close message was returned, further
+    /// reads fail.
+    pub async fn read(&mut self) -> Result<Message, Error> {
+        let mut buffer = [0; 4096];
+        loop {
+            if let Some(message) = self.messages.pop_front() {
+                return Ok(message);
+            }
+
+            let result = self.inner.try_read();
+            // pongs and the answer to a close frame
+            self.write_output().await?;
+            match result {
+                Ok(messages) => self.messages.extend(messages),
+                Err(Error::WouldBlock) => {},
+                Err(e) => return Err(e),
+            }
+            if self.messages.len() > 0 {
+                continue;
+            }
+            if self.inner.is_closed() {
+                return Err(Error::WebsocketError("websocket is closed"));
+            }
+
+            let n = match self.stream.read(&mut buffer).await {
+                Ok(n) => n,
+                Err(e) => {
+                    // reported like a failing connection of the blocking websocket
+                    self.inner.state = State::Closed;
+                    return Ok(Message::Close(Some(1006), Some(e.to_string())));
+                },
+            };
+            if n == 0 {
+                // the peer went away without a close frame
+                self.inner.state = State::Closed;
+                return Ok(Message::Close(Some(1006), None));
+            }
+            self.inner.connection.input.extend(&buffer[..n]);
+        }
+    }
+
+    /// Sends a message and waits until the stream accepted it.
+    pub async fn send<M : Into<Message>>(&mut self, message : M) -> Result<(), Error> {
+        self.inner.send(message)?;
+        self.write_output().await
+    }
+
+    /// Starts the closing handshake and waits until the peer answered with its own close frame.
+    /// Messages arriving in the meantime are discarded, the peer's close message is returned by
+    /// the next `read`. A reason can only be given together with a status code.
+    pub async fn close(&mut self, code : Option<u16>, reason : Option<&str>) -> Result<(), Error> {
+        if self.inner.state != State::Open {
+            return Ok(());
+        }
+
+        let payload = super::close_payload(code, reason)?;
+        self.inner.state = State::Closing;
+        self.inner.send_raw(0x8, &payload)?;
+        self.write_output().await?;
+
+        self.messages.clear();
+        loop {
+            if let Message::Close(code, reason) = self.read().await? {
+                self.messages.push_back(Message::Close(code, reason));
+                return Ok(());
+            }
+        }
+    }
+
+    /// Limits the size of incoming messages, see `ws::Websocket::set_max_message_size`.
+    pub fn set_max_message_size(&mut self, max_size : usize) {
+        self.inner.set_max_message_size(max_size);
+    }
+
+    /// Where the websocket is in the closing handshake.
+    pub fn state(&self) -> State { self.inner.state }
+
+    /// The subprotocol agreed on during the handshake, if any.
+    pub fn subprotocol(&self) -> Option<&str> { self.inner.subprotocol() }
+
+    /// The extensions agreed on during the handshake, see `ws::Websocket::extensions`.
+    pub fn extensions(&self) -> Vec<super::extension::Offer<'_>> { self.inner.extensions() }
+
+    pub fn get_ref(&self) -> &T { &self.stream }
+
+    pub fn get_mut(&mut self) -> &mut T { &mut self.stream }
+
+    /// Writes everything the inner websocket produced to the stream.
+    async fn write_output(&mut self) -> Result<(), Error> {
+        if self.inner.connection.output.len() == 0 {
+            return Ok(());
+        }
+        self.stream.write_all(&self.inner.connection.output).await?;
+        self.inner.connection.output.clear();
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use super::extension::{Extension, Offer};
+use super::frame::FrameHeader;
+use super::{Error, ProtocolError};
+
+/// Every message compressed with a sync flush ends in these four bytes. They are stripped
+/// before sending and appended again before inflating (RFC 7692 section 7.2.1).
+const TAIL : [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+/// Negotiated state of the permessage-deflate extension.
+pub struct PerMessageDeflate {
+    compress : Compress,
+    decompress : Decompress,
+    // reset the compressor after every outgoing message
+    server_no_context_takeover : bool,
+    // the peer resets its compressor after every message, so we reset our decompressor as well
+    client_no_context_takeover : bool,
+    // the incoming message being received is compressed
+    inflating : bool,
+}
+
+impl PerMessageDeflate {
+
+    pub const NAME : &'static str = "permessage-deflate";
+
+    /// The no_context_takeover arguments request dropping the sliding window after every message
+    /// even if the client didn't ask for it.
+    pub fn new(server_no_context_takeover : bool, client_no_context_takeover : bool) -> Self {
+        Self {
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            server_no_context_takeover: server_no_context_takeover,
+            client_no_context_takeover: client_no_context_takeover,
+            inflating: false,
+        }
+    }
+
+    pub fn compress(&mut self, data : &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = self.compress_fragment(data)?;
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        if self.server_no_context_takeover {
+            self.compress.reset();
+        }
+        return Ok(out);
+    }
+
+    /// Compresses one fragment of a message which is sent in several frames. The output keeps the
+    /// trailing empty block, the last frame of the message has to be produced by `compress`.
+    pub fn compress_fragment(&mut self, data : &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity().max(64));
+            }
+            self.compress.compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|_| Error::WebsocketError("could not compress payload"))?;
+
+            let consumed = (self.compress.total_in() - start) as usize;
+            // the flush is complete once all input is consumed and the output wasn't filled up
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        return Ok(out);
+    }
+
+    /// Inflates one fragment of a message, `fin` marks the last fragment of the message. Fails
+    /// with 1009 as soon as the output would exceed `max_len` bytes, so a small frame can't
+    /// inflate to an arbitrary amount of memory.
+    pub fn decompress_fragment(&mut self, mut data : Vec<u8>, fin : bool, max_len : usize) -> Result<Vec<u8>, ProtocolError> {
+        if fin {
+            data.extend_from_slice(&TAIL);
+        }
+        let invalid = || ProtocolError::new(1007, "invalid compressed payload");
+        // one byte more than allowed tells a payload which exceeds the limit from one which fills it
+        let limit = max_len.saturating_add(1);
+        let mut out = Vec::with_capacity((data.len() * 2).min(limit));
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let produced = out.len();
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity().max(64).min(limit - out.len()));
+            }
+            let status = self.decompress.decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|_| invalid())?;
+            if out.len() > max_len {
+                return Err(ProtocolError::new(1009, "message exceeds the maximum message size"));
+            }
+
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if status == Status::StreamEnd || (consumed == data.len() && out.len() < out.capacity()) {
+                break;
+            }
+            if status == Status::BufError && out.len() == produced {
+                return Err(invalid());
+            }
+        }
+
+        if fin && self.client_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        return Ok(out);
+    }
+}
+
+impl Extension for PerMessageDeflate {
+    fn name(&self) -> &str { Self::NAME }
+
+    fn reserved_bits(&self) -> u8 { 0b100 }
+
+    /// Picks the first acceptable offer and returns the value of the Sec-WebSocket-Extensions
+    /// response header.
+    fn negotiate(&mut self, offers : &[Offer<'_>]) -> Option<String> {
+        'offers: for offer in offers {
+            let mut server_no_context_takeover = self.server_no_context_takeover;
+            let mut client_no_context_takeover = self.client_no_context_takeover;
+            let mut seen = Vec::new();
+            for (key, value) in &offer.params {
+                if seen.contains(key) {
+                    continue 'offers; // duplicate parameters make the offer invalid
+                }
+                seen.push(key);
+                match (*key, value.as_deref()) {
+                    ("server_no_context_takeover", None) => server_no_context_takeover = true,
+                    ("client_no_context_takeover", None) => client_no_context_takeover = true,
+                    // only the default window size is supported for the compressor
+                    ("server_max_window_bits", Some("15")) => {},
+                    ("client_max_window_bits", _) => {},
+                    _ => continue 'offers,
+                }
+            }
+
+            let mut response = String::from(Self::NAME);
+            if server_no_context_takeover {
+                response += "; server_no_context_takeover";
+            }
+            if client_no_context_takeover {
+                response += "; client_no_context_takeover";
+            }
+            self.server_no_context_takeover = server_no_context_takeover;
+            self.client_no_context_takeover = client_no_context_takeover;
+            return Some(response);
+        }
+        None
+    }
+
+    /// Every data message is compressed, RSV1 marks the first frame of a compressed message.
+    fn encode_frame(&mut self, header : &mut FrameHeader, payload : Vec<u8>) -> Result<Vec<u8>, Error> {
+        if header.opcode != 0x0 {
+            header.rsv |= 0b100;
+        }
+        if header.fin {
+            self.compress(&payload)
+        }else{
+            self.compress_fragment(&payload)
+        }
+    }
+
+    fn decode_frame(&mut self, header : &FrameHeader, payload : Vec<u8>, max_len : usize) -> Result<Vec<u8>, ProtocolError> {
+        if header.opcode != 0x0 {
+            self.inflating = header.rsv & 0b100 != 0;
+        }else if header.rsv & 0b100 != 0 {
+            // only the first frame of a message is marked
+            return Err(ProtocolError::new(1002, "RSV1 set on a continuation frame"));
+        }
+        if !self.inflating {
+            return Ok(payload);
+        }
+        self.decompress_fragment(payload, header.fin, max_len)
+    }
+
+    /// The reading half keeps the decompressor, the writing half the compressor.
+    fn split(self : Box<Self>) -> Option<(Box<dyn Extension>, Box<dyn Extension>)> {
+        let writing = Self {
+            compress: self.compress,
+            decompress: Decompress::new(false),
+            server_no_context_takeover: self.server_no_context_takeover,
+            client_no_context_takeover: self.client_no_context_takeover,
+            inflating: false,
+        };
+        let reading = Self {
+            compress: Compress::new(Compression::default(), false),
+            decompress: self.decompress,
+            server_no_context_takeover: self.server_no_context_takeover,
+            client_no_context_takeover: self.client_no_context_takeover,
+            inflating: self.inflating,
+        };
+        Some((Box::new(reading), Box::new(writing)))
+    }
+}
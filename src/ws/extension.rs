@@ -0,0 +1,169 @@
+//! Websocket extensions (RFC 6455 section 9), negotiated during the upgrade and applied to the
+//! payload of every data frame. permessage-deflate is implemented on top of this.
+
+use std::borrow::Cow;
+
+use crate::http::is_token_char;
+use super::frame::FrameHeader;
+use super::{Error, ProtocolError};
+
+/// One entry of a Sec-WebSocket-Extensions header: the extension token and its parameters.
+/// Quoted parameter values are unescaped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Offer<'a> {
+    pub name : &'a str,
+    pub params : Vec<(&'a str, Option<Cow<'a, str>>)>,
+}
+
+impl<'a> Offer<'a> {
+    /// The value of the parameter `name`: None if it is missing, Some(None) if it has no value.
+    pub fn param(&self, name : &str) -> Option<Option<&str>> {
+        self.params.iter().find(|(key, _)| *key == name).map(|(_, value)| value.as_deref())
+    }
+}
+
+/// Parses a Sec-WebSocket-Extensions header (RFC 6455 section 9.1) into its offers, in the
+/// client's order of preference. Fails if the header doesn't follow the grammar.
+pub fn parse_offers(header : &str) -> Result<Vec<Offer<'_>>, Error> {
+    let mut parser = Parser{input: header, position: 0};
+    let mut offers = Vec::new();
+    loop {
+        parser.skip_whitespace();
+        if parser.at_end() {
+            break;
+        }
+        // the list syntax allows empty elements
+        if parser.eat(b',') {
+            continue;
+        }
+
+        let name = parser.token()?;
+        let mut params = Vec::new();
+        parser.skip_whitespace();
+        while parser.eat(b';') {
+            parser.skip_whitespace();
+            let key = parser.token()?;
+            parser.skip_whitespace();
+            let value = if parser.eat(b'=') {
+                parser.skip_whitespace();
+                let value = if parser.peek() == Some(b'"') { parser.quoted_string()? } else { Cow::Borrowed(parser.token()?) };
+                // quoting doesn't widen what a value may contain
+                if value.len() == 0 || !value.bytes().all(is_token_char) {
+                    return Err(Error::WebsocketError("extension parameter value is not a token"));
+                }
+                parser.skip_whitespace();
+                Some(value)
+            }else{
+                None
+            };
+            params.push((key, value));
+        }
+        offers.push(Offer{name: name, params: params});
+
+        if !parser.at_end() && !parser.eat(b',') {
+            return Err(Error::WebsocketError("expected ',' between extensions"));
+        }
+    }
+    return Ok(offers);
+}
+
+struct Parser<'a> {
+    input : &'a str,
+    position : usize,
+}
+
+impl<'a> Parser<'a> {
+    fn at_end(&self) -> bool {
+        self.position == self.input.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.position).copied()
+    }
+
+    fn eat(&mut self, c : u8) -> bool {
+        if self.peek() == Some(c) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek() == Some(b' ') || self.peek() == Some(b'\t') {
+            self.position += 1;
+        }
+    }
+
+    fn token(&mut self) -> Result<&'a str, Error> {
+        let start = self.position;
+        while self.peek().is_some_and(is_token_char) {
+            self.position += 1;
+        }
+        if self.position == start {
+            return Err(Error::WebsocketError("expected a token in Sec-WebSocket-Extensions"));
+        }
+        Ok(&self.input[start..self.position])
+    }
+
+    /// A quoted string starting at the current position, borrowed unless it contains escapes.
+    fn quoted_string(&mut self) -> Result<Cow<'a, str>, Error> {
+        self.position += 1; // opening quote
+        let start = self.position;
+        let mut unescaped : Option<String> = None;
+        loop {
+            match self.peek() {
+                None => return Err(Error::WebsocketError("unterminated quoted string in Sec-WebSocket-Extensions")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let escaped = self.input[self.position + 1..].chars().next()
+                        .ok_or(Error::WebsocketError("unterminated quoted string in Sec-WebSocket-Extensions"))?;
+                    unescaped.get_or_insert_with(|| String::from(&self.input[start..self.position])).push(escaped);
+                    self.position += 1 + escaped.len_utf8();
+                },
+                Some(_) => {
+                    let c = self.input[self.position..].chars().next().unwrap();
+                    if let Some(unescaped) = &mut unescaped {
+                        unescaped.push(c);
+                    }
+                    self.position += c.len_utf8();
+                },
+            }
+        }
+        let value = match unescaped {
+            Some(unescaped) => Cow::Owned(unescaped),
+            None => Cow::Borrowed(&self.input[start..self.position]),
+        };
+        self.position += 1; // closing quote
+        Ok(value)
+    }
+}
+
+/// An extension of a single connection. Outgoing data frames pass through the negotiated
+/// extensions in the order of negotiation, incoming ones in reverse. Control frames are not
+/// passed to extensions.
+pub trait Extension : Send {
+    /// The extension token used in Sec-WebSocket-Extensions.
+    fn name(&self) -> &str;
+
+    /// The reserved bits the extension gives a meaning to, as in `FrameHeader::rsv`.
+    fn reserved_bits(&self) -> u8;
+
+    /// Picks one of the client's offers of this extension, in the client's order of preference.
+    /// Returns the entry for the Sec-WebSocket-Extensions response header, None declines.
+    fn negotiate(&mut self, offers : &[Offer<'_>]) -> Option<String>;
+
+    /// Transforms the payload of an outgoing data frame and may set reserved bits in its header.
+    fn encode_frame(&mut self, header : &mut FrameHeader, payload : Vec<u8>) -> Result<Vec<u8>, Error>;
+
+    /// Transforms the payload of an incoming data frame. Errors fail the connection with their code.
+    /// Payloads which would grow beyond `max_len` bytes, what is left of the maximum message size,
+    /// should fail with 1009 before they are produced.
+    fn decode_frame(&mut self, header : &FrameHeader, payload : Vec<u8>, max_len : usize) -> Result<Vec<u8>, ProtocolError>;
+
+    /// Separates the state for reading and for writing, needed for `Websocket::split`.
+    /// Extensions which can't be split prevent splitting the connection.
+    fn split(self : Box<Self>) -> Option<(Box<dyn Extension>, Box<dyn Extension>)> {
+        None
+    }
+}
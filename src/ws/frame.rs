@@ -0,0 +1,45 @@
+//! Low level access to websocket frames (RFC 6455 section 5.2), for custom fragmentation,
+//! proxies or inspecting traffic. `Websocket` is built on top of these types, which live in
+//! `codec` together with the parts that don't need `std`.
+
+use std::io::Read;
+
+use super::Error;
+use crate::codec;
+
+pub use crate::codec::{apply_mask, Frame, FrameHeader};
+pub use crate::codec::{OPCODE_BINARY, OPCODE_CLOSE, OPCODE_CONTINUATION, OPCODE_PING, OPCODE_PONG, OPCODE_TEXT};
+
+/// Reads exactly one frame, blocking until it is complete. The payload is unmasked.
+pub fn read_frame<Reader : Read>(reader : &mut Reader) -> Result<Frame, Error> {
+    let mut bytes = vec![0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    let len = FrameHeader::encoded_len(&bytes).unwrap();
+    bytes.resize(len, 0);
+    reader.read_exact(&mut bytes[2..])?;
+    let (header, _) = FrameHeader::parse(&bytes).unwrap();
+
+    // the payload grows as it arrives instead of trusting the announced length for an allocation
+    let mut payload = Vec::new();
+    reader.by_ref().take(header.payload_len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < header.payload_len {
+        return Err(Error::IoError(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    if let Some(mask) = header.mask {
+        apply_mask(&mut payload, mask);
+    }
+
+    Ok(Frame {
+        header: header,
+        payload: payload,
+    })
+}
+
+/// Writes a frame, masking the payload if the header carries a masking key.
+/// The header's payload length is taken from the payload.
+pub fn write_frame<Writer : std::io::Write>(writer : &mut Writer, frame : &Frame) -> Result<(), Error> {
+    let mut bytes = Vec::new();
+    codec::encode_frame(frame, &mut bytes);
+    writer.write_all(&bytes)?;
+    Ok(())
+}
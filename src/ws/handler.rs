@@ -0,0 +1,86 @@
+use super::{Error, Message, Progress, State, Websocket};
+
+/// Callbacks for the events of a connection, driven by `Websocket::run`. Only `on_message` has to
+/// be implemented. The websocket is passed along to send messages or close the connection.
+pub trait Handler<Connection : std::io::Read + std::io::Write> {
+    /// Called once before the first message is read.
+    fn on_open(&mut self, _socket : &mut Websocket<Connection>) {}
+
+    /// Called for every text and binary message.
+    fn on_message(&mut self, socket : &mut Websocket<Connection>, message : Message);
+
+    /// Called for every ping, after the pong was sent.
+    fn on_ping(&mut self, _socket : &mut Websocket<Connection>, _payload : &[u8]) {}
+
+    /// Called for every pong with the latest measured round-trip time, see `Websocket::last_rtt`.
+    fn on_pong(&mut self, _socket : &mut Websocket<Connection>, _latency : Option<std::time::Duration>) {}
+
+    /// Called after a read which received part of a message without completing it, e.g. to
+    /// report the progress of an upload or abort it by closing the connection.
+    fn on_progress(&mut self, _socket : &mut Websocket<Connection>, _progress : Progress) {}
+
+    /// Called once when the connection is closed, with the code and reason of the close frame.
+    /// A connection closed without a close frame, e.g. after an error, is reported with 1006.
+    fn on_close(&mut self, _code : Option<u16>, _reason : Option<&str>) {}
+
+    /// Called if reading fails, the connection is closed afterwards.
+    fn on_error(&mut self, _socket : &mut Websocket<Connection>, _error : &Error) {}
+}
+
+impl<Connection : std::io::Read + std::io::Write> Websocket<Connection> {
+    /// Reads messages and passes them to `handler` until the connection is closed, or a shutdown
+    /// set with `set_shutdown` is requested.
+    pub fn run<H : Handler<Connection>>(&mut self, handler : &mut H) {
+        handler.on_open(self);
+        loop {
+            if let Some(request) = self.shutdown.as_ref().and_then(|shutdown| shutdown.request()) {
+                if self.state == State::Open {
+                    // the peer's reply, or 1006 if it doesn't arrive in time, is handled below
+                    self.set_close_timeout(request.deadline.saturating_duration_since(std::time::Instant::now()));
+                    self.close(Some(request.code), Some(&request.reason)).ok();
+                }
+            }
+
+            if self.is_closed() && self.pending.len() == 0 {
+                handler.on_close(Some(1006), None);
+                return;
+            }
+
+            let messages = match self.read() {
+                Ok(messages) => messages,
+                Err(e) => {
+                    handler.on_error(self, &e);
+                    handler.on_close(Some(1006), None);
+                    return;
+                },
+            };
+            for message in messages {
+                if !self.dispatch(handler, message) {
+                    return;
+                }
+            }
+            let progress = if self.last_read_empty { None } else { self.incoming_progress() };
+            if let Some(progress) = progress {
+                handler.on_progress(self, progress);
+            }
+        }
+    }
+
+    /// Passes a message read from the connection to the matching callback. Returns false once the
+    /// close message was handled.
+    pub(crate) fn dispatch<H : Handler<Connection>>(&mut self, handler : &mut H, message : Message) -> bool {
+        match message {
+            Message::Close(code, reason) => {
+                handler.on_close(code, reason.as_deref());
+                return false;
+            },
+            Message::Ping(payload) => handler.on_ping(self, &payload),
+            Message::Pong(_) => {
+                let latency = self.last_rtt();
+                handler.on_pong(self, latency);
+            },
+            message => handler.on_message(self, message),
+        }
+        return true;
+    }
+}
@@ -0,0 +1,132 @@
+//! Fan-out of messages to many connections, e.g. for chat or notification servers.
+//!
+//! Every connection is `split` and its writer registered with the hub, while the reader stays with
+//! the thread handling the connection. Connections which fail to send are dropped from the hub.
+//! With the `bytes` feature, the connections share the payload of a message instead of each
+//! getting a copy.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{Error, Message, WsWriter};
+
+/// Identifies a connection registered with a `Hub`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ConnectionId(u64);
+
+struct Entry {
+    writer : Mutex<WsWriter>,
+    topics : Mutex<HashSet<String>>,
+}
+
+/// A set of connections which can be sent to as a whole or by topic. Meant to be shared between
+/// the connection threads in an `Arc`.
+pub struct Hub {
+    connections : Mutex<HashMap<ConnectionId, Arc<Entry>>>,
+    next_id : AtomicU64,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Self {
+            connections: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn register(&self, writer : WsWriter) -> ConnectionId {
+        let id = ConnectionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let entry = Entry {
+            writer: Mutex::new(writer),
+            topics: Mutex::new(HashSet::new()),
+        };
+        self.lock().insert(id, Arc::new(entry));
+        return id;
+    }
+
+    /// Removes the connection, e.g. once its reader saw the close message.
+    pub fn unregister(&self, id : ConnectionId) {
+        self.lock().remove(&id);
+    }
+
+    pub fn len(&self) -> usize { self.lock().len() }
+
+    pub fn is_empty(&self) -> bool { self.lock().is_empty() }
+
+    /// Subscribes the connection to `topic`. Returns false if the connection isn't registered.
+    pub fn subscribe(&self, id : ConnectionId, topic : &str) -> bool {
+        match self.lock().get(&id) {
+            Some(entry) => {
+                entry.topics.lock().unwrap_or_else(|e| e.into_inner()).insert(String::from(topic));
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn unsubscribe(&self, id : ConnectionId, topic : &str) {
+        if let Some(entry) = self.lock().get(&id) {
+            entry.topics.lock().unwrap_or_else(|e| e.into_inner()).remove(topic);
+        }
+    }
+
+    /// Sends a message to a single connection. The connection is removed if sending fails.
+    pub fn send_to<M : Into<Message>>(&self, id : ConnectionId, message : M) -> Result<(), Error> {
+        let entry = match self.lock().get(&id) {
+            Some(entry) => entry.clone(),
+            None => return Err(Error::WebsocketError("connection is not registered")),
+        };
+        let result = entry.writer.lock().unwrap_or_else(|e| e.into_inner()).send(message);
+        if result.is_err() {
+            self.unregister(id);
+        }
+        return result;
+    }
+
+    /// Sends a message to every connection. Returns the number of connections it was delivered to.
+    pub fn broadcast<M : Into<Message>>(&self, message : M) -> usize {
+        self.send_where(message.into(), |_| true)
+    }
+
+    /// Sends a message to the connections subscribed to `topic`. Returns the number of connections
+    /// it was delivered to.
+    pub fn publish<M : Into<Message>>(&self, topic : &str, message : M) -> usize {
+        self.send_where(message.into(), |topics| topics.contains(topic))
+    }
+
+    fn send_where<F : Fn(&HashSet<String>) -> bool>(&self, message : Message, filter : F) -> usize {
+        // the map isn't locked while sending, so a slow connection doesn't block registrations
+        let entries : Vec<(ConnectionId, Arc<Entry>)> = self.lock().iter()
+            .filter(|(_, entry)| filter(&entry.topics.lock().unwrap_or_else(|e| e.into_inner())))
+            .map(|(id, entry)| (*id, entry.clone()))
+            .collect();
+
+        let mut delivered = 0;
+        let mut dead = Vec::new();
+        for (id, entry) in entries {
+            match entry.writer.lock().unwrap_or_else(|e| e.into_inner()).send(message.clone()) {
+                Ok(()) => delivered += 1,
+                Err(_) => dead.push(id),
+            }
+        }
+
+        if dead.len() > 0 {
+            let mut connections = self.lock();
+            for id in dead {
+                connections.remove(&id);
+            }
+        }
+        return delivered;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<ConnectionId, Arc<Entry>>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
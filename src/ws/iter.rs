@@ -0,0 +1,53 @@
+use super::{Error, Message, Websocket};
+
+/// Iterator over the messages of a connection, created by `Websocket::messages`.
+pub struct Messages<'a, Connection : std::io::Read + std::io::Write> {
+    socket : &'a mut Websocket<Connection>,
+    done : bool,
+}
+
+impl<'a, Connection : std::io::Read + std::io::Write> Messages<'a, Connection> {
+    pub(super) fn new(socket : &'a mut Websocket<Connection>) -> Self {
+        Self {
+            socket: socket,
+            done: false,
+        }
+    }
+
+    /// The connection being iterated, e.g. to answer a message from within the loop.
+    pub fn socket(&mut self) -> &mut Websocket<Connection> {
+        self.socket
+    }
+}
+
+impl<Connection : std::io::Read + std::io::Write> Iterator for Messages<'_, Connection> {
+    type Item = Result<Message, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || (self.socket.is_closed() && self.socket.pending.len() == 0) {
+            return None;
+        }
+
+        match self.socket.read_message() {
+            Ok(message) => {
+                if let Message::Close(..) = message {
+                    self.done = true;
+                }
+                Some(Ok(message))
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+impl<'a, Connection : std::io::Read + std::io::Write> IntoIterator for &'a mut Websocket<Connection> {
+    type Item = Result<Message, Error>;
+    type IntoIter = Messages<'a, Connection>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.messages()
+    }
+}
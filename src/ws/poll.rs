@@ -0,0 +1,217 @@
+//! An event loop serving many websockets from a single thread with mio, for servers where a
+//! thread per connection doesn't scale.
+//!
+//! Every connection is registered with a `mio::Poll`. Readiness events drive the opening
+//! handshake, reading and the writes which the connection didn't accept right away. Messages are
+//! passed to a `Handler` created for each connection. Keepalive pings are only sent while a
+//! connection receives data.
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+
+use crate::http;
+use super::{Config, Error, Handler, Websocket};
+
+const LISTENER : Token = Token(0);
+
+// most connections are open, boxing the websocket would only save memory during handshakes
+#[allow(clippy::large_enum_variant)]
+enum Entry<H> {
+    /// Waiting for the complete upgrade request.
+    Handshake {
+        stream : TcpStream,
+        request : Vec<u8>,
+        peer_addr : SocketAddr,
+    },
+    Open {
+        socket : Websocket<TcpStream>,
+        handler : H,
+        // the connection is registered for writable events
+        writable : bool,
+    },
+}
+
+/// Accepts connections on a listener and serves them as websockets. `poll` handles the events of
+/// one wakeup, `run` keeps doing so.
+pub struct Server<H : Handler<TcpStream>> {
+    poll : Poll,
+    events : Events,
+    listener : TcpListener,
+    connections : HashMap<Token, Entry<H>>,
+    next_token : usize,
+    config : Config,
+    new_handler : Box<dyn FnMut() -> H>,
+}
+
+impl<H : Handler<TcpStream>> Server<H> {
+    /// Listens on `addr`. Every accepted websocket gets its own handler from `new_handler`.
+    pub fn bind<F : FnMut() -> H + 'static>(addr : SocketAddr, new_handler : F) -> std::io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut listener = TcpListener::bind(addr)?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+        Ok(Self {
+            poll: poll,
+            events: Events::with_capacity(1024),
+            listener: listener,
+            connections: HashMap::new(),
+            next_token: LISTENER.0 + 1,
+            config: Config::default(),
+            new_handler: Box::new(new_handler),
+        })
+    }
+
+    /// The configuration used for the handshakes of connections accepted from now on.
+    pub fn set_config(&mut self, config : Config) {
+        self.config = config;
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The number of connections, including those still in the opening handshake.
+    pub fn connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Serves connections until polling fails.
+    pub fn run(&mut self) -> std::io::Result<()> {
+        loop {
+            self.poll(None)?;
+        }
+    }
+
+    /// Waits up to `timeout` for events and handles them. Errors of single connections close them
+    /// and are reported to their handler, only a failure of the poll itself is returned.
+    pub fn poll(&mut self, timeout : Option<Duration>) -> std::io::Result<()> {
+        match self.poll.poll(&mut self.events, timeout) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let tokens : Vec<(Token, bool, bool)> = self.events.iter()
+            .map(|event| (event.token(), event.is_readable() || event.is_read_closed(), event.is_writable()))
+            .collect();
+        for (token, readable, writable) in tokens {
+            if token == LISTENER {
+                self.accept()?;
+                continue;
+            }
+            let entry = match self.connections.remove(&token) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let entry = match entry {
+                Entry::Handshake{stream, request, peer_addr} => self.handshake(token, stream, request, peer_addr),
+                Entry::Open{socket, handler, writable: registered} => self.serve(token, socket, handler, registered, readable, writable),
+            };
+            if let Some(entry) = entry {
+                self.connections.insert(token, entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self) -> std::io::Result<()> {
+        loop {
+            let (mut stream, peer_addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                // e.g. the client reset the connection before it was accepted
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted || e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            self.poll.registry().register(&mut stream, token, Interest::READABLE)?;
+            self.connections.insert(token, Entry::Handshake{stream: stream, request: Vec::new(), peer_addr: peer_addr});
+        }
+    }
+
+    /// Reads the upgrade request and answers it once it is complete.
+    fn handshake(&mut self, token : Token, mut stream : TcpStream, mut request : Vec<u8>, peer_addr : SocketAddr) -> Option<Entry<H>> {
+        let limits = super::handshake_limits();
+        let mut buffer = [0; 1024];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => return None,
+                Ok(n) => request.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(_) => return None,
+            }
+            if request.len() > limits.get_max_head_size() {
+                return None;
+            }
+        }
+
+        if !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            return Some(Entry::Handshake{stream: stream, request: request, peer_addr: peer_addr});
+        }
+        // the client waits for the response before sending frames, so the request is all there is
+        let request = http::parse_request_with(&mut &request[..], &limits).ok()?;
+        let mut socket = super::upgrade_with_config(stream, &request, &self.config).ok()?;
+        socket.set_peer_addr(peer_addr);
+        socket.set_drain(true);
+
+        let mut handler = (self.new_handler)();
+        handler.on_open(&mut socket);
+        self.serve(token, socket, handler, false, false, false)
+    }
+
+    /// Reads and writes what the connection allows and updates its registration. Returns None
+    /// once the connection is closed.
+    fn serve(&mut self, token : Token, mut socket : Websocket<TcpStream>, mut handler : H, registered : bool, readable : bool, writable : bool) -> Option<Entry<H>> {
+        if writable && socket.wants_write() {
+            if let Err(e) = socket.flush_pending() {
+                return Self::fail(socket, handler, &e);
+            }
+        }
+
+        // events are edge-triggered, so the connection is read until it has no more data
+        while readable || socket.has_pending_messages() {
+            let messages = match socket.try_read() {
+                Ok(messages) => messages,
+                Err(Error::WouldBlock) => break,
+                Err(e) => return Self::fail(socket, handler, &e),
+            };
+            for message in messages {
+                if !socket.dispatch(&mut handler, message) {
+                    socket.flush_pending().ok();
+                    return None;
+                }
+            }
+            if socket.is_closed() || (socket.last_read_empty && !socket.has_pending_messages()) {
+                // failed by a protocol error or the peer went away without a close frame
+                handler.on_close(Some(1006), None);
+                return None;
+            }
+        }
+        if let Some(progress) = socket.incoming_progress().filter(|_| readable) {
+            handler.on_progress(&mut socket, progress);
+        }
+
+        // messages sent by the handler are written while the connection is writable
+        let wants_write = socket.wants_write();
+        if wants_write != registered {
+            let interest = if wants_write { Interest::READABLE | Interest::WRITABLE } else { Interest::READABLE };
+            if self.poll.registry().reregister(&mut socket.connection, token, interest).is_err() {
+                handler.on_close(Some(1006), None);
+                return None;
+            }
+        }
+        Some(Entry::Open{socket: socket, handler: handler, writable: wants_write})
+    }
+
+    fn fail(mut socket : Websocket<TcpStream>, mut handler : H, error : &Error) -> Option<Entry<H>> {
+        handler.on_error(&mut socket, error);
+        handler.on_close(Some(1006), None);
+        None
+    }
+}
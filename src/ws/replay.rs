@@ -0,0 +1,56 @@
+//! Replays the incoming bytes of a connection recorded with `Websocket::record_to`, to reproduce
+//! parsing failures without the peer.
+//!
+//! The recording is parsed by a server side websocket which also accepts unmasked frames, so
+//! recordings of both clients and servers can be replayed. To reproduce a server rejecting
+//! unmasked frames, call `set_accept_unmasked(false)` before replaying. Extensions negotiated by
+//! the recorded connection are not applied.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use super::{Error, Message, Websocket};
+
+/// A connection reading a recording. Everything written to it is discarded.
+pub struct Replay {
+    recording : File,
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        self.recording.read(buf)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A websocket reading the recording at `path`.
+pub fn from_file<P : AsRef<Path>>(path : P) -> std::io::Result<Websocket<Replay>> {
+    let mut socket = Websocket::from(Replay{recording: File::open(path)?});
+    socket.set_accept_unmasked(true);
+    Ok(socket)
+}
+
+impl Websocket<Replay> {
+    /// Reads the whole recording and returns its messages, or the error the parser failed with.
+    /// Messages after a close message aren't parsed. A recording ending without a close frame ends
+    /// with a close message with code 1006.
+    pub fn replay(&mut self) -> Result<Vec<Message>, Error> {
+        let mut messages = Vec::new();
+        loop {
+            messages.extend(self.read()?);
+            if self.last_read_empty || self.is_closed() {
+                return Ok(messages);
+            }
+        }
+    }
+}
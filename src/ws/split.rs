@@ -0,0 +1,267 @@
+//! Independent read and write halves of a websocket, so one thread can block on reads while
+//! another sends messages.
+//!
+//! Each half needs its own handle of the connection, see `TryClone`. Connections which can't be
+//! cloned, like TLS streams, are shared behind a mutex with `Websocket::shared`.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use super::frame::{FrameHeader, OPCODE_CLOSE};
+use super::{close_payload, Error, Message, State, Websocket};
+
+/// A connection with several handles to the same stream, which `Websocket::split` hands to
+/// its halves.
+pub trait TryClone : Sized {
+    /// Another handle, reading and writing the same stream.
+    fn try_clone(&self) -> std::io::Result<Self>;
+}
+
+/// A connection used by both halves of a split websocket in turns. Every read and write holds a
+/// lock, so a read waiting for data also blocks the writer: the underlying socket should have a
+/// read timeout.
+pub struct SharedConnection<C> {
+    inner : Arc<Mutex<C>>,
+}
+
+/// The reading half returned by `Websocket::split`. Pings are still answered by this half.
+pub struct WsReader<C : Read + Write = TcpStream> {
+    socket : Websocket<SplitStream<C>>,
+}
+
+/// The writing half returned by `Websocket::split`.
+pub struct WsWriter<C : Read + Write = TcpStream> {
+    socket : Websocket<SplitStream<C>>,
+}
+
+/// The connection of either half. Reads go to its own handle of the stream, writes to the shared one.
+struct SplitStream<C> {
+    stream : C,
+    shared : Arc<Mutex<Shared<C>>>,
+}
+
+struct Shared<C> {
+    stream : C,
+    // once a close frame is sent by either half, nothing else may follow it
+    close_sent : bool,
+}
+
+impl TryClone for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+#[cfg(unix)]
+impl TryClone for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+}
+
+impl<C> TryClone for SharedConnection<C> {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self{inner: self.inner.clone()})
+    }
+}
+
+impl<C> SharedConnection<C> {
+    pub fn new(connection : C) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(connection)),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, C> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<C : Read> Read for SharedConnection<C> {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        self.lock().read(buf)
+    }
+}
+
+impl<C : Write> Write for SharedConnection<C> {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+/// Whether `bytes`, starting at a frame boundary, contain a frame `matches` is true for.
+fn contains_frame<F : Fn(&FrameHeader) -> bool>(bytes : &[u8], matches : F) -> bool {
+    let mut rest = bytes;
+    while let Some((header, len)) = FrameHeader::parse(rest) {
+        if matches(&header) {
+            return true;
+        }
+        let end = header.payload_size().map_or(usize::MAX, |size| len.saturating_add(size));
+        if end >= rest.len() {
+            break;
+        }
+        rest = &rest[end..];
+    }
+    return false;
+}
+
+impl<C> SplitStream<C> {
+    fn close_sent(&self) -> bool {
+        self.shared.lock().unwrap_or_else(|e| e.into_inner()).close_sent
+    }
+}
+
+impl<C : Read> Read for SplitStream<C> {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<C : Write> Write for SplitStream<C> {
+    /// Writes the whole buffer while holding the lock, so frames of both halves never interleave.
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        let mut shared = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+        if shared.close_sent {
+            // the reader's automatic answers are dropped, messages of the writer fail
+            if contains_frame(buf, |header| !header.is_control()) {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            return Ok(buf.len());
+        }
+        shared.stream.write_all(buf)?;
+        if contains_frame(buf, |header| header.opcode == OPCODE_CLOSE) {
+            shared.close_sent = true;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.shared.lock().unwrap_or_else(|e| e.into_inner()).stream.flush()
+    }
+}
+
+/// Moves the socket into the closing state if the other half already sent a close frame.
+fn sync_state<C : Read + Write>(socket : &mut Websocket<SplitStream<C>>) {
+    if socket.state == State::Open && socket.connection.close_sent() {
+        socket.state = State::Closing;
+    }
+}
+
+impl<C : Read + Write> Websocket<C> {
+    /// Puts the connection behind a mutex, so the websocket can be split although the connection
+    /// can't be cloned, see `SharedConnection`.
+    pub fn shared(self) -> Websocket<SharedConnection<C>> {
+        self.map_connection(SharedConnection::new)
+    }
+}
+
+impl<C : Read + Write + TryClone> Websocket<C> {
+    /// Splits the websocket into a reader and a writer which can be used from different threads.
+    /// Both halves share the stream, which should be blocking. The context stays with the reader.
+    pub fn split(mut self) -> Result<(WsReader<C>, WsWriter<C>), Error> {
+        while self.flush_pending()? > 0 {}
+
+        let mut reading = Vec::new();
+        let mut writing = Vec::new();
+        for extension in std::mem::take(&mut self.extensions) {
+            let (read, write) = extension.split().ok_or(Error::WebsocketError("an extension doesn't support split"))?;
+            reading.push(read);
+            writing.push(write);
+        }
+        let shared = Arc::new(Mutex::new(Shared {
+            stream: self.connection.try_clone()?,
+            close_sent: self.state != State::Open,
+        }));
+        let write_stream = self.connection.try_clone()?;
+
+        // the writer gets every setting, the buffers and the context stay with the reader
+        let mut writer = self.with_settings(SplitStream{stream: write_stream, shared: shared.clone()});
+        writer.extensions = writing;
+        let mut reader = self.map_connection(|stream| SplitStream{stream: stream, shared: shared});
+        reader.extensions = reading;
+
+        Ok((WsReader{socket: reader}, WsWriter{socket: writer}))
+    }
+}
+
+impl<C : Read + Write> WsReader<C> {
+    pub fn is_closed(&self) -> bool { self.socket.is_closed() }
+
+    /// See `Websocket::context`.
+    pub fn context<T : std::any::Any>(&self) -> Option<&T> { self.socket.context() }
+
+    pub fn context_mut<T : std::any::Any>(&mut self) -> Option<&mut T> { self.socket.context_mut() }
+
+    pub fn set_context<T : std::any::Any + Send>(&mut self, context : T) { self.socket.set_context(context) }
+
+    /// See `Websocket::read`.
+    pub fn read(&mut self) -> Result<Vec<Message>, Error> {
+        sync_state(&mut self.socket);
+        self.socket.read()
+    }
+
+    /// See `Websocket::read_message`. Returns the close message once the peer answered a close
+    /// sent by the writer.
+    pub fn read_message(&mut self) -> Result<Message, Error> {
+        sync_state(&mut self.socket);
+        self.socket.read_message()
+    }
+}
+
+impl<C : Read + Write> WsWriter<C> {
+    /// See `Websocket::context`. The writer has its own context, independent of the reader's.
+    pub fn context<T : std::any::Any>(&self) -> Option<&T> { self.socket.context() }
+
+    pub fn context_mut<T : std::any::Any>(&mut self) -> Option<&mut T> { self.socket.context_mut() }
+
+    pub fn set_context<T : std::any::Any + Send>(&mut self, context : T) { self.socket.set_context(context) }
+
+    /// See `Websocket::send`. Close messages are sent like `close`.
+    pub fn send<M : Into<Message>>(&mut self, message : M) -> Result<(), Error> {
+        match message.into() {
+            Message::Close(code, reason) => self.close(code, reason.as_deref()),
+            message => {
+                sync_state(&mut self.socket);
+                self.socket.send(message)
+            },
+        }
+    }
+
+    pub fn send_text(&mut self, data : &str) -> Result<(), Error> {
+        sync_state(&mut self.socket);
+        self.socket.send_text(data)
+    }
+
+    pub fn send_bytes(&mut self, data : &[u8]) -> Result<(), Error> {
+        sync_state(&mut self.socket);
+        self.socket.send_bytes(data)
+    }
+
+    pub fn send_ping(&mut self, data : &[u8]) -> Result<(), Error> {
+        sync_state(&mut self.socket);
+        self.socket.send_ping(data)
+    }
+
+    pub fn send_pong(&mut self, data : &[u8]) -> Result<(), Error> {
+        sync_state(&mut self.socket);
+        self.socket.send_pong(data)
+    }
+
+    /// Sends a close frame without waiting for the answer, which is received by the reader.
+    pub fn close(&mut self, code : Option<u16>, reason : Option<&str>) -> Result<(), Error> {
+        sync_state(&mut self.socket);
+        if self.socket.state != State::Open {
+            return Ok(());
+        }
+        let payload = close_payload(code, reason)?;
+        self.socket.state = State::Closing;
+        self.socket.send_raw(0x8, &payload)?;
+        self.socket.connection.flush().map_err(|e| self.socket.write_failed(e))?;
+        Ok(())
+    }
+}
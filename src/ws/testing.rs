@@ -0,0 +1,209 @@
+//! An in-memory connection for testing code built on `Websocket` without real sockets.
+//!
+//! `MockConnection::pair` returns two connected endpoints, one for the websocket under test and
+//! one acting as the peer, e.g. writing frames with `frame::write_frame`. Both can be moved to
+//! different threads and cloned like sockets, e.g. for `Websocket::split`.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use super::TryClone;
+
+/// One direction of the pair.
+struct Pipe {
+    state : Mutex<PipeState>,
+    // notified whenever data is written or the writing end is closed
+    changed : Condvar,
+}
+
+struct PipeState {
+    data : VecDeque<u8>,
+    // the writing end was dropped or shut down, reads return end of file once the data is consumed
+    closed : bool,
+    // the most unread bytes, writes beyond return WouldBlock
+    capacity : Option<usize>,
+}
+
+/// Closes both directions once the last handle of an endpoint is dropped: the peer reads end of
+/// file and its writes fail with `BrokenPipe`.
+struct Endpoint {
+    incoming : Arc<Pipe>,
+    outgoing : Arc<Pipe>,
+}
+
+/// One endpoint of an in-memory duplex connection. Reads block like a socket until the peer
+/// writes or closes, unless it is nonblocking or a read timeout is set.
+pub struct MockConnection {
+    incoming : Arc<Pipe>,
+    outgoing : Arc<Pipe>,
+    // shared by the clones of the endpoint
+    endpoint : Arc<Endpoint>,
+    read_chunk : Option<usize>,
+    write_chunk : Option<usize>,
+    read_errors : VecDeque<ErrorKind>,
+    write_errors : VecDeque<ErrorKind>,
+    nonblocking : bool,
+    read_timeout : Option<Duration>,
+}
+
+impl Pipe {
+    fn new() -> Arc<Pipe> {
+        Arc::new(Pipe {
+            state: Mutex::new(PipeState{data: VecDeque::new(), closed: false, capacity: None}),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+impl MockConnection {
+    /// Two endpoints where everything written to one is read from the other.
+    pub fn pair() -> (MockConnection, MockConnection) {
+        let a = Pipe::new();
+        let b = Pipe::new();
+        let first = Endpoint{incoming: a.clone(), outgoing: b.clone()};
+        let second = Endpoint{incoming: b, outgoing: a};
+        (Self::new(Arc::new(first)), Self::new(Arc::new(second)))
+    }
+
+    fn new(endpoint : Arc<Endpoint>) -> Self {
+        Self {
+            incoming: endpoint.incoming.clone(),
+            outgoing: endpoint.outgoing.clone(),
+            endpoint: endpoint,
+            read_chunk: None,
+            write_chunk: None,
+            read_errors: VecDeque::new(),
+            write_errors: VecDeque::new(),
+            nonblocking: false,
+            read_timeout: None,
+        }
+    }
+
+    /// Limits every read to at most `bytes` bytes, to deliver frames in pieces.
+    pub fn set_read_chunk(&mut self, bytes : Option<usize>) {
+        self.read_chunk = bytes.map(|bytes| bytes.max(1));
+    }
+
+    /// Limits every write to at most `bytes` bytes, like a socket with a full send buffer.
+    pub fn set_write_chunk(&mut self, bytes : Option<usize>) {
+        self.write_chunk = bytes.map(|bytes| bytes.max(1));
+    }
+
+    /// Limits the written bytes the peer didn't read yet, for this endpoint and its clones. Writes
+    /// to a full buffer return `WouldBlock`, like a socket with a send timeout whose peer stopped
+    /// reading.
+    pub fn set_send_buffer(&mut self, bytes : Option<usize>) {
+        self.outgoing.lock().capacity = bytes;
+    }
+
+    /// Makes the next read fail with `kind`. Several errors are returned in order.
+    pub fn inject_read_error(&mut self, kind : ErrorKind) {
+        self.read_errors.push_back(kind);
+    }
+
+    /// Makes the next write fail with `kind`. Several errors are returned in order.
+    pub fn inject_write_error(&mut self, kind : ErrorKind) {
+        self.write_errors.push_back(kind);
+    }
+
+    /// Reads without available data return `WouldBlock` instead of waiting.
+    pub fn set_nonblocking(&mut self, nonblocking : bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// Reads waiting longer than `timeout` return `WouldBlock`, like `TcpStream::set_read_timeout`.
+    pub fn set_read_timeout(&mut self, timeout : Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// The number of written bytes the peer didn't read yet.
+    pub fn unread_by_peer(&self) -> usize {
+        self.outgoing.lock().data.len()
+    }
+
+    /// Closes the writing direction, the peer reads end of file once it consumed the data.
+    pub fn shutdown(&mut self) {
+        self.outgoing.close();
+    }
+}
+
+impl Read for MockConnection {
+    fn read(&mut self, buf : &mut [u8]) -> std::io::Result<usize> {
+        if let Some(kind) = self.read_errors.pop_front() {
+            return Err(Error::from(kind));
+        }
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.incoming.lock();
+        while state.data.len() == 0 && !state.closed && buf.len() > 0 {
+            if self.nonblocking {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(ErrorKind::WouldBlock.into());
+                    }
+                    self.incoming.changed.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0
+                },
+                None => self.incoming.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+
+        let n = buf.len().min(state.data.len()).min(self.read_chunk.unwrap_or(usize::MAX));
+        for (byte, value) in buf.iter_mut().zip(state.data.drain(..n)) {
+            *byte = value;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MockConnection {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        if let Some(kind) = self.write_errors.pop_front() {
+            return Err(Error::from(kind));
+        }
+        let mut state = self.outgoing.lock();
+        if state.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        let free = state.capacity.map(|capacity| capacity.saturating_sub(state.data.len()));
+        if free == Some(0) && buf.len() > 0 {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(self.write_chunk.unwrap_or(usize::MAX)).min(free.unwrap_or(usize::MAX));
+        state.data.extend(&buf[..n]);
+        drop(state);
+        self.outgoing.changed.notify_all();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TryClone for MockConnection {
+    /// Another handle of the endpoint. Chunk limits, injected errors and timeouts aren't copied.
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self::new(self.endpoint.clone()))
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.outgoing.close();
+        self.incoming.close();
+    }
+}
@@ -0,0 +1,64 @@
+use super::{Error, Websocket};
+
+/// Sends a message as a sequence of frames. Created by `Websocket::start_text` and
+/// `Websocket::start_binary`. Dropping the writer finishes the message.
+pub struct MessageWriter<'a, Connection : std::io::Read + std::io::Write> {
+    socket : &'a mut Websocket<Connection>,
+    opcode : u8,
+    started : bool,
+    finished : bool,
+}
+
+impl<'a, Connection : std::io::Read + std::io::Write> MessageWriter<'a, Connection> {
+    pub(super) fn new(socket : &'a mut Websocket<Connection>, opcode : u8) -> Self {
+        Self {
+            socket: socket,
+            opcode: opcode,
+            started: false,
+            finished: false,
+        }
+    }
+
+    fn write_fragment(&mut self, fin : bool, data : &[u8]) -> Result<(), Error> {
+        // only the first frame carries the opcode, all following are continuation frames
+        let opcode = if self.started { 0x0 } else { self.opcode };
+        self.socket.write_frame(fin, opcode, data)?;
+        self.started = true;
+        Ok(())
+    }
+
+    /// Sends the final frame of the message.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.finish_message()
+    }
+
+    fn finish_message(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let opcode = if self.started { 0x0 } else { self.opcode };
+        self.socket.write_frame(true, opcode, &[])
+    }
+}
+
+impl<Connection : std::io::Read + std::io::Write> std::io::Write for MessageWriter<'_, Connection> {
+    fn write(&mut self, buf : &[u8]) -> std::io::Result<usize> {
+        if buf.len() == 0 {
+            return Ok(0);
+        }
+        self.write_fragment(false, buf).map_err(std::io::Error::from)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.socket.flush_pending()?;
+        self.socket.connection.flush().map_err(|e| self.socket.write_failed(e).into())
+    }
+}
+
+impl<Connection : std::io::Read + std::io::Write> Drop for MessageWriter<'_, Connection> {
+    fn drop(&mut self) {
+        self.finish_message().ok();
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+
+use websocket::ws::{self, frame, testing::MockConnection, State};
+
+/// Sends a masked frame like a client.
+fn send(peer: &mut MockConnection, opcode: u8, payload: &[u8]) {
+    let mut header = frame::FrameHeader::new(true, opcode, payload.len() as u64);
+    header.mask = Some([0x12, 0x34, 0x56, 0x78]);
+    frame::write_frame(peer, &frame::Frame { header: header, payload: payload.to_vec() }).unwrap();
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+#[test]
+fn answers_the_close_of_the_peer() {
+    let (server, mut peer) = MockConnection::pair();
+    let mut socket = ws::Websocket::from(server);
+
+    send(&mut peer, frame::OPCODE_CLOSE, &close_payload(1000, "bye"));
+    assert_eq!(socket.read_message().unwrap(), ws::Message::Close(Some(1000), Some("bye".into())));
+    assert_eq!(socket.state(), State::Closed);
+
+    // the code is echoed, nothing can be sent afterwards
+    let answer = frame::read_frame(&mut peer).unwrap();
+    assert_eq!(answer.header.opcode, frame::OPCODE_CLOSE);
+    assert_eq!(answer.payload[..2], 1000u16.to_be_bytes());
+    assert!(socket.send_text("too late").is_err());
+    drop(socket);
+    assert!(frame::read_frame(&mut peer).is_err());
+}
+
+#[test]
+fn close_waits_for_the_answer() {
+    let (server, mut peer) = MockConnection::pair();
+    let mut socket = ws::Websocket::from(server);
+
+    let answering = std::thread::spawn(move || {
+        let close = frame::read_frame(&mut peer).unwrap();
+        assert_eq!(close.payload, close_payload(1001, "going away"));
+        // messages sent before the answer are discarded
+        send(&mut peer, frame::OPCODE_TEXT, b"late");
+        send(&mut peer, frame::OPCODE_CLOSE, &close_payload(1001, ""));
+        peer
+    });
+    assert_eq!(socket.state(), State::Open);
+    socket.close(Some(1001), Some("going away")).unwrap();
+    assert_eq!(socket.state(), State::Closed);
+    assert_eq!(socket.read_message().unwrap(), ws::Message::Close(Some(1001), None));
+    assert!(socket.read_message().is_err());
+
+    // the close frame is sent once
+    socket.close(Some(1000), None).unwrap();
+    let mut peer = answering.join().unwrap();
+    drop(socket);
+    assert!(frame::read_frame(&mut peer).is_err());
+}
+
+#[test]
+fn close_times_out() {
+    let (mut server, mut peer) = MockConnection::pair();
+    server.set_read_timeout(Some(Duration::from_millis(10)));
+    let mut socket = ws::Websocket::from(server);
+    socket.set_close_timeout(Duration::from_millis(100));
+
+    // the peer never answers
+    let start = Instant::now();
+    assert!(matches!(socket.close(None, None), Err(ws::Error::WebsocketError("close handshake timed out"))));
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(socket.state(), State::Closed);
+    assert_eq!(socket.read_message().unwrap(), ws::Message::Close(Some(1006), Some("close handshake timed out".into())));
+    assert!(frame::read_frame(&mut peer).unwrap().payload.is_empty());
+}
+
+#[test]
+fn connection_lost_without_close_frame() {
+    let (server, peer) = MockConnection::pair();
+    let mut socket = ws::Websocket::from(server);
+
+    drop(peer);
+    assert_eq!(socket.read_message().unwrap(), ws::Message::Close(Some(1006), None));
+    assert_eq!(socket.state(), State::Closed);
+}
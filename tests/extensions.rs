@@ -0,0 +1,31 @@
+use websocket::ws::extension::parse_offers;
+
+#[test]
+fn offers_with_parameters() {
+    let offers = parse_offers("permessage-deflate; client_max_window_bits, permessage-deflate;server_max_window_bits=10 , x-custom").unwrap();
+    assert_eq!(offers.len(), 3);
+    assert_eq!(offers[0].name, "permessage-deflate");
+    assert_eq!(offers[0].param("client_max_window_bits"), Some(None));
+    assert_eq!(offers[1].param("server_max_window_bits"), Some(Some("10")));
+    assert_eq!(offers[1].param("client_max_window_bits"), None);
+    assert_eq!(offers[2].name, "x-custom");
+    assert!(offers[2].params.is_empty());
+}
+
+#[test]
+fn quoted_values_are_unescaped() {
+    // separators inside quotes don't split the header
+    let offers = parse_offers("x-a; v=\"12\", x-b; v=\"1\\2\"").unwrap();
+    assert_eq!(offers.len(), 2);
+    assert_eq!(offers[0].param("v"), Some(Some("12")));
+    assert_eq!(offers[1].param("v"), Some(Some("12")));
+    assert!(parse_offers("x-a; v=\"a,b\"").is_err());
+}
+
+#[test]
+fn malformed_headers_are_rejected() {
+    for header in ["x-a;", "x-a; =1", "x-a; v=", "x-a; v=\"1", "x-a x-b", "\"x-a\"", "x-a; v=\"\""] {
+        assert!(parse_offers(header).is_err(), "{header}");
+    }
+    assert_eq!(parse_offers(" , x-a ,, ").unwrap().len(), 1);
+}
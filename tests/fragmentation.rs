@@ -0,0 +1,282 @@
+use websocket::ws;
+
+/// In-memory connection delivering `input` in chunks of `chunk` bytes and recording all writes.
+struct Connection {
+    input: Vec<u8>,
+    position: usize,
+    chunk: usize,
+    output: Vec<u8>,
+    writes: usize,
+}
+
+impl std::io::Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.chunk.min(buf.len()).min(self.input.len() - self.position);
+        buf[..n].copy_from_slice(&self.input[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl std::io::Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        self.writes += 1;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Encodes a masked client frame.
+fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut bytes = vec![((fin as u8) << 7) | opcode];
+    assert!(payload.len() < 126);
+    bytes.push(0x80 | payload.len() as u8);
+    bytes.extend_from_slice(&mask);
+    for (i, byte) in payload.iter().enumerate() {
+        bytes.push(byte ^ mask[i % 4]);
+    }
+    bytes
+}
+
+fn socket(frames: &[Vec<u8>], chunk: usize) -> ws::Websocket<Connection> {
+    ws::Websocket::from(Connection {
+        input: frames.concat(),
+        position: 0,
+        chunk: chunk,
+        output: Vec::new(),
+        writes: 0,
+    })
+}
+
+fn read_all(socket: &mut ws::Websocket<Connection>) -> Vec<ws::Message> {
+    let mut messages = Vec::new();
+    for _ in 0..1000 {
+        messages.extend(socket.read().unwrap());
+        if socket.is_closed() {
+            break;
+        }
+    }
+    messages
+}
+
+#[test]
+fn ping_between_fragments() {
+    for chunk in [1, 3, 1024] {
+        let mut socket = socket(&[
+            frame(false, 0x1, b"Hello, "),
+            frame(true, 0x9, b"ping"),
+            frame(false, 0x0, b"fragmented "),
+            frame(true, 0x0, b"world"),
+            frame(true, 0x8, &1000u16.to_be_bytes()),
+        ], chunk);
+
+        let messages = read_all(&mut socket);
+        assert_eq!(messages, vec![
+            ws::Message::Ping(b"ping".to_vec()),
+            ws::Message::Text("Hello, fragmented world".into()),
+            ws::Message::Close(Some(1000), None),
+        ]);
+    }
+}
+
+#[test]
+fn binary_message_around_ping() {
+    let mut socket = socket(&[
+        frame(false, 0x2, &[1, 2]),
+        frame(true, 0x9, b"abc"),
+        frame(true, 0x0, &[3]),
+    ], 1024);
+
+    let messages = socket.read().unwrap();
+    assert_eq!(messages, vec![
+        ws::Message::Ping(b"abc".to_vec()),
+        ws::Message::from(vec![1, 2, 3]),
+    ]);
+}
+
+#[test]
+fn several_control_frames_between_fragments() {
+    let mut socket = socket(&[
+        frame(false, 0x1, b"a"),
+        frame(true, 0xA, b"1"),
+        frame(false, 0x0, b"b"),
+        frame(true, 0x9, b"2"),
+        frame(true, 0xA, b"3"),
+        frame(true, 0x0, b"c"),
+        frame(true, 0x1, b"next"),
+    ], 2);
+
+    let mut messages = Vec::new();
+    while messages.len() < 5 {
+        messages.extend(socket.read().unwrap());
+    }
+    assert_eq!(messages, vec![
+        ws::Message::Pong(b"1".to_vec()),
+        ws::Message::Ping(b"2".to_vec()),
+        ws::Message::Pong(b"3".to_vec()),
+        ws::Message::Text("abc".into()),
+        ws::Message::Text("next".into()),
+    ]);
+}
+
+#[test]
+fn close_during_fragmentation() {
+    let mut socket = socket(&[
+        frame(false, 0x1, b"never "),
+        frame(true, 0x8, &1001u16.to_be_bytes()),
+        frame(true, 0x0, b"finished"),
+    ], 1024);
+
+    let messages = read_all(&mut socket);
+    assert_eq!(messages, vec![ws::Message::Close(Some(1001), None)]);
+    assert!(socket.is_closed());
+}
+
+#[test]
+fn chunks_with_interleaved_ping() {
+    let mut socket = socket(&[
+        frame(false, 0x2, &[1]),
+        frame(true, 0x9, b""),
+        frame(true, 0x0, &[2]),
+    ], 1024);
+
+    let chunks = socket.read_chunks().unwrap();
+    assert_eq!(chunks.len(), 3);
+    assert!(matches!(&chunks[0], ws::Chunk::Data{kind: ws::MessageKind::Binary, payload, fin: false} if payload == &[1]));
+    assert!(matches!(&chunks[1], ws::Chunk::Control(ws::Message::Ping(payload)) if payload.is_empty()));
+    assert!(matches!(&chunks[2], ws::Chunk::Data{kind: ws::MessageKind::Binary, payload, fin: true} if payload == &[2]));
+}
+
+#[test]
+fn continuation_without_start_is_rejected() {
+    let mut socket = socket(&[frame(true, 0x0, b"orphan")], 1024);
+    assert!(socket.read().is_err());
+}
+
+#[test]
+fn close_reason_must_be_utf8() {
+    let mut payload = 1000u16.to_be_bytes().to_vec();
+    payload.extend_from_slice(&[0xce, 0xba, 0xe1, 0xbd]);
+    let mut socket = socket(&[frame(true, 0x8, &payload)], 1024);
+    assert!(socket.read().is_err());
+    assert!(socket.is_closed());
+
+    // failed with 1007, the server's frames are unmasked
+    let (connection, _) = socket.into_inner();
+    assert_eq!(connection.output[0], 0x88);
+    assert_eq!(&connection.output[2..4], &1007u16.to_be_bytes());
+}
+
+#[test]
+fn end_of_connection_is_an_abnormal_close() {
+    let mut socket = socket(&[frame(false, 0x1, b"cut ")], 1024);
+    let messages = read_all(&mut socket);
+    assert_eq!(messages, vec![ws::Message::Close(Some(1006), None)]);
+    assert!(socket.is_closed());
+    // the close was not sent, there is no connection left to send it on
+    assert!(socket.into_inner().0.output.is_empty());
+}
+
+#[test]
+fn small_read_buffer() {
+    let mut socket = socket(&[
+        frame(false, 0x1, b"split "),
+        frame(true, 0x0, b"reads"),
+        frame(true, 0x8, &1000u16.to_be_bytes()),
+    ], 1024);
+    socket.set_read_buffer_size(3);
+
+    let messages = read_all(&mut socket);
+    assert_eq!(messages, vec![
+        ws::Message::Text("split reads".into()),
+        ws::Message::Close(Some(1000), None),
+    ]);
+}
+
+#[test]
+fn pongs_of_one_read_are_written_together() {
+    let mut socket = socket(&[
+        frame(true, 0x9, b"1"),
+        frame(true, 0x9, b"2"),
+        frame(true, 0x9, b"3"),
+    ], 1024);
+
+    assert_eq!(socket.read().unwrap().len(), 3);
+    let (connection, _) = socket.into_inner();
+    assert_eq!(connection.writes, 1);
+    assert_eq!(connection.output, [0x8a, 1, b'1', 0x8a, 1, b'2', 0x8a, 1, b'3']);
+}
+
+#[test]
+fn close_is_sent_once() {
+    let mut socket = socket(&[frame(true, 0x8, &1000u16.to_be_bytes())], 1024);
+    assert_eq!(socket.state(), ws::State::Open);
+    socket.close(Some(1000), None).unwrap();
+    assert_eq!(socket.state(), ws::State::Closed);
+    socket.close(Some(1000), None).unwrap();
+
+    let (connection, _) = socket.into_inner();
+    assert_eq!(connection.output, [0x88, 2, 0x03, 0xe8]);
+}
+
+#[test]
+fn compressed_message_is_limited_while_inflating() {
+    // 16 MiB of zeros deflate to a few KiB
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+    std::io::Write::write_all(&mut encoder, &vec![0; 16 << 20]).unwrap();
+    let compressed = encoder.finish().unwrap();
+    assert!(compressed.len() < 65536);
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    // a text frame with RSV1 set, marking it as compressed
+    let mut input = vec![0xc1, 0x80 | 126];
+    input.extend_from_slice(&(compressed.len() as u16).to_be_bytes());
+    input.extend_from_slice(&mask);
+    input.extend(compressed.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+
+    let request = "GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n";
+    let request = websocket::http::parse_request(&mut request.as_bytes()).unwrap();
+    let connection = Connection { input: input, position: 0, chunk: 1 << 20, output: Vec::new(), writes: 0 };
+    let mut socket = ws::upgrade(connection, &request).unwrap();
+    socket.set_max_message_size(1 << 20);
+    assert!(socket.read().is_err());
+    assert!(socket.is_closed());
+
+    let (connection, _) = socket.into_inner();
+    let head_len = connection.output.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+    let close = &connection.output[head_len..];
+    assert_eq!(close[0], 0x88);
+    assert_eq!(&close[2..4], &1009u16.to_be_bytes());
+}
+
+#[test]
+fn traced_frames_go_to_the_logger() {
+    let lines = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut socket = socket(&[frame(true, 0x9, b"ping")], 1024);
+    socket.set_trace(Some(2));
+    let logged = lines.clone();
+    socket.set_logger(move |line| logged.lock().unwrap().push(String::from(line)));
+    assert_eq!(socket.read().unwrap(), vec![ws::Message::Ping(b"ping".to_vec())]);
+
+    let lines = lines.lock().unwrap();
+    assert_eq!(*lines, vec![
+        String::from("ws <- fin=1 rsv=000 opcode=0x9 len=4 mask=12345678 | 70 69 ..."),
+        String::from("ws -> fin=1 rsv=000 opcode=0xa len=4 | 70 69 ..."),
+    ]);
+}
+
+#[test]
+fn tolerated_violations_go_to_the_logger() {
+    let lines = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    // a server must not receive unmasked frames
+    let mut socket = socket(&[vec![0x81, 2, b'h', b'i']], 1024);
+    socket.set_conformance(ws::Conformance::Lenient);
+    let logged = lines.clone();
+    socket.set_logger(move |line| logged.lock().unwrap().push(String::from(line)));
+    assert_eq!(socket.read().unwrap(), vec![ws::Message::Text("hi".into())]);
+    assert_eq!(*lines.lock().unwrap(), vec![String::from("ws: tolerating protocol violation: received unmasked frame")]);
+}
@@ -0,0 +1,146 @@
+use std::io::Read;
+use std::time::Duration;
+
+use websocket::http;
+use websocket::ws::{self, frame, testing::MockConnection, Conformance, State};
+
+/// Sends a frame like a client, masked unless `mask` is None.
+fn send_frame(peer: &mut MockConnection, fin: bool, rsv: u8, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) {
+    let mut header = frame::FrameHeader::new(fin, opcode, payload.len() as u64);
+    header.rsv = rsv;
+    header.mask = mask;
+    frame::write_frame(peer, &frame::Frame { header: header, payload: payload.to_vec() }).unwrap();
+}
+
+const MASK: Option<[u8; 4]> = Some([0x12, 0x34, 0x56, 0x78]);
+
+/// Reads the frame the websocket sent when it failed the connection and returns its close code.
+fn close_code(peer: &mut MockConnection) -> u16 {
+    let close = frame::read_frame(peer).unwrap();
+    assert_eq!(close.header.opcode, frame::OPCODE_CLOSE);
+    u16::from_be_bytes([close.payload[0], close.payload[1]])
+}
+
+/// Expects the websocket to fail the connection with 1002 on the next read.
+fn assert_protocol_error(socket: &mut ws::Websocket<MockConnection>, peer: &mut MockConnection, reason: &str) {
+    match socket.read_message() {
+        Err(ws::Error::WebsocketError(e)) => assert_eq!(e, reason),
+        other => panic!("{other:?}"),
+    }
+    assert_eq!(socket.state(), State::Closed);
+    assert_eq!(close_code(peer), 1002);
+}
+
+#[test]
+fn unmasked_client_frames() {
+    let (server, mut peer) = MockConnection::pair();
+    let mut socket = ws::Websocket::from(server);
+    send_frame(&mut peer, true, 0, frame::OPCODE_TEXT, b"plain", None);
+    assert_protocol_error(&mut socket, &mut peer, "received unmasked frame");
+
+    // tolerated on request
+    for lenient in [false, true] {
+        let (server, mut peer) = MockConnection::pair();
+        let mut socket = ws::Websocket::from(server);
+        if lenient {
+            socket.set_conformance(Conformance::Lenient);
+        }else{
+            socket.set_accept_unmasked(true);
+        }
+        send_frame(&mut peer, true, 0, frame::OPCODE_TEXT, b"plain", None);
+        assert_eq!(socket.read_message().unwrap(), ws::Message::Text("plain".into()));
+    }
+}
+
+#[test]
+fn invalid_control_frames() {
+    let (server, mut peer) = MockConnection::pair();
+    let mut socket = ws::Websocket::from(server);
+    send_frame(&mut peer, true, 0, frame::OPCODE_PING, &[0; 126], MASK);
+    assert_protocol_error(&mut socket, &mut peer, "control frame payload exceeds 125 bytes");
+
+    let (server, mut peer) = MockConnection::pair();
+    let mut socket = ws::Websocket::from(server);
+    send_frame(&mut peer, false, 0, frame::OPCODE_PING, b"half", MASK);
+    assert_protocol_error(&mut socket, &mut peer, "fragmented control frame");
+
+    // 125 bytes are allowed, the pong echoes them
+    let (server, mut peer) = MockConnection::pair();
+    let mut socket = ws::Websocket::from(server);
+    socket.set_read_buffer_size(256);
+    send_frame(&mut peer, true, 0, frame::OPCODE_PING, &[7; 125], MASK);
+    assert_eq!(socket.read().unwrap(), vec![ws::Message::Ping(vec![7; 125])]);
+    let pong = frame::read_frame(&mut peer).unwrap();
+    assert_eq!((pong.header.opcode, pong.payload), (frame::OPCODE_PONG, vec![7; 125]));
+}
+
+#[test]
+fn reserved_bits_without_extension() {
+    let (server, mut peer) = MockConnection::pair();
+    let mut socket = ws::Websocket::from(server);
+    send_frame(&mut peer, true, 0b100, frame::OPCODE_TEXT, b"compressed?", MASK);
+    assert_protocol_error(&mut socket, &mut peer, "reserved bits set without a negotiated extension");
+
+    let (server, mut peer) = MockConnection::pair();
+    let mut socket = ws::Websocket::from(server);
+    socket.set_conformance(Conformance::Lenient);
+    send_frame(&mut peer, true, 0b001, frame::OPCODE_TEXT, b"text", MASK);
+    assert_eq!(socket.read_message().unwrap(), ws::Message::Text("text".into()));
+}
+
+#[test]
+fn peers_which_stop_reading() {
+    // the peer never reads, nothing can be written
+    let (mut server, mut peer) = MockConnection::pair();
+    server.set_send_buffer(Some(0));
+    let mut socket = ws::Websocket::from(server);
+    socket.set_write_timeout(Some(Duration::from_millis(50)));
+    socket.send_text("queued").unwrap();
+    assert_eq!(socket.state(), State::Open);
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(matches!(socket.flush_pending(), Err(ws::Error::WebsocketError("peer is not reading"))));
+    assert_eq!(socket.state(), State::Closed);
+
+    // nothing got through
+    drop(socket);
+    assert!(frame::read_frame(&mut peer).is_err());
+
+    // more unsent data than allowed fails at once
+    let (mut server, _peer) = MockConnection::pair();
+    server.set_send_buffer(Some(0));
+    let mut socket = ws::Websocket::from(server);
+    socket.set_max_outgoing(10);
+    socket.send_bytes(&[0; 8]).unwrap();
+    assert!(matches!(socket.send_bytes(&[0; 8]), Err(ws::Error::WebsocketError("peer is not reading"))));
+    assert_eq!(socket.state(), State::Closed);
+}
+
+/// Upgrades `request` with `config` and returns the result and everything written to the peer.
+fn upgrade(request: &str, config: &ws::Config) -> (Result<(), ws::UpgradeError>, String) {
+    let (server, mut peer) = MockConnection::pair();
+    let request = http::parse_request(&mut request.as_bytes()).unwrap();
+    let result = ws::upgrade_with_config(server, &request, config).map(drop);
+    let mut response = String::new();
+    peer.read_to_string(&mut response).unwrap();
+    (result, response)
+}
+
+const REQUEST: &str = "GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+
+#[test]
+fn rejected_upgrades() {
+    let (result, response) = upgrade(&format!("{REQUEST}Sec-WebSocket-Version: 8\r\n\r\n"), &ws::Config::new());
+    assert_eq!(result, Err(ws::UpgradeError::UnsupportedVersion));
+    assert!(response.starts_with("HTTP/1.1 426 "));
+    assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+
+    let config = ws::Config::new().allowed_origins(&["https://example.com"]);
+    let (result, response) = upgrade(&format!("{REQUEST}Sec-WebSocket-Version: 13\r\nOrigin: https://evil.example\r\n\r\n"), &config);
+    assert_eq!(result, Err(ws::UpgradeError::OriginNotAllowed));
+    assert!(response.starts_with("HTTP/1.1 403 "));
+
+    let (result, response) = upgrade(&format!("{REQUEST}Sec-WebSocket-Version: 13\r\nOrigin: https://example.com\r\n\r\n"), &config);
+    assert_eq!(result, Ok(()));
+    assert!(response.starts_with("HTTP/1.1 101 "));
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+}
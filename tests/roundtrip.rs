@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use proptest::prelude::*;
+use websocket::{http, ws};
+
+/// Writes into a buffer shared with the test.
+#[derive(Clone, Default)]
+struct Recorder {
+    written: Rc<RefCell<Vec<u8>>>,
+}
+
+impl std::io::Read for Recorder {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl std::io::Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.written.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records everything written. Reads answer the recorded handshake request by upgrading it with
+/// the server side of the crate, so a client can be set up without a server.
+struct Loopback {
+    output: Recorder,
+    response: Vec<u8>,
+    position: usize,
+}
+
+impl std::io::Read for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.response.is_empty() {
+            let request = self.output.written.take();
+            let request = http::parse_request(&mut &request[..]).unwrap();
+            let server = Recorder::default();
+            ws::upgrade(server.clone(), &request).unwrap();
+            self.response = server.written.take();
+        }
+        let n = buf.len().min(self.response.len() - self.position);
+        buf[..n].copy_from_slice(&self.response[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl std::io::Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Delivers the input in chunks of `chunk` bytes and discards all writes.
+struct Chunked {
+    input: Vec<u8>,
+    position: usize,
+    chunk: usize,
+}
+
+impl std::io::Read for Chunked {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.chunk.min(buf.len()).min(self.input.len() - self.position);
+        buf[..n].copy_from_slice(&self.input[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl std::io::Write for Chunked {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A connection whose peer went away: reads end and writes fail.
+struct Broken;
+
+impl std::io::Read for Broken {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl std::io::Write for Broken {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A websocket whose frames end up in the returned buffer. Clients mask their frames, servers don't.
+fn sender(masked: bool) -> (ws::Websocket<Loopback>, Rc<RefCell<Vec<u8>>>) {
+    let output = Recorder::default();
+    let written = output.written.clone();
+    let connection = Loopback { output: output, response: Vec::new(), position: 0 };
+    let socket = if masked {
+        let socket = ws::client_handshake(connection, "localhost", "/").unwrap();
+        written.borrow_mut().clear();
+        socket
+    } else {
+        ws::Websocket::from(connection)
+    };
+    (socket, written)
+}
+
+/// Reads `bytes` in chunks of `chunk` bytes until `count` messages arrived.
+fn receive(bytes: Vec<u8>, chunk: usize, count: usize) -> Vec<ws::Message> {
+    let mut socket = ws::Websocket::from(Chunked { input: bytes, position: 0, chunk: chunk });
+    // frames sent by the server side are unmasked
+    socket.set_accept_unmasked(true);
+    let mut messages = Vec::new();
+    while messages.len() < count {
+        let read = socket.read().unwrap();
+        assert!(read.len() > 0 || socket.partial_read_bytes() > 0, "incomplete message");
+        messages.extend(read);
+    }
+    messages
+}
+
+/// Payloads of every length class: 7 bit, 16 bit and 64 bit lengths.
+fn payload() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        prop::collection::vec(any::<u8>(), 0..126),
+        prop::collection::vec(any::<u8>(), 126..=65535),
+        prop::collection::vec(any::<u8>(), 65536..70000),
+    ]
+}
+
+fn message() -> impl Strategy<Value = ws::Message> {
+    prop_oneof![
+        payload().prop_map(|bytes| ws::Message::from(String::from_utf8_lossy(&bytes).into_owned())),
+        payload().prop_map(ws::Message::from),
+    ]
+}
+
+/// Splits `payload` at the given points, which are taken modulo its length.
+fn fragments<'a>(payload: &'a [u8], splits: &[usize]) -> Vec<&'a [u8]> {
+    let mut points: Vec<usize> = splits.iter().map(|split| split % (payload.len() + 1)).collect();
+    points.sort();
+    let mut fragments = Vec::new();
+    let mut start = 0;
+    for point in points {
+        fragments.push(&payload[start..point]);
+        start = point;
+    }
+    fragments.push(&payload[start..]);
+    fragments
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn send_roundtrip(messages in prop::collection::vec(message(), 1..4), masked: bool, chunk in prop_oneof![Just(1usize), 1usize..4096]) {
+        let (mut socket, written) = sender(masked);
+        for message in &messages {
+            socket.send(message.clone()).unwrap();
+        }
+        let bytes = written.take();
+        prop_assert_eq!(bytes[1] >> 7 == 1, masked);
+        prop_assert_eq!(receive(bytes, chunk, messages.len()), messages);
+    }
+
+    #[test]
+    fn fragmented_roundtrip(message in message(), splits in prop::collection::vec(any::<usize>(), 0..8), masked: bool, chunk in prop_oneof![Just(1usize), 1usize..4096]) {
+        let (mut socket, written) = sender(masked);
+        let (mut writer, payload) = match &message {
+            ws::Message::Text(text) => (socket.start_text().unwrap(), str::as_bytes(text)),
+            ws::Message::Binary(data) => (socket.start_binary().unwrap(), &data[..]),
+            _ => unreachable!(),
+        };
+        for fragment in fragments(payload, &splits) {
+            writer.write_all(fragment).unwrap();
+        }
+        writer.finish().unwrap();
+        let bytes = written.take();
+        prop_assert_eq!(receive(bytes, chunk, 1), vec![message]);
+    }
+}
+
+#[test]
+fn failed_write_closes_the_websocket() {
+    let mut socket = ws::Websocket::from(Broken);
+    assert!(matches!(socket.send("lost"), Err(ws::Error::ConnectionClosed(e)) if e.kind() == std::io::ErrorKind::BrokenPipe));
+    assert!(socket.is_closed());
+    assert_eq!(socket.pending_write_bytes(), 0);
+    // nothing is written into the broken connection anymore
+    assert!(matches!(socket.send("again"), Err(ws::Error::WebsocketError(_))));
+}
@@ -0,0 +1,101 @@
+use websocket::ws::{self, frame, testing::MockConnection};
+
+/// Sends a masked frame like a client.
+fn send(peer: &mut MockConnection, opcode: u8, payload: &[u8]) {
+    let mut header = frame::FrameHeader::new(true, opcode, payload.len() as u64);
+    header.mask = Some([0x12, 0x34, 0x56, 0x78]);
+    frame::write_frame(peer, &frame::Frame { header: header, payload: payload.to_vec() }).unwrap();
+}
+
+#[test]
+fn halves_of_cloneable_connections() {
+    let (server, mut peer) = MockConnection::pair();
+    let (mut reader, mut writer) = ws::Websocket::from(server).split().unwrap();
+
+    // the reader blocks in another thread while the writer sends
+    let receiving = std::thread::spawn(move || reader.read_message().map(|message| (message, reader)));
+    writer.send_text("from the writer").unwrap();
+    assert_eq!(frame::read_frame(&mut peer).unwrap().payload, b"from the writer");
+    send(&mut peer, frame::OPCODE_TEXT, b"from the peer");
+    let (message, _reader) = receiving.join().unwrap().unwrap();
+    assert_eq!(message, ws::Message::Text("from the peer".into()));
+}
+
+#[test]
+fn halves_of_shared_connections() {
+    // like a TLS stream, the connection can only be shared; the read timeout lets the writer in
+    let (mut server, mut peer) = MockConnection::pair();
+    server.set_read_timeout(Some(std::time::Duration::from_millis(10)));
+    let (mut reader, mut writer) = ws::Websocket::from(server).shared().split().unwrap();
+
+    let receiving = std::thread::spawn(move || loop {
+        match reader.read() {
+            Ok(messages) if messages.len() > 0 => return messages,
+            Ok(_) => {},
+            Err(e) => panic!("{e}"),
+        }
+    });
+    writer.send_bytes(&[1, 2, 3]).unwrap();
+    assert_eq!(frame::read_frame(&mut peer).unwrap().payload, [1, 2, 3]);
+    send(&mut peer, frame::OPCODE_TEXT, b"shared");
+    assert_eq!(receiving.join().unwrap(), vec![ws::Message::Text("shared".into())]);
+}
+
+#[test]
+fn writes_after_the_close_fail() {
+    let (server, mut peer) = MockConnection::pair();
+    let (mut reader, mut writer) = ws::Websocket::from(server).split().unwrap();
+
+    // the writer keeps sending while the reader answers the close of the peer
+    let sending = std::thread::spawn(move || loop {
+        if let Err(e) = writer.send_text("data") {
+            return e;
+        }
+    });
+    send(&mut peer, frame::OPCODE_CLOSE, &1000u16.to_be_bytes());
+    assert!(matches!(reader.read_message().unwrap(), ws::Message::Close(Some(1000), _)));
+    match sending.join().unwrap() {
+        ws::Error::ConnectionClosed(e) => assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe),
+        ws::Error::WebsocketError(_) => {},
+        e => panic!("{e}"),
+    }
+    drop(reader);
+
+    // no message follows the close frame
+    while frame::read_frame(&mut peer).unwrap().header.opcode != frame::OPCODE_CLOSE {}
+    assert!(frame::read_frame(&mut peer).is_err());
+}
+
+#[test]
+fn halves_keep_the_settings() {
+    let (mut server, _peer) = MockConnection::pair();
+    server.set_send_buffer(Some(0));
+    let mut socket = ws::Websocket::from(server);
+    socket.set_fragment_size(Some(4));
+    socket.set_max_outgoing(16);
+    let (_reader, mut writer) = socket.split().unwrap();
+
+    // the peer doesn't read, the writer gives up once the limit of the original is exceeded
+    writer.send_text("abcdefgh").unwrap();
+    assert!(matches!(writer.send_text("abcdefgh"), Err(ws::Error::WebsocketError("peer is not reading"))));
+}
+
+#[test]
+fn writer_closes_while_the_reader_waits() {
+    let (server, mut peer) = MockConnection::pair();
+    let (mut reader, mut writer) = ws::Websocket::from(server).split().unwrap();
+
+    let receiving = std::thread::spawn(move || reader.read_message().map(|message| (message, reader)));
+    writer.close(Some(1000), None).unwrap();
+    writer.close(Some(1000), None).unwrap();
+    assert!(writer.send_text("after the close").is_err());
+
+    // the peer's answer completes the handshake without being answered again
+    assert_eq!(frame::read_frame(&mut peer).unwrap().header.opcode, frame::OPCODE_CLOSE);
+    send(&mut peer, frame::OPCODE_CLOSE, &1000u16.to_be_bytes());
+    let (message, reader) = receiving.join().unwrap().unwrap();
+    assert_eq!(message, ws::Message::Close(Some(1000), None));
+    assert!(reader.is_closed());
+    drop((reader, writer));
+    assert!(frame::read_frame(&mut peer).is_err());
+}